@@ -0,0 +1,447 @@
+use std::collections::HashMap;
+use std::env;
+use std::error::Error;
+use std::fs;
+use std::path::PathBuf;
+use std::process;
+use std::thread;
+use std::time::Duration;
+
+use chrono::{DateTime, Local, TimeZone, Utc};
+use clap::{ArgEnum, Parser, Subcommand};
+use serde::Deserialize;
+use serde_json::{Map, Value};
+
+use event_api_client::Client;
+use lib::model::{Comment, Event, EventFilter, Severity, Status};
+use lib::model::metadata::MetadataFilter;
+
+/// Query and post events on a running event-api server.
+#[derive(Parser)]
+#[clap(name = "events", version)]
+struct Cli {
+    /// Server URL. Overrides the profile and EVENTS_URL.
+    #[clap(long, global = true)]
+    url: Option<String>,
+    /// API key. Overrides the profile and EVENTS_API_KEY.
+    #[clap(long, global = true)]
+    api_key: Option<String>,
+    /// Profile to read from the profile file.
+    #[clap(long, global = true, default_value = "default")]
+    profile: String,
+    /// Output format.
+    #[clap(long, short, global = true, arg_enum, default_value = "table")]
+    output: Output,
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// List events.
+    List {
+        #[clap(long)]
+        app: Option<String>,
+        /// Start time: epoch milliseconds, RFC 3339, `now` or relative like `-1h`.
+        #[clap(long, allow_hyphen_values = true)]
+        from: Option<String>,
+        /// End time, in the same formats as --from.
+        #[clap(long, allow_hyphen_values = true)]
+        to: Option<String>,
+        #[clap(long)]
+        source_id: Option<String>,
+        #[clap(long)]
+        source_name: Option<String>,
+        /// Only events with this tag. Repeat for several.
+        #[clap(long = "tag")]
+        tags: Vec<String>,
+        /// Match events with any of the tags instead of all of them.
+        #[clap(long)]
+        any_tag: bool,
+        /// Only events with this metadata, `key=value`, or with the key at
+        /// all, `key`. Repeat for several.
+        #[clap(long = "meta")]
+        metadata: Vec<String>,
+        /// Only events of this severity. Repeat for several.
+        #[clap(long, arg_enum)]
+        severity: Vec<SeverityArg>,
+        /// Only events in this status. Repeat for several.
+        #[clap(long, arg_enum)]
+        status: Vec<StatusArg>,
+    },
+    /// Create an event.
+    Add {
+        text: String,
+        #[clap(long)]
+        app: Option<String>,
+        #[clap(long, allow_hyphen_values = true, default_value = "now")]
+        from: String,
+        #[clap(long, allow_hyphen_values = true)]
+        to: Option<String>,
+        #[clap(long)]
+        source_id: Option<String>,
+        #[clap(long)]
+        source_name: Option<String>,
+        /// Tag the event. Repeat for several.
+        #[clap(long = "tag")]
+        tags: Vec<String>,
+        /// Metadata as `key=value`. Values that parse as JSON are kept as
+        /// such, others as strings. Repeat for several.
+        #[clap(long = "meta")]
+        metadata: Vec<String>,
+        #[clap(long, arg_enum, default_value = "info")]
+        severity: SeverityArg,
+    },
+    /// Acknowledge an open event.
+    Ack {
+        event_id: String,
+    },
+    /// Resolve an event, ending it now if it has no end.
+    Resolve {
+        event_id: String,
+    },
+    /// Comment on an event.
+    Comment {
+        event_id: String,
+        text: String,
+        /// Defaults to the profile's user, then $USER.
+        #[clap(long)]
+        user: Option<String>,
+    },
+    /// List the comments on an event.
+    Comments {
+        event_id: String,
+    },
+    /// Print new events as they arrive.
+    Tail {
+        #[clap(long)]
+        app: Option<String>,
+        /// Seconds between polls.
+        #[clap(long, default_value = "5")]
+        interval: u64,
+        /// Seconds into the past in which backdated events are still printed.
+        #[clap(long, default_value = "3600")]
+        window: i64,
+    },
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum SeverityArg {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl From<SeverityArg> for Severity {
+    fn from(severity: SeverityArg) -> Severity {
+        match severity {
+            SeverityArg::Info => Severity::Info,
+            SeverityArg::Warning => Severity::Warning,
+            SeverityArg::Critical => Severity::Critical,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum StatusArg {
+    Open,
+    Acknowledged,
+    Resolved,
+}
+
+impl From<StatusArg> for Status {
+    fn from(status: StatusArg) -> Status {
+        match status {
+            StatusArg::Open => Status::Open,
+            StatusArg::Acknowledged => Status::Acknowledged,
+            StatusArg::Resolved => Status::Resolved,
+        }
+    }
+}
+
+#[derive(Clone, Copy, ArgEnum)]
+enum Output {
+    Table,
+    Json,
+    Ndjson,
+}
+
+/// One section of the profile file, `~/.config/events/profiles.toml`:
+///
+/// ```toml
+/// [default]
+/// url = "http://localhost:8000"
+/// user = "jon"
+/// api_key = "evk_..."
+/// ```
+#[derive(Default, Deserialize)]
+struct Profile {
+    url: Option<String>,
+    user: Option<String>,
+    api_key: Option<String>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    if let Err(err) = run(cli) {
+        eprintln!("events: {}", err);
+        process::exit(1);
+    }
+}
+
+fn run(cli: Cli) -> Result<(), Box<dyn Error>> {
+    let profile = read_profile(&cli.profile)?;
+    let url = cli.url.clone()
+        .or_else(|| env::var("EVENTS_URL").ok())
+        .or_else(|| profile.url.clone())
+        .unwrap_or("http://localhost:8000".to_string());
+    let api_key = cli.api_key.clone()
+        .or_else(|| env::var("EVENTS_API_KEY").ok())
+        .or_else(|| profile.api_key.clone());
+    let client = match api_key {
+        Some(api_key) => Client::new(&url).with_authorization(&format!("Bearer {}", api_key)),
+        None => Client::new(&url),
+    };
+
+    match cli.command {
+        Command::List { app, from, to, source_id, source_name, tags, any_tag, metadata, severity, status } => {
+            let filter = EventFilter {
+                from: from.map(|from| parse_time(&from)).transpose()?,
+                to: to.map(|to| parse_time(&to)).transpose()?,
+                app_name: app,
+                source_id,
+                source_name,
+                tags: if tags.is_empty() { None } else { Some(tags) },
+                any_tag,
+                metadata: metadata.iter().map(|condition| MetadataFilter::parse(condition)).collect(),
+                severity: if severity.is_empty() { None } else { Some(severity.into_iter().map(Severity::from).collect()) },
+                status: if status.is_empty() { None } else { Some(status.into_iter().map(Status::from).collect()) },
+            };
+            let mut events = client.list_events(&filter)?;
+            events.sort_by_key(|e| e.from);
+            print_events(&events, cli.output)?;
+        },
+        Command::Add { text, app, from, to, source_id, source_name, tags, metadata, severity } => {
+            let event = Event {
+                id: None,
+                from: parse_time(&from)?,
+                to: to.map(|to| parse_time(&to)).transpose()?,
+                text,
+                app_name: app,
+                source_id,
+                source_name,
+                tags,
+                metadata: parse_metadata(&metadata)?,
+                severity: severity.into(),
+                status: Some(Status::Open),
+                created_by: None,
+                _links: None,
+                _templates: None,
+                _embedded: None,
+            };
+            let created = client.create_event(&event)?;
+            print_events(&[created.data], cli.output)?;
+        },
+        Command::Ack { event_id } => {
+            let event = client.acknowledge_event(&event_id)?;
+            print_events(&[event.data], cli.output)?;
+        },
+        Command::Resolve { event_id } => {
+            let event = client.resolve_event(&event_id)?;
+            print_events(&[event.data], cli.output)?;
+        },
+        Command::Comment { event_id, text, user } => {
+            let user_id = user
+                .or(profile.user)
+                .or_else(|| env::var("USER").ok())
+                .ok_or("No user given, use --user")?;
+            let comment = Comment {
+                id: None,
+                event_id,
+                user_id,
+                comment: text,
+                timestamp: Utc::now().timestamp_millis(),
+                _links: None,
+                _templates: None,
+            };
+            let created = client.create_comment(&comment)?;
+            print_comments(&[created.data], cli.output)?;
+        },
+        Command::Comments { event_id } => {
+            let comments: Vec<Comment> = client.get_comments(&event_id)?
+                .into_iter()
+                .map(|resource| resource.data)
+                .collect();
+            print_comments(&comments, cli.output)?;
+        },
+        Command::Tail { app, interval, window } => {
+            // Ids of the events within the window, with the time they start
+            // at. Events already there when tailing starts are not printed.
+            let mut seen: HashMap<String, i64> = HashMap::new();
+            let mut first = true;
+            loop {
+                let since = Utc::now().timestamp_millis() - window * 1000;
+                let filter = EventFilter { from: Some(since), app_name: app.clone(), ..Default::default() };
+                let mut events: Vec<Event> = client.list_events(&filter)?
+                    .into_iter()
+                    .filter(|e| e.from >= since && e.id.as_ref().map_or(false, |id| !seen.contains_key(id)))
+                    .collect();
+                events.sort_by_key(|e| e.from);
+                if !first && !events.is_empty() {
+                    print_events(&events, match cli.output { Output::Table => Output::Table, _ => Output::Ndjson })?;
+                }
+                first = false;
+                for event in events.iter() {
+                    seen.insert(event.id.clone().unwrap_or_default(), event.from);
+                }
+                seen.retain(|_, from| *from >= since);
+                thread::sleep(Duration::from_secs(interval));
+            }
+        },
+    }
+    Ok(())
+}
+
+fn read_profile(name: &str) -> Result<Profile, Box<dyn Error>> {
+    let path = match profile_path() {
+        Some(path) if path.exists() => path,
+        _ => return Ok(Profile::default()),
+    };
+    let mut profiles: HashMap<String, Profile> = toml::from_str(&fs::read_to_string(&path)?)?;
+    match profiles.remove(name) {
+        Some(profile) => Ok(profile),
+        None if name == "default" => Ok(Profile::default()),
+        None => Err(format!("No profile {} in {}", name, path.display()).into()),
+    }
+}
+
+fn profile_path() -> Option<PathBuf> {
+    if let Ok(path) = env::var("EVENTS_PROFILE_FILE") {
+        return Some(PathBuf::from(path));
+    }
+    let config = env::var("XDG_CONFIG_HOME").map(PathBuf::from)
+        .or_else(|_| env::var("HOME").map(|home| PathBuf::from(home).join(".config")))
+        .ok()?;
+    Some(config.join("events").join("profiles.toml"))
+}
+
+/// Parses epoch milliseconds, RFC 3339, `now`, or an offset from now such
+/// as `-90s`, `-15m`, `-1h`, `-2d` or `+1w`.
+fn parse_time(value: &str) -> Result<i64, Box<dyn Error>> {
+    let now = Utc::now().timestamp_millis();
+    if value == "now" {
+        return Ok(now);
+    }
+    if let Ok(millis) = value.parse::<i64>() {
+        return Ok(millis);
+    }
+    if let Ok(time) = DateTime::parse_from_rfc3339(value) {
+        return Ok(time.timestamp_millis());
+    }
+    if (value.starts_with('-') || value.starts_with('+')) && value.len() > 2 && value.is_ascii() {
+        let sign = if value.starts_with('-') { -1 } else { 1 };
+        let (amount, unit) = value[1..].split_at(value.len() - 2);
+        let amount: i64 = amount.parse().map_err(|_| format!("Invalid time: {}", value))?;
+        let unit_millis = match unit {
+            "s" => 1000,
+            "m" => 60 * 1000,
+            "h" => 60 * 60 * 1000,
+            "d" => 24 * 60 * 60 * 1000,
+            "w" => 7 * 24 * 60 * 60 * 1000,
+            _ => return Err(format!("Invalid time unit in {}", value).into()),
+        };
+        return Ok(now + sign * amount * unit_millis);
+    }
+    Err(format!("Invalid time: {}", value).into())
+}
+
+/// Falls back to the raw milliseconds for times chrono cannot represent.
+fn format_time(millis: i64) -> String {
+    match Local.timestamp_millis_opt(millis).single() {
+        Some(time) => time.format("%Y-%m-%d %H:%M:%S").to_string(),
+        None => millis.to_string(),
+    }
+}
+
+fn parse_metadata(pairs: &[String]) -> Result<Map<String, Value>, Box<dyn Error>> {
+    let mut metadata = Map::new();
+    for pair in pairs {
+        let mut parts = pair.splitn(2, '=');
+        let key = parts.next().unwrap_or("");
+        let value = parts.next().ok_or(format!("Invalid metadata {}, expected key=value", pair))?;
+        let value = serde_json::from_str(value).unwrap_or(Value::String(value.to_string()));
+        metadata.insert(key.to_string(), value);
+    }
+    Ok(metadata)
+}
+
+fn print_events(events: &[Event], output: Output) -> Result<(), Box<dyn Error>> {
+    match output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(events)?),
+        Output::Ndjson => {
+            for event in events {
+                println!("{}", serde_json::to_string(event)?);
+            }
+        },
+        Output::Table => {
+            let rows: Vec<Vec<String>> = events.iter()
+                .map(|e| vec![
+                    e.id.clone().unwrap_or_default(),
+                    format_time(e.from),
+                    e.to.map(format_time).unwrap_or_default(),
+                    e.app_name.clone().unwrap_or_default(),
+                    e.severity.as_str().to_string(),
+                    e.status().as_str().to_string(),
+                    e.text.lines().next().unwrap_or("").to_string(),
+                ])
+                .collect();
+            print_table(&["ID", "FROM", "TO", "APP", "SEVERITY", "STATUS", "TEXT"], &rows);
+        },
+    }
+    Ok(())
+}
+
+fn print_comments(comments: &[Comment], output: Output) -> Result<(), Box<dyn Error>> {
+    match output {
+        Output::Json => println!("{}", serde_json::to_string_pretty(comments)?),
+        Output::Ndjson => {
+            for comment in comments {
+                println!("{}", serde_json::to_string(comment)?);
+            }
+        },
+        Output::Table => {
+            let rows: Vec<Vec<String>> = comments.iter()
+                .map(|c| vec![
+                    c.id.clone().unwrap_or_default(),
+                    format_time(c.timestamp),
+                    c.user_id.clone(),
+                    c.comment.lines().next().unwrap_or("").to_string(),
+                ])
+                .collect();
+            print_table(&["ID", "TIME", "USER", "COMMENT"], &rows);
+        },
+    }
+    Ok(())
+}
+
+fn print_table(headers: &[&str], rows: &[Vec<String>]) {
+    let mut widths: Vec<usize> = headers.iter().map(|h| h.len()).collect();
+    for row in rows {
+        for (i, cell) in row.iter().enumerate() {
+            widths[i] = widths[i].max(cell.chars().count());
+        }
+    }
+    let line = |cells: Vec<String>| {
+        cells.iter()
+            .enumerate()
+            .map(|(i, cell)| format!("{:width$}", cell, width = widths[i]))
+            .collect::<Vec<String>>()
+            .join("  ")
+            .trim_end()
+            .to_string()
+    };
+    println!("{}", line(headers.iter().map(|h| h.to_string()).collect()));
+    for row in rows {
+        println!("{}", line(row.clone()));
+    }
+}
@@ -0,0 +1,403 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fmt;
+use std::marker::PhantomData;
+
+use reqwest::blocking::{Client as HttpClient, RequestBuilder};
+use serde::Serialize;
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+
+use lib::envelope::{Envelope, Link, Status, Template};
+use lib::model::{BatchOperation, BatchResult, Comment, Event, EventFilter};
+
+/// Events requested per page when listing.
+const PAGE_SIZE: usize = 100;
+
+#[derive(Debug)]
+pub enum ClientError {
+    /// The request could not be sent or the response could not be read.
+    Http(reqwest::Error),
+    /// The server answered with an error envelope.
+    Api { code: i32, description: String },
+    /// The response did not have the expected shape.
+    Decode(String),
+}
+
+impl fmt::Display for ClientError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ClientError::Http(err) => write!(f, "HTTP error: {}", err),
+            ClientError::Api { code, description } => write!(f, "API error {}: {}", code, description),
+            ClientError::Decode(message) => write!(f, "Unexpected response: {}", message),
+        }
+    }
+}
+
+impl Error for ClientError {}
+
+impl From<reqwest::Error> for ClientError {
+    fn from(err: reqwest::Error) -> ClientError {
+        ClientError::Http(err)
+    }
+}
+
+/// A resource together with the links and templates the server sent with it.
+#[derive(Debug, Clone)]
+pub struct Resource<T> {
+    pub data: T,
+    pub links: HashMap<String, Link>,
+    pub templates: HashMap<String, Template>,
+}
+
+impl<T> Resource<T> {
+    pub fn link(&self, key: &str) -> Option<&Link> {
+        self.links.get(key)
+    }
+}
+
+/// Client for a running event-api server.
+///
+/// ```no_run
+/// use event_api_client::Client;
+///
+/// let client = Client::new("http://localhost:8000");
+/// for event in client.list_events(&Default::default()).unwrap() {
+///     println!("{}", event.text);
+/// }
+/// ```
+pub struct Client {
+    base_url: String,
+    http: HttpClient,
+    authorization: Option<String>,
+}
+
+impl Client {
+    pub fn new(base_url: &str) -> Client {
+        Client {
+            base_url: base_url.trim_end_matches('/').to_string(),
+            http: HttpClient::new(),
+            authorization: None,
+        }
+    }
+
+    /// Sends `value` as the `Authorization` header on every request.
+    pub fn with_authorization(mut self, value: &str) -> Client {
+        self.authorization = Some(value.to_string());
+        self
+    }
+
+    pub fn list_events(&self, filter: &EventFilter) -> Result<Vec<Event>, ClientError> {
+        let mut events = Vec::new();
+        for page in self.events(filter) {
+            events.extend(page?.into_iter().map(|resource| resource.data));
+        }
+        Ok(events)
+    }
+
+    /// Iterates over the pages of events matching `filter`, with their links
+    /// and templates, following the envelope's `nextPage` until the server
+    /// stops sending one.
+    pub fn events(&self, filter: &EventFilter) -> Pages<Event> {
+        let query = filter_query(filter);
+        let separator = if query.is_empty() { "?" } else { "&" };
+        Pages {
+            client: self,
+            next: Some(self.url(&format!("/events{}{}pageSize={}", query, separator, PAGE_SIZE))),
+            item: PhantomData,
+        }
+    }
+
+    pub fn get_event(&self, event_id: &str) -> Result<Resource<Event>, ClientError> {
+        let envelope = self.send(self.http.get(&self.url(&format!("/events/{}", encode(event_id)))))?;
+        resource(envelope)
+    }
+
+    pub fn create_event(&self, event: &Event) -> Result<Resource<Event>, ClientError> {
+        resource(self.send_json(self.http.post(&self.url("/events")), event)?)
+    }
+
+    pub fn update_event(&self, event: &Event) -> Result<Resource<Event>, ClientError> {
+        let event_id = event.id.as_ref().ok_or(ClientError::Decode("Event has no id".to_string()))?;
+        let request = self.http.patch(&self.url(&format!("/events/{}", encode(event_id))));
+        resource(self.send_json(request, event)?)
+    }
+
+    pub fn acknowledge_event(&self, event_id: &str) -> Result<Resource<Event>, ClientError> {
+        resource(self.send(self.http.post(&self.url(&format!("/events/{}/acknowledge", encode(event_id)))))?)
+    }
+
+    /// Resolves an event, which also ends it if it has no end yet.
+    pub fn resolve_event(&self, event_id: &str) -> Result<Resource<Event>, ClientError> {
+        resource(self.send(self.http.post(&self.url(&format!("/events/{}/resolve", encode(event_id)))))?)
+    }
+
+    pub fn delete_event(&self, event_id: &str) -> Result<bool, ClientError> {
+        let envelope = self.send(self.http.delete(&self.url(&format!("/events/{}", encode(event_id)))))?;
+        data(envelope)
+    }
+
+    pub fn batch(&self, operations: &[BatchOperation], all_or_nothing: bool) -> Result<Vec<BatchResult>, ClientError> {
+        let request = self.http.post(&self.url(&format!("/events/batch?allOrNothing={}", all_or_nothing)));
+        data(self.send_json(request, &operations)?)
+    }
+
+    pub fn get_comments(&self, event_id: &str) -> Result<Vec<Resource<Comment>>, ClientError> {
+        let envelope = self.send(self.http.get(&self.url(&format!("/events/{}/comments", encode(event_id)))))?;
+        resources(envelope)
+    }
+
+    pub fn get_user_comments(&self, user_id: &str) -> Result<Vec<Resource<Comment>>, ClientError> {
+        let request = self.http.get(&self.url("/comments")).query(&[("userId", user_id)]);
+        resources(self.send(request)?)
+    }
+
+    pub fn get_comment(&self, event_id: &str, comment_id: &str) -> Result<Resource<Comment>, ClientError> {
+        let url = self.url(&format!("/events/{}/comments/{}", encode(event_id), encode(comment_id)));
+        resource(self.send(self.http.get(&url))?)
+    }
+
+    pub fn create_comment(&self, comment: &Comment) -> Result<Resource<Comment>, ClientError> {
+        let request = self.http.post(&self.url(&format!("/events/{}/comments", encode(&comment.event_id))));
+        resource(self.send_json(request, comment)?)
+    }
+
+    pub fn update_comment(&self, comment: &Comment) -> Result<Resource<Comment>, ClientError> {
+        let comment_id = comment.id.as_ref().ok_or(ClientError::Decode("Comment has no id".to_string()))?;
+        let url = self.url(&format!("/events/{}/comments/{}", encode(&comment.event_id), encode(comment_id)));
+        resource(self.send_json(self.http.patch(&url), comment)?)
+    }
+
+    pub fn delete_comment(&self, event_id: &str, comment_id: &str) -> Result<bool, ClientError> {
+        let url = self.url(&format!("/events/{}/comments/{}", encode(event_id), encode(comment_id)));
+        data(self.send(self.http.delete(&url))?)
+    }
+
+    /// Fetches the resource a link points to, e.g. `event.link("comments")`.
+    pub fn follow<T: DeserializeOwned>(&self, link: &Link) -> Result<Resource<T>, ClientError> {
+        resource(self.send(self.http.get(&self.url(&link.href)))?)
+    }
+
+    /// Fetches the list of resources a link points to.
+    pub fn follow_all<T: DeserializeOwned>(&self, link: &Link) -> Result<Vec<Resource<T>>, ClientError> {
+        resources(self.send(self.http.get(&self.url(&link.href)))?)
+    }
+
+    /// Resolves a path or an href from a link against the base URL.
+    fn url(&self, href: &str) -> String {
+        if href.starts_with("http://") || href.starts_with("https://") {
+            href.to_string()
+        } else {
+            format!("{}{}", self.base_url, href)
+        }
+    }
+
+    fn send_json<B: Serialize + ?Sized>(&self, request: RequestBuilder, body: &B) -> Result<Envelope, ClientError> {
+        self.send(request.json(body))
+    }
+
+    fn send(&self, request: RequestBuilder) -> Result<Envelope, ClientError> {
+        let request = match self.authorization {
+            Some(ref authorization) => request.header("Authorization", authorization.as_str()),
+            None => request,
+        };
+        let envelope: Envelope = request.header("Accept", "application/json").send()?.json()?;
+        match envelope.status {
+            Status::OK => Ok(envelope),
+            Status::Error => {
+                let error = envelope.error.unwrap_or(lib::envelope::Error { code: 0, description: "Unknown error".to_string() });
+                Err(ClientError::Api { code: error.code, description: error.description })
+            },
+        }
+    }
+}
+
+/// Iterator over pages of resources. Each item is one page.
+pub struct Pages<'a, T> {
+    client: &'a Client,
+    next: Option<String>,
+    item: PhantomData<T>,
+}
+
+impl<'a, T: DeserializeOwned> Iterator for Pages<'a, T> {
+    type Item = Result<Vec<Resource<T>>, ClientError>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let url = self.next.take()?;
+        let envelope = match self.client.send(self.client.http.get(&url)) {
+            Ok(envelope) => envelope,
+            Err(err) => return Some(Err(err)),
+        };
+        self.next = envelope.next_page.as_ref().map(|next| self.client.url(next));
+        Some(resources(envelope))
+    }
+}
+
+fn filter_query(filter: &EventFilter) -> String {
+    let mut pairs: Vec<String> = Vec::new();
+    if let Some(from) = filter.from {
+        pairs.push(format!("from={}", from));
+    }
+    if let Some(to) = filter.to {
+        pairs.push(format!("to={}", to));
+    }
+    let strings = [
+        ("appName", &filter.app_name),
+        ("sourceId", &filter.source_id),
+        ("sourceName", &filter.source_name),
+    ];
+    for (key, value) in strings.iter() {
+        if let Some(value) = value {
+            pairs.push(format!("{}={}", key, encode(value)));
+        }
+    }
+    if let Some(ref tags) = filter.tags {
+        pairs.push(format!("tags={}", encode(&tags.join(","))));
+        if filter.any_tag {
+            pairs.push("tagMatch=any".to_string());
+        }
+    }
+    if let Some(ref severity) = filter.severity {
+        let severity: Vec<&str> = severity.iter().map(|s| s.as_str()).collect();
+        pairs.push(format!("severity={}", encode(&severity.join(","))));
+    }
+    if let Some(ref status) = filter.status {
+        let status: Vec<&str> = status.iter().map(|s| s.as_str()).collect();
+        pairs.push(format!("status={}", encode(&status.join(","))));
+    }
+    for condition in filter.metadata.iter() {
+        match condition.value {
+            Some(ref value) => pairs.push(format!("meta.{}={}", encode(&condition.key), encode(value))),
+            None => pairs.push(format!("meta.{}", encode(&condition.key))),
+        }
+    }
+    if pairs.is_empty() { String::new() } else { format!("?{}", pairs.join("&")) }
+}
+
+fn encode(value: &str) -> String {
+    value.bytes()
+        .map(|b| match b {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'_' | b'.' | b'~' => (b as char).to_string(),
+            _ => format!("%{:02X}", b),
+        })
+        .collect()
+}
+
+fn data<T: DeserializeOwned>(envelope: Envelope) -> Result<T, ClientError> {
+    let value = envelope.data.map(|data| data.0).unwrap_or(Value::Null);
+    serde_json::from_value(value).map_err(|err| ClientError::Decode(err.to_string()))
+}
+
+fn resource<T: DeserializeOwned>(envelope: Envelope) -> Result<Resource<T>, ClientError> {
+    let links = keyed(envelope._links.clone().unwrap_or_default());
+    let templates = keyed_templates(envelope._templates.clone().unwrap_or_default());
+    Ok(Resource { data: data(envelope)?, links, templates })
+}
+
+fn resources<T: DeserializeOwned>(envelope: Envelope) -> Result<Vec<Resource<T>>, ClientError> {
+    let items: Vec<Value> = data(envelope)?;
+    items.into_iter().map(item_resource).collect()
+}
+
+/// Lists embed `_links` and `_templates` in every item, which the model
+/// types skip on deserialization, so they are read from the raw item.
+fn item_resource<T: DeserializeOwned>(item: Value) -> Result<Resource<T>, ClientError> {
+    let links: HashMap<String, Link> = match item.get("_links") {
+        Some(links) => serde_json::from_value(links.clone()).map_err(|err| ClientError::Decode(err.to_string()))?,
+        None => HashMap::new(),
+    };
+    let templates: HashMap<String, Template> = match item.get("_templates") {
+        Some(templates) => serde_json::from_value(templates.clone()).map_err(|err| ClientError::Decode(err.to_string()))?,
+        None => HashMap::new(),
+    };
+    let data = serde_json::from_value(item).map_err(|err| ClientError::Decode(err.to_string()))?;
+    Ok(Resource { data, links: keyed(links), templates: keyed_templates(templates) })
+}
+
+/// Links are keyed by relation in `_links`; copy the relation into `Link::key`.
+fn keyed(links: HashMap<String, Link>) -> HashMap<String, Link> {
+    links.into_iter()
+        .map(|(key, mut link)| {
+            link.key = key.clone();
+            (key, link)
+        })
+        .collect()
+}
+
+fn keyed_templates(templates: HashMap<String, Template>) -> HashMap<String, Template> {
+    templates.into_iter()
+        .map(|(key, mut template)| {
+            template.key = key.clone();
+            (key, template)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encode_escapes_path_separators() {
+        assert_eq!(encode("a b/c?d"), "a%20b%2Fc%3Fd");
+        assert_eq!(encode("abc-1_2.3~"), "abc-1_2.3~");
+    }
+
+    #[test]
+    fn filter_query_sends_source_filters() {
+        let filter = EventFilter {
+            source_id: Some("host 1".to_string()),
+            source_name: Some("db".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(filter_query(&filter), "?sourceId=host%201&sourceName=db");
+        assert_eq!(filter_query(&EventFilter::default()), "");
+    }
+
+    /// Answers each request with the next body, passing the request lines on.
+    fn server(bodies: Vec<String>) -> (String, std::sync::mpsc::Receiver<String>) {
+        use std::io::{BufRead, BufReader, Write};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}", listener.local_addr().unwrap());
+        let (sender, requests) = std::sync::mpsc::channel();
+        std::thread::spawn(move || {
+            for body in bodies {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                write!(stream, "HTTP/1.1 200 OK\r\nContent-Type: application/json\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{}", body.len(), body).unwrap();
+                sender.send(request_line).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    #[test]
+    fn pages_follow_next_page() {
+        let page = |text: &str, next: Option<&str>| serde_json::json!({
+            "status": "OK",
+            "data": [{ "id": text, "from": 1, "text": text }],
+            "error": null,
+            "pageNumber": null,
+            "nextPage": next,
+            "totalPages": 2,
+            "_links": {},
+            "_templates": {},
+        }).to_string();
+        let (url, requests) = server(vec![page("a", Some("/events?pageSize=100&page=2")), page("b", None)]);
+        let events = Client::new(&url).list_events(&EventFilter::default()).unwrap();
+        let texts: Vec<&str> = events.iter().map(|e| e.text.as_str()).collect();
+        assert_eq!(texts, vec!["a", "b"]);
+        assert!(requests.recv().unwrap().starts_with("GET /events?pageSize=100 "));
+        assert!(requests.recv().unwrap().starts_with("GET /events?pageSize=100&page=2 "));
+    }
+}
@@ -0,0 +1,319 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::process;
+
+use clap::{Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+
+use lib::auth::{KeyStore, Scope};
+use lib::db::{self, EventDb};
+use lib::model::{self, Comment, Event};
+
+/// Offline maintenance of an event-api data store. Run it while the server
+/// is stopped; it opens the store directly instead of going through HTTP.
+///
+/// Stores are given as URLs: `file:<directory>` or `sqlite:<path>`.
+#[derive(Parser)]
+#[clap(name = "event-admin", version)]
+struct Cli {
+    #[clap(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Check every record against the model and report duplicate ids and
+    /// comments whose event no longer exists. Exits with 1 on problems.
+    Validate {
+        #[clap(long, default_value = "file:data")]
+        db: String,
+    },
+    /// Delete comments whose event no longer exists.
+    Repair {
+        #[clap(long, default_value = "file:data")]
+        db: String,
+        /// Only report what would be deleted.
+        #[clap(long)]
+        dry_run: bool,
+    },
+    /// Copy all events and comments from one store to an empty one, keeping
+    /// ids. Comments whose event does not exist are reported and skipped.
+    Migrate {
+        #[clap(long)]
+        from: String,
+        #[clap(long)]
+        to: String,
+    },
+    /// Reclaim space and drop superseded records.
+    Compact {
+        #[clap(long, default_value = "file:data")]
+        db: String,
+    },
+    /// Rebuild the store's indexes.
+    Reindex {
+        #[clap(long, default_value = "file:data")]
+        db: String,
+    },
+    /// Write all events and comments to a JSON file.
+    Dump {
+        #[clap(long, default_value = "file:data")]
+        db: String,
+        #[clap(long)]
+        output: String,
+    },
+    /// Load events and comments from a file written by `dump` into an empty
+    /// store, keeping ids. Comments whose event does not exist are reported
+    /// and skipped.
+    Restore {
+        #[clap(long, default_value = "file:data")]
+        db: String,
+        #[clap(long)]
+        input: String,
+    },
+    /// Manage API keys.
+    Keys {
+        /// Path of the key file, the server's `api_keys` setting.
+        #[clap(long, default_value = "data/keys.json")]
+        keys: String,
+        #[clap(subcommand)]
+        command: KeyCommand,
+    },
+}
+
+#[derive(Subcommand)]
+enum KeyCommand {
+    /// List keys and their scopes.
+    List,
+    /// Create a key and print its token. The token cannot be shown again.
+    Create {
+        name: String,
+        /// events:read, events:write, comments:write or admin. Repeatable.
+        #[clap(long = "scope", required = true)]
+        scopes: Vec<String>,
+        /// Bind the key to a tenant from the tenant file.
+        #[clap(long)]
+        tenant: Option<String>,
+    },
+    /// Delete a key.
+    Revoke {
+        id: String,
+    },
+}
+
+#[derive(Serialize, Deserialize)]
+struct Dump {
+    events: Vec<Event>,
+    comments: Vec<Comment>,
+}
+
+fn main() {
+    let cli = Cli::parse();
+    match run(cli) {
+        Ok(true) => {},
+        Ok(false) => process::exit(1),
+        Err(err) => {
+            eprintln!("event-admin: {}", err);
+            process::exit(2);
+        },
+    }
+}
+
+/// Returns whether the store was found in good shape.
+fn run(cli: Cli) -> Result<bool, Box<dyn Error>> {
+    match cli.command {
+        Command::Validate { db } => {
+            let edb = db::open(&db)?;
+            let problems = validate(&*edb)?;
+            for problem in problems.iter() {
+                println!("{}", problem);
+            }
+            println!("{} problem(s) found", problems.len());
+            Ok(problems.is_empty())
+        },
+        Command::Repair { db, dry_run } => {
+            let edb = db::open(&db)?;
+            let orphans = orphaned_comments(&*edb)?;
+            for comment in orphans.iter() {
+                let id = comment.id.clone().unwrap_or_default();
+                println!("{} comment {} on missing event {}", if dry_run { "Would delete" } else { "Deleting" }, id, comment.event_id);
+                if !dry_run {
+                    edb.delete_comment(id)?;
+                }
+            }
+            println!("{} orphaned comment(s)", orphans.len());
+            Ok(true)
+        },
+        Command::Migrate { from, to } => {
+            let source = db::open(&from)?;
+            let target = db::open(&to)?;
+            let (events, comments, orphans) = copy(source.get_events(None)?, source.get_comments(None)?, &*target)?;
+            report_skipped(&orphans);
+            println!("Migrated {} event(s) and {} comment(s)", events, comments);
+            Ok(orphans.is_empty())
+        },
+        Command::Compact { db } => {
+            db::open(&db)?.compact()?;
+            Ok(true)
+        },
+        Command::Reindex { db } => {
+            db::open(&db)?.reindex()?;
+            Ok(true)
+        },
+        Command::Dump { db, output } => {
+            let edb = db::open(&db)?;
+            let dump = Dump { events: edb.get_events(None)?, comments: edb.get_comments(None)? };
+            fs::write(&output, serde_json::to_string_pretty(&dump)?)?;
+            println!("Dumped {} event(s) and {} comment(s)", dump.events.len(), dump.comments.len());
+            Ok(true)
+        },
+        Command::Restore { db, input } => {
+            let edb = db::open(&db)?;
+            let dump: Dump = serde_json::from_str(&fs::read_to_string(&input)?)?;
+            let (events, comments, orphans) = copy(dump.events, dump.comments, &*edb)?;
+            report_skipped(&orphans);
+            println!("Restored {} event(s) and {} comment(s)", events, comments);
+            Ok(orphans.is_empty())
+        },
+        Command::Keys { keys, command } => run_keys(&KeyStore::new(keys), command),
+    }
+}
+
+fn run_keys(store: &KeyStore, command: KeyCommand) -> Result<bool, Box<dyn Error>> {
+    match command {
+        KeyCommand::List => {
+            for key in store.list()? {
+                let scopes: Vec<&str> = key.scopes.iter().map(|s| s.as_str()).collect();
+                println!("{}  {}  {}  {}", key.id, key.name, scopes.join(","), key.tenant.unwrap_or("-".to_string()));
+            }
+            Ok(true)
+        },
+        KeyCommand::Create { name, scopes, tenant } => {
+            let mut parsed = Vec::new();
+            for scope in scopes.iter() {
+                parsed.push(Scope::parse(scope).ok_or(format!("Unknown scope {}", scope))?);
+            }
+            let (key, token) = store.create(&name, parsed, tenant)?;
+            println!("Created key {}", key.id);
+            println!("{}", token);
+            Ok(true)
+        },
+        KeyCommand::Revoke { id } => {
+            if store.revoke(&id)? {
+                println!("Revoked key {}", id);
+                Ok(true)
+            } else {
+                println!("No key {}", id);
+                Ok(false)
+            }
+        },
+    }
+}
+
+fn validate(edb: &dyn EventDb) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut problems: Vec<String> = Vec::new();
+
+    let events = edb.get_events(None)?;
+    let mut event_ids: HashSet<String> = HashSet::new();
+    for (index, event) in events.iter().enumerate() {
+        let name = match event.id {
+            Some(ref id) => format!("Event {}", id),
+            None => format!("Event #{}", index),
+        };
+        match event.id {
+            Some(ref id) if !event_ids.insert(id.clone()) => problems.push(format!("{}: duplicate id", name)),
+            None => problems.push(format!("{}: missing id", name)),
+            _ => {},
+        }
+        if let Err(err) = model::validate_event(event) {
+            problems.push(format!("{}: {}", name, err));
+        }
+    }
+
+    let comments = edb.get_comments(None)?;
+    let mut comment_ids: HashSet<String> = HashSet::new();
+    for (index, comment) in comments.iter().enumerate() {
+        let name = match comment.id {
+            Some(ref id) => format!("Comment {}", id),
+            None => format!("Comment #{}", index),
+        };
+        match comment.id {
+            Some(ref id) if !comment_ids.insert(id.clone()) => problems.push(format!("{}: duplicate id", name)),
+            None => problems.push(format!("{}: missing id", name)),
+            _ => {},
+        }
+        if let Err(err) = model::validate_comment(comment) {
+            problems.push(format!("{}: {}", name, err));
+        }
+        if !event_ids.contains(&comment.event_id) {
+            problems.push(format!("{}: event {} does not exist", name, comment.event_id));
+        }
+    }
+
+    Ok(problems)
+}
+
+fn orphaned_comments(edb: &dyn EventDb) -> Result<Vec<Comment>, Box<dyn Error>> {
+    let event_ids: HashSet<String> = edb.get_events(None)?
+        .into_iter()
+        .filter_map(|e| e.id)
+        .collect();
+    Ok(edb.get_comments(None)?
+        .into_iter()
+        .filter(|c| !event_ids.contains(&c.event_id))
+        .collect())
+}
+
+/// Imports records into an empty store, keeping ids, and returns how many
+/// events and comments were imported along with the comments left out
+/// because their event is not among `events`.
+fn copy(events: Vec<Event>, comments: Vec<Comment>, target: &dyn EventDb) -> Result<(usize, usize, Vec<Comment>), Box<dyn Error>> {
+    if !target.get_events(None)?.is_empty() || !target.get_comments(None)?.is_empty() {
+        return Err("The target store is not empty".into());
+    }
+    let event_ids: HashSet<String> = events.iter().filter_map(|e| e.id.clone()).collect();
+    let (comments, orphans): (Vec<Comment>, Vec<Comment>) = comments
+        .into_iter()
+        .partition(|c| event_ids.contains(&c.event_id));
+    let events = target.import_events(events, true)?;
+    let comments = target.import_comments(comments, true)?;
+    Ok((events.len(), comments.len(), orphans))
+}
+
+fn report_skipped(orphans: &[Comment]) {
+    for comment in orphans.iter() {
+        println!("Skipped comment {}: event {} does not exist", comment.id.clone().unwrap_or_default(), comment.event_id);
+    }
+    if !orphans.is_empty() {
+        println!("{} orphaned comment(s) skipped", orphans.len());
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use lib::db::file_based::FileBasedEventDb;
+
+    use super::*;
+
+    fn temp_db() -> FileBasedEventDb {
+        FileBasedEventDb::new(std::env::temp_dir().join(format!("event-api-test-{}", uuid::Uuid::new_v4())))
+    }
+
+    #[test]
+    fn copies_skip_orphans_and_need_an_empty_target() {
+        let events: Vec<Event> = vec![serde_json::from_value(json!({ "id": "e1", "from": 1, "text": "a" })).unwrap()];
+        let comments: Vec<Comment> = vec![
+            serde_json::from_value(json!({ "id": "c1", "eventId": "e1", "userId": "u", "comment": "kept", "timestamp": 1 })).unwrap(),
+            serde_json::from_value(json!({ "id": "c2", "eventId": "gone", "userId": "u", "comment": "orphan", "timestamp": 1 })).unwrap(),
+        ];
+        let target = temp_db();
+        let (imported_events, imported_comments, orphans) = copy(events.clone(), comments.clone(), &target).unwrap();
+        assert_eq!((imported_events, imported_comments), (1, 1));
+        assert_eq!(orphans[0].id.as_deref(), Some("c2"));
+        assert!(target.get_comment("c1".to_string()).is_ok());
+
+        assert!(copy(events, comments, &target).is_err());
+    }
+}
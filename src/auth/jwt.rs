@@ -0,0 +1,128 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs;
+
+use jsonwebtoken::{decode, decode_header, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+use serde_json::Value;
+
+use super::{Principal, Scope};
+
+/// JWT settings read from the `[global]` section of `Rocket.toml`. JWTs are
+/// accepted when at least one key is configured:
+///
+/// * `jwt_hs256_secret`: shared secret for HS256 tokens.
+/// * `jwt_rs256_public_key`: path of a PEM public key for RS256 tokens.
+/// * `jwt_jwks`: path of a JWKS file with RS256 keys, picked by `kid`.
+/// * `jwt_issuer`, `jwt_audience`: required `iss` and `aud`, when set.
+/// * `jwt_tenant_claim`: claim naming the caller's tenant. Defaults to
+///   `tenant`.
+/// * `jwt_default_scopes`: scopes for tokens without a `scope` claim.
+///   Defaults to `events:read,comments:write`.
+#[derive(Debug, Clone, Default)]
+pub struct JwtConfig {
+    pub hs256_secret: Option<String>,
+    pub rs256_public_key: Option<Vec<u8>>,
+    pub jwks: Vec<Jwk>,
+    pub issuer: Option<String>,
+    pub audience: Option<String>,
+    pub tenant_claim: String,
+    pub default_scopes: Vec<Scope>,
+}
+
+/// An RSA key from a JWKS file.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Jwk {
+    pub kid: Option<String>,
+    pub kty: String,
+    pub n: Option<String>,
+    pub e: Option<String>,
+}
+
+#[derive(Deserialize)]
+struct JwkSet {
+    keys: Vec<Jwk>,
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: String,
+    /// Space-separated, as in OAuth 2.0.
+    scope: Option<String>,
+    #[serde(flatten)]
+    other: HashMap<String, Value>,
+}
+
+impl JwtConfig {
+    pub fn from_rocket(rocket: &rocket::Rocket) -> Result<Option<JwtConfig>, Box<dyn Error>> {
+        let config = rocket.config();
+        let mut jwt = JwtConfig {
+            hs256_secret: config.get_string("jwt_hs256_secret").ok(),
+            issuer: config.get_string("jwt_issuer").ok(),
+            audience: config.get_string("jwt_audience").ok(),
+            tenant_claim: config.get_string("jwt_tenant_claim").unwrap_or("tenant".to_string()),
+            default_scopes: vec![Scope::EventsRead, Scope::CommentsWrite],
+            ..Default::default()
+        };
+        if let Ok(path) = config.get_string("jwt_rs256_public_key") {
+            jwt.rs256_public_key = Some(fs::read(&path)?);
+        }
+        if let Ok(path) = config.get_string("jwt_jwks") {
+            let set: JwkSet = serde_json::from_str(&fs::read_to_string(&path)?)?;
+            jwt.jwks = set.keys.into_iter().filter(|k| k.kty == "RSA").collect();
+        }
+        if let Ok(scopes) = config.get_string("jwt_default_scopes") {
+            jwt.default_scopes = super::parse_scopes(&scopes)?;
+        }
+
+        if jwt.hs256_secret.is_none() && jwt.rs256_public_key.is_none() && jwt.jwks.is_empty() {
+            return Ok(None);
+        }
+        Ok(Some(jwt))
+    }
+
+    /// Checks the signature, expiry, issuer and audience of a token, and
+    /// returns who it was issued to.
+    pub fn verify(&self, token: &str) -> Result<Principal, String> {
+        let header = decode_header(token).map_err(|err| format!("Invalid token: {}", err))?;
+        let key = match header.alg {
+            Algorithm::HS256 => {
+                let secret = self.hs256_secret.as_ref().ok_or("HS256 tokens are not accepted")?;
+                DecodingKey::from_secret(secret.as_bytes())
+            },
+            Algorithm::RS256 => self.rsa_key(header.kid.as_ref())?,
+            _ => return Err("Unsupported token algorithm".to_string()),
+        };
+
+        let mut validation = Validation::new(header.alg);
+        validation.iss = self.issuer.clone();
+        if let Some(ref audience) = self.audience {
+            validation.set_audience(&[audience]);
+        }
+        let claims = decode::<Claims>(token, &key, &validation)
+            .map_err(|err| format!("Invalid token: {}", err))?
+            .claims;
+
+        let scopes = match claims.scope {
+            Some(scope) => scope.split_whitespace().filter_map(Scope::parse).collect(),
+            None => self.default_scopes.clone(),
+        };
+        let tenant = claims.other.get(&self.tenant_claim).and_then(|t| t.as_str()).map(|t| t.to_string());
+        Ok(Principal { client: format!("jwt:{}", claims.sub), subject: claims.sub, scopes, tenant })
+    }
+
+    fn rsa_key(&self, kid: Option<&String>) -> Result<DecodingKey, String> {
+        let jwk = match kid {
+            Some(kid) => self.jwks.iter().find(|k| k.kid.as_ref() == Some(kid)),
+            None if self.jwks.len() == 1 => self.jwks.first(),
+            None => None,
+        };
+        if let Some(Jwk { n: Some(n), e: Some(e), .. }) = jwk {
+            return Ok(DecodingKey::from_rsa_components(n, e));
+        }
+        match self.rs256_public_key {
+            Some(ref pem) => DecodingKey::from_rsa_pem(pem).map_err(|err| format!("Invalid RS256 key: {}", err)),
+            None => Err("No key for this token".to_string()),
+        }
+    }
+}
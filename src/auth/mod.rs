@@ -0,0 +1,511 @@
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::marker::PhantomData;
+use std::path::{Path, PathBuf};
+
+use chrono::Utc;
+use rocket::{Outcome, State};
+use rocket::http::Status;
+use rocket::request::{self, FromRequest, Request};
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use uuid::Uuid;
+
+pub mod jwt;
+
+use self::jwt::JwtConfig;
+use crate::db::tenant::{self, Tenant};
+use crate::limits::{RateLimiter, RetryAfter, RouteGroup};
+
+static KEYS_JSON: &str = "data/keys.json";
+static TENANTS_JSON: &str = "data/tenants.json";
+static TOKEN_PREFIX: &str = "evk_";
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Scope {
+    #[serde(rename = "events:read")]
+    EventsRead,
+    #[serde(rename = "events:write")]
+    EventsWrite,
+    #[serde(rename = "comments:write")]
+    CommentsWrite,
+    /// Manages keys, and implies every other scope.
+    #[serde(rename = "admin")]
+    Admin,
+}
+
+impl Scope {
+    pub fn parse(scope: &str) -> Option<Scope> {
+        match scope.trim() {
+            "events:read" => Some(Scope::EventsRead),
+            "events:write" => Some(Scope::EventsWrite),
+            "comments:write" => Some(Scope::CommentsWrite),
+            "admin" => Some(Scope::Admin),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Scope::EventsRead => "events:read",
+            Scope::EventsWrite => "events:write",
+            Scope::CommentsWrite => "comments:write",
+            Scope::Admin => "admin",
+        }
+    }
+}
+
+/// Parses a comma-separated list of scopes.
+pub fn parse_scopes(scopes: &str) -> Result<Vec<Scope>, String> {
+    scopes.split(',')
+        .filter(|s| !s.trim().is_empty())
+        .map(|s| Scope::parse(s).ok_or(format!("Unknown scope {}", s)))
+        .collect()
+}
+
+/// Who a request was authenticated as: the subject of a JWT, or the name of
+/// an API key. Credentials bound to a tenant carry its id.
+#[derive(Debug, Clone)]
+pub struct Principal {
+    pub subject: String,
+    /// The rate limit bucket of the credentials, `key:<id>` for API keys,
+    /// whose names need not be unique, and `jwt:<sub>` for tokens.
+    pub client: String,
+    pub scopes: Vec<Scope>,
+    pub tenant: Option<String>,
+}
+
+impl Principal {
+    pub fn allows(&self, scope: Scope) -> bool {
+        self.scopes.iter().any(|s| *s == scope || *s == Scope::Admin)
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.scopes.contains(&Scope::Admin)
+    }
+}
+
+/// A stored API key. Only the SHA-256 hash of the token is kept; the token
+/// itself is shown once, when the key is created.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ApiKey {
+    pub id: String,
+    pub name: String,
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub hash: String,
+    pub scopes: Vec<Scope>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub tenant: Option<String>,
+    pub created: i64,
+}
+
+impl ApiKey {
+    pub fn principal(&self) -> Principal {
+        Principal {
+            subject: self.name.clone(),
+            client: format!("key:{}", self.id),
+            scopes: self.scopes.clone(),
+            tenant: self.tenant.clone(),
+        }
+    }
+
+    /// The key without its hash, for listing.
+    pub fn redacted(&self) -> ApiKey {
+        ApiKey { hash: String::new(), ..self.clone() }
+    }
+}
+
+/// Request body for creating a key.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewApiKey {
+    pub name: String,
+    pub scopes: Vec<Scope>,
+    #[serde(default)]
+    pub tenant: Option<String>,
+}
+
+/// API keys kept as a JSON file.
+pub struct KeyStore {
+    path: PathBuf,
+}
+
+impl KeyStore {
+    pub fn new<P: AsRef<Path>>(path: P) -> KeyStore {
+        KeyStore { path: path.as_ref().to_path_buf() }
+    }
+
+    pub fn list(&self) -> Result<Vec<ApiKey>, Box<dyn Error>> {
+        if !self.path.exists() {
+            return Ok(Vec::new());
+        }
+        Ok(serde_json::from_str(&fs::read_to_string(&self.path)?)?)
+    }
+
+    /// Creates a key and returns it together with its token.
+    pub fn create(&self, name: &str, scopes: Vec<Scope>, tenant: Option<String>) -> Result<(ApiKey, String), Box<dyn Error>> {
+        let token = format!(
+            "{}{}{}",
+            TOKEN_PREFIX,
+            Uuid::new_v4().to_simple(),
+            Uuid::new_v4().to_simple(),
+        );
+        let key = ApiKey {
+            id: Uuid::new_v4().to_hyphenated().to_string(),
+            name: name.to_string(),
+            hash: hash_token(&token),
+            scopes,
+            tenant,
+            created: Utc::now().timestamp_millis(),
+        };
+        let mut keys = self.list()?;
+        keys.push(key.clone());
+        self.write(&keys)?;
+        Ok((key, token))
+    }
+
+    /// Removes a key. Returns whether it existed.
+    pub fn revoke(&self, key_id: &str) -> Result<bool, Box<dyn Error>> {
+        let mut keys = self.list()?;
+        let count = keys.len();
+        keys.retain(|k| k.id != key_id);
+        if keys.len() == count {
+            return Ok(false);
+        }
+        self.write(&keys)?;
+        Ok(true)
+    }
+
+    pub fn find(&self, token: &str) -> Result<Option<ApiKey>, Box<dyn Error>> {
+        let hash = hash_token(token);
+        Ok(self.list()?.into_iter().find(|k| k.hash == hash))
+    }
+
+    fn write(&self, keys: &[ApiKey]) -> Result<(), Box<dyn Error>> {
+        if let Some(dir) = self.path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(keys)?)?;
+        Ok(())
+    }
+}
+
+impl Default for KeyStore {
+    fn default() -> KeyStore {
+        KeyStore::new(KEYS_JSON)
+    }
+}
+
+pub fn hash_token(token: &str) -> String {
+    format!("{:x}", Sha256::digest(token.as_bytes()))
+}
+
+/// Authentication settings read from the `[global]` section of `Rocket.toml`:
+///
+/// * `api_keys`: path of the key file. Defaults to `data/keys.json`.
+/// * `anonymous_scopes`: comma-separated scopes granted to requests without
+///   a key, e.g. `"events:read"` for a publicly readable server. Defaults to
+///   none.
+///
+/// * `tenants`: path of the tenant file, a JSON list of
+///   `{ "id": ..., "appNames": [...] }`. Defaults to `data/tenants.json`.
+///   Callers bound to a tenant only see its events; other callers see all.
+///
+/// Bearer tokens that look like JWTs are checked against `jwt` instead of
+/// the key file; see `JwtConfig` for its settings.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    pub api_keys: String,
+    pub anonymous_scopes: Vec<Scope>,
+    pub jwt: Option<JwtConfig>,
+    pub tenants: Vec<Tenant>,
+}
+
+impl AuthConfig {
+    pub fn from_rocket(rocket: &rocket::Rocket) -> Result<AuthConfig, Box<dyn Error>> {
+        let anonymous_scopes = match rocket.config().get_string("anonymous_scopes") {
+            Ok(scopes) => parse_scopes(&scopes)?,
+            Err(_) => Vec::new(),
+        };
+        Ok(AuthConfig {
+            api_keys: rocket.config().get_string("api_keys").unwrap_or(KEYS_JSON.to_string()),
+            anonymous_scopes,
+            jwt: JwtConfig::from_rocket(rocket)?,
+            tenants: tenant::read_tenants(rocket.config().get_string("tenants").unwrap_or(TENANTS_JSON.to_string()))?,
+        })
+    }
+
+    pub fn tenant(&self, tenant_id: &str) -> Option<&Tenant> {
+        self.tenants.iter().find(|t| t.id == tenant_id)
+    }
+
+    pub fn key_store(&self) -> KeyStore {
+        KeyStore::new(&self.api_keys)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub enum AuthError {
+    Missing,
+    Invalid,
+    InvalidToken(String),
+    UnknownTenant(String),
+    RateLimited(u64),
+    Forbidden(Scope),
+    Unavailable,
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AuthError::Missing => write!(f, "An API key or token is required"),
+            AuthError::Invalid => write!(f, "Invalid API key"),
+            AuthError::InvalidToken(reason) => write!(f, "{}", reason),
+            AuthError::UnknownTenant(tenant_id) => write!(f, "Unknown tenant {}", tenant_id),
+            AuthError::RateLimited(seconds) => write!(f, "Too many requests, retry in {} seconds", seconds),
+            AuthError::Forbidden(scope) => write!(f, "The credentials lack the {} scope", scope.as_str()),
+            AuthError::Unavailable => write!(f, "API keys could not be read"),
+        }
+    }
+}
+
+/// The first authentication failure of a request, kept for the 401, 403 and
+/// 429 catchers.
+pub struct AuthFailure(pub Option<AuthError>);
+
+/// The scope a route requires, used as the parameter of `Authorized`.
+pub trait RequiredScope {
+    const SCOPE: Scope;
+}
+
+pub mod require {
+    use super::{RequiredScope, Scope};
+
+    pub struct EventsRead;
+    pub struct EventsWrite;
+    pub struct CommentsWrite;
+    pub struct Admin;
+
+    impl RequiredScope for EventsRead {
+        const SCOPE: Scope = Scope::EventsRead;
+    }
+
+    impl RequiredScope for EventsWrite {
+        const SCOPE: Scope = Scope::EventsWrite;
+    }
+
+    impl RequiredScope for CommentsWrite {
+        const SCOPE: Scope = Scope::CommentsWrite;
+    }
+
+    impl RequiredScope for Admin {
+        const SCOPE: Scope = Scope::Admin;
+    }
+}
+
+/// Request guard admitting requests whose key or token has scope `S`, e.g.
+/// `_auth: Authorized<require::EventsWrite>`. The credentials are read from
+/// `Authorization: Bearer <token>`. `principal` is `None` for requests let
+/// in by `anonymous_scopes`, and `tenant` is the principal's tenant, if any.
+pub struct Authorized<S: RequiredScope> {
+    pub principal: Option<Principal>,
+    pub tenant: Option<Tenant>,
+    scope: PhantomData<S>,
+}
+
+impl<S: RequiredScope> Authorized<S> {
+    pub fn subject(&self) -> Option<String> {
+        self.principal.as_ref().map(|p| p.subject.clone())
+    }
+
+    pub fn is_admin(&self) -> bool {
+        self.principal.as_ref().map_or(false, |p| p.is_admin())
+    }
+}
+
+impl<'a, 'r, S: RequiredScope> FromRequest<'a, 'r> for Authorized<S> {
+    type Error = AuthError;
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, Self::Error> {
+        let config = match request.guard::<State<AuthConfig>>() {
+            Outcome::Success(config) => config,
+            _ => return fail(request, Status::InternalServerError, AuthError::Unavailable),
+        };
+
+        let token = request.headers().get_one("Authorization")
+            .and_then(|value| {
+                let mut parts = value.splitn(2, ' ');
+                match (parts.next(), parts.next()) {
+                    (Some(scheme), Some(token)) if scheme.eq_ignore_ascii_case("bearer") => Some(token.trim()),
+                    _ => None,
+                }
+            });
+
+        let token = match token {
+            Some(token) => token,
+            None if config.anonymous_scopes.contains(&S::SCOPE) => {
+                if let Err(seconds) = rate_limit::<S>(request, &ip_client(request)) {
+                    return fail(request, Status::TooManyRequests, AuthError::RateLimited(seconds));
+                }
+                return Outcome::Success(Authorized { principal: None, tenant: None, scope: PhantomData });
+            },
+            None => return unauthorized::<S, _>(request, AuthError::Missing),
+        };
+
+        let principal = match config.jwt {
+            Some(ref jwt) if token.matches('.').count() == 2 => match jwt.verify(token) {
+                Ok(principal) => principal,
+                Err(reason) => return unauthorized::<S, _>(request, AuthError::InvalidToken(reason)),
+            },
+            _ => match config.key_store().find(token) {
+                Ok(Some(key)) => key.principal(),
+                Ok(None) => return unauthorized::<S, _>(request, AuthError::Invalid),
+                Err(_) => return fail(request, Status::InternalServerError, AuthError::Unavailable),
+            },
+        };
+
+        let tenant = match principal.tenant {
+            Some(ref tenant_id) => match config.tenant(tenant_id) {
+                Some(tenant) => Some(tenant.clone()),
+                None => return unauthorized::<S, _>(request, AuthError::UnknownTenant(tenant_id.clone())),
+            },
+            None => None,
+        };
+
+        if !principal.allows(S::SCOPE) {
+            return fail(request, Status::Forbidden, AuthError::Forbidden(S::SCOPE));
+        }
+        if let Err(seconds) = rate_limit::<S>(request, &principal.client) {
+            return fail(request, Status::TooManyRequests, AuthError::RateLimited(seconds));
+        }
+        Outcome::Success(Authorized { principal: Some(principal), tenant, scope: PhantomData })
+    }
+}
+
+/// Counts the request against the client's limit for the route's group.
+fn rate_limit<S: RequiredScope>(request: &Request, client: &str) -> Result<(), u64> {
+    match request.guard::<State<RateLimiter>>() {
+        Outcome::Success(limiter) => {
+            limiter.take(RouteGroup::for_scope(S::SCOPE), client).map_err(|seconds| {
+                request.local_cache(|| RetryAfter(Some(seconds)));
+                seconds
+            })
+        },
+        _ => Ok(()),
+    }
+}
+
+fn ip_client(request: &Request) -> String {
+    format!("ip:{}", request.client_ip().map_or("unknown".to_string(), |ip| ip.to_string()))
+}
+
+/// Fails with 401, after counting the attempt against the client's IP so
+/// that guessing credentials is rate limited too.
+fn unauthorized<S: RequiredScope, T>(request: &Request, error: AuthError) -> request::Outcome<T, AuthError> {
+    if let Err(seconds) = rate_limit::<S>(request, &ip_client(request)) {
+        return fail(request, Status::TooManyRequests, AuthError::RateLimited(seconds));
+    }
+    fail(request, Status::Unauthorized, error)
+}
+
+fn fail<T>(request: &Request, status: Status, error: AuthError) -> request::Outcome<T, AuthError> {
+    request.local_cache(|| AuthFailure(Some(error.clone())));
+    Outcome::Failure((status, error))
+}
+
+/// Describes why a request was turned away, for the error catchers.
+pub fn failure_description(request: &Request) -> String {
+    match request.local_cache(|| AuthFailure(None)).0 {
+        Some(ref error) => error.to_string(),
+        None => "Not authorized".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::{Data, Route};
+    use rocket::config::{Config, Environment};
+    use rocket::handler::{self, Handler};
+    use rocket::http::{Header, Method};
+    use rocket::local::Client;
+
+    use super::*;
+    use crate::limits::Rate;
+
+    /// Answers with the caller's subject when `Authorized<EventsWrite>`
+    /// lets the request in.
+    #[derive(Clone)]
+    struct Guarded;
+
+    impl Handler for Guarded {
+        fn handle<'r>(&self, request: &'r Request, data: Data) -> handler::Outcome<'r> {
+            match request.guard::<Authorized<require::EventsWrite>>() {
+                Outcome::Success(auth) => handler::Outcome::from(request, auth.subject().unwrap_or_default()),
+                Outcome::Failure((status, _)) => handler::Outcome::Failure(status),
+                Outcome::Forward(_) => handler::Outcome::Forward(data),
+            }
+        }
+    }
+
+    fn client(path: &Path, limiter: RateLimiter) -> Client {
+        let config = AuthConfig {
+            api_keys: path.to_string_lossy().into_owned(),
+            anonymous_scopes: Vec::new(),
+            jwt: None,
+            tenants: Vec::new(),
+        };
+        let rocket = rocket::custom(Config::build(Environment::Development).finalize().unwrap())
+            .manage(config)
+            .manage(limiter)
+            .mount("/", vec![Route::new(Method::Post, "/", Guarded)]);
+        Client::new(rocket).unwrap()
+    }
+
+    fn post(client: &Client, token: Option<&str>) -> (Status, Option<String>) {
+        let mut request = client.post("/");
+        if let Some(token) = token {
+            request.add_header(Header::new("Authorization", format!("Bearer {}", token)));
+        }
+        let mut response = request.dispatch();
+        (response.status(), response.body_string())
+    }
+
+    #[test]
+    fn guard_checks_keys_and_scopes() {
+        let path = std::env::temp_dir().join(format!("event-api-test-{}.json", Uuid::new_v4()));
+        let keys = KeyStore::new(&path);
+        let (_, reader) = keys.create("reader", vec![Scope::EventsRead], None).unwrap();
+        let (_, writer) = keys.create("writer", vec![Scope::EventsWrite], None).unwrap();
+        let client = client(&path, RateLimiter::new(None, None));
+
+        assert_eq!(post(&client, None).0, Status::Unauthorized);
+        assert_eq!(post(&client, Some("evk_wrong")).0, Status::Unauthorized);
+        assert_eq!(post(&client, Some(&reader)).0, Status::Forbidden);
+        assert_eq!(post(&client, Some(&writer)), (Status::Ok, Some("writer".to_string())));
+    }
+
+    #[test]
+    fn failed_attempts_count_against_the_client() {
+        let path = std::env::temp_dir().join(format!("event-api-test-{}.json", Uuid::new_v4()));
+        let keys = KeyStore::new(&path);
+        let (_, writer) = keys.create("writer", vec![Scope::EventsWrite], None).unwrap();
+        let client = client(&path, RateLimiter::new(None, Some(Rate::parse("1/h").unwrap())));
+
+        assert_eq!(post(&client, Some("evk_wrong")).0, Status::Unauthorized);
+        assert_eq!(post(&client, Some("evk_wrong")).0, Status::TooManyRequests);
+        assert_eq!(post(&client, Some(&writer)).0, Status::Ok);
+    }
+
+    #[test]
+    fn keys_with_the_same_name_have_their_own_buckets() {
+        let path = std::env::temp_dir().join(format!("event-api-test-{}.json", Uuid::new_v4()));
+        let keys = KeyStore::new(&path);
+        let (_, first) = keys.create("ci", vec![Scope::EventsWrite], None).unwrap();
+        let (_, second) = keys.create("ci", vec![Scope::EventsWrite], None).unwrap();
+        let client = client(&path, RateLimiter::new(None, Some(Rate::parse("1/h").unwrap())));
+
+        assert_eq!(post(&client, Some(&first)).0, Status::Ok);
+        assert_eq!(post(&client, Some(&second)).0, Status::Ok);
+        assert_eq!(post(&client, Some(&first)).0, Status::TooManyRequests);
+    }
+}
@@ -5,49 +5,193 @@ extern crate rocket;
 #[macro_use]
 extern crate rocket_contrib;
 
+use std::collections::HashMap;
+use std::env;
+
+use chrono::Utc;
 use rocket_contrib::json::{Json, JsonValue};
-use rocket::{Request, Response};
-use rocket::http::{Header, RawStr};
-use rocket::fairing::{Fairing, Info, Kind};
+use rocket::{Request, State};
+use rocket::http::{ContentType, RawStr};
+use rocket::http::uri::Origin;
+use rocket::response::Stream;
+use rocket::response::status::Custom;
+use rocket::response::content::{Content, Html};
+use rocket::fairing::AdHoc;
 
-use lib::db::{EventDb, file_based::FileBasedEventDb};
-use lib::model::{self, Event, EventFilter, Comment, CommentFilter};
+use lib::auth::{self, require, ApiKey, AuthConfig, Authorized, NewApiKey, RequiredScope};
+use lib::cors::Cors;
+use lib::db::{self, EventDb, SharedEventDb, tenant::TenantEventDb};
+use lib::model::{self, Event, EventFilter, Comment, CommentFilter, BatchOperation, Severity, Status};
+use lib::model::metadata::MetadataQuery;
 use lib::envelope::{self, Envelope, Payload};
+use lib::feed;
+use lib::grafana::{self, Annotation, AnnotationChange, AnnotationQuery};
+use lib::ical;
+use lib::ingest::{Action, IngestResult};
+use lib::ingest::alertmanager::{self, AlertmanagerConfig, Notification};
+use lib::ingest::syslog::{self, SyslogConfig};
+use lib::limits::{self, ImportBody, Limited, RateLimiter, RetryAfter, TextLimits, TooManyRequests};
+use lib::links::{LinkBuilder, LinkConfig};
+use lib::openapi;
+use lib::transfer::{self, ExportReader, Format, ImportReport};
+use lib::webhooks::{NewSubscription, Operation, Subscription, Webhooks};
 
-pub struct CORS();
+/// Events per page when only `page` is given, and the most one page holds.
+const PAGE_SIZE: usize = 100;
+const MAX_PAGE_SIZE: usize = 1000;
 
-impl Fairing for CORS {
-    fn info(&self) -> Info {
-        Info {
-            name: "Add CORS headers to requests",
-            kind: Kind::Response
-        }
+pub struct OpenApiDocument(JsonValue);
+
+fn main() {
+    let database = match SharedEventDb::open(&database_url()) {
+        Ok(database) => database,
+        Err(err) => {
+            eprintln!("Could not open database {}: {}", database_url(), err);
+            std::process::exit(1);
+        },
+    };
+    rocket().manage(database).launch();
+}
+
+/// The backend to use, e.g. `file:data` or `sqlite:events.db`. Set with
+/// the `EVENT_DB` environment variable.
+fn database_url() -> String {
+    env::var("EVENT_DB").unwrap_or("file:data".to_string())
+}
+
+/// The backend, scoped to the caller's tenant when it has one.
+fn get_event_db<S: RequiredScope>(database: &SharedEventDb, auth: &Authorized<S>) -> Box<dyn EventDb> {
+    let edb: Box<dyn EventDb> = Box::new(database.clone());
+    match auth.tenant {
+        Some(ref tenant) => Box::new(TenantEventDb::new(edb, tenant.clone())),
+        None => edb,
+    }
+}
+
+/// The stored author of an event, which updates keep.
+fn created_by(edb: &dyn EventDb, event_id: &Option<String>) -> Option<String> {
+    event_id.as_ref()
+        .and_then(|id| edb.get_event(id.clone()).ok())
+        .and_then(|event| event.created_by)
+}
+
+/// Decodes an id from the path. An id that is not valid percent-encoded
+/// UTF-8 cannot exist, so it fails with the route's not-found error.
+fn decode_id(id: &RawStr, code: i32, description: &str) -> Result<String, Envelope> {
+    id.url_decode().map_err(|_| envelope::error(code, description.to_string()))
+}
+
+/// Parses a comma-separated query parameter, failing with the first value
+/// that does not parse.
+fn parse_list<T>(values: Option<String>, parse: fn(&str) -> Option<T>) -> Result<Option<Vec<T>>, String> {
+    values.map(|values| {
+        values.split(',')
+            .map(|value| value.trim())
+            .filter(|value| !value.is_empty())
+            .map(|value| parse(value).ok_or(value.to_string()))
+            .collect()
+    }).transpose()
+}
+
+/// Whether the event's appName is one the caller's tenant owns. Callers
+/// without a tenant may use any appName.
+fn in_tenant<S: RequiredScope>(auth: &Authorized<S>, event: &Event) -> bool {
+    auth.tenant.as_ref().map_or(true, |tenant| tenant.owns(event))
+}
+
+/// Whether the caller may change or delete a comment: its author or an admin.
+fn may_change<S: RequiredScope>(auth: &Authorized<S>, comment: &Comment) -> bool {
+    auth.is_admin() || auth.subject().as_ref() == Some(&comment.user_id)
+}
+
+/// Queues webhook deliveries for a change. The change itself went through,
+/// so failures are only logged.
+fn notify(webhooks: &Webhooks, operation: Operation, event: &Event, comment: Option<&Comment>) {
+    if let Err(err) = webhooks.notify(operation, event, comment) {
+        eprintln!("Could not queue webhooks: {}", err);
     }
+}
 
-    fn on_response(&self, request: &Request, response: &mut Response) {
-        response.set_header(Header::new("Access-Control-Allow-Origin", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Methods", "POST, GET, PATCH, OPTIONS"));
-        response.set_header(Header::new("Access-Control-Allow-Headers", "*"));
-        response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+/// Queues webhook deliveries for a change to a comment, matched against the
+/// event it belongs to.
+fn notify_comment(webhooks: &Webhooks, edb: &dyn EventDb, operation: Operation, comment: &Comment) {
+    if let Ok(event) = edb.get_event(comment.event_id.clone()) {
+        notify(webhooks, operation, &event, Some(comment));
     }
 }
 
-fn main() {
-    rocket().launch();
+/// Queues webhook deliveries for the records of an import, as updates for
+/// those that replaced stored ones and as creations for the rest.
+fn notify_imported(webhooks: &Webhooks, edb: &dyn EventDb, report: &ImportReport, comments: bool) {
+    for id in report.ids.iter() {
+        let replaced = report.replaced.contains(id);
+        if comments {
+            let operation = if replaced { Operation::CommentUpdated } else { Operation::CommentCreated };
+            if let Ok(comment) = edb.get_comment(id.clone()) {
+                notify_comment(webhooks, edb, operation, &comment);
+            }
+        } else {
+            let operation = if replaced { Operation::EventUpdated } else { Operation::EventCreated };
+            if let Ok(event) = edb.get_event(id.clone()) {
+                notify(webhooks, operation, &event, None);
+            }
+        }
+    }
 }
 
-fn get_event_db() -> impl EventDb {
-    FileBasedEventDb {}
+/// Loads the comments to embed when `embed` lists `comments`.
+fn get_embedded_comments(edb: &dyn EventDb, embed: &Option<String>, event_id: Option<String>) -> Result<Option<Vec<Comment>>, Envelope> {
+    let wanted = embed.as_ref().map_or(false, |embed| embed.split(',').any(|e| e.trim() == "comments"));
+    if !wanted {
+        return Ok(None);
+    }
+    let filter = CommentFilter { event_id: event_id, user_id: None };
+    match edb.get_comments(Some(filter)) {
+        Ok(comments) => Ok(Some(comments)),
+        Err(_) => Err(envelope::error(4, "Could not read comments".to_string())),
+    }
 }
 
-#[get("/?<from>&<to>&<appName>")]
-fn get_events(from: Option<i64>, to: Option<i64>, appName: Option<String>) -> Envelope {
-    let edb = get_event_db();
-    
-    if from.is_none() && to.is_none() && appName.is_none() {
+/// `tags` is comma-separated; events must carry all of them, or any of them
+/// with `tagMatch=any`. `meta.<key>=<value>` matches on metadata, and a bare
+/// `meta.<key>` on the key being present. `severity` and `status` are
+/// comma-separated too, and events must have one of them.
+#[get("/?<from>&<to>&<appName>&<sourceId>&<sourceName>&<tags>&<tagMatch>&<severity>&<status>&<embed>&<page>&<pageSize>&<meta..>")]
+fn get_events(
+    from: Option<i64>,
+    to: Option<i64>,
+    appName: Option<String>,
+    sourceId: Option<String>,
+    sourceName: Option<String>,
+    tags: Option<String>,
+    tagMatch: Option<String>,
+    severity: Option<String>,
+    status: Option<String>,
+    embed: Option<String>,
+    page: Option<usize>,
+    pageSize: Option<usize>,
+    meta: MetadataQuery,
+    uri: &Origin,
+    links: LinkBuilder,
+    database: State<SharedEventDb>,
+    auth: Authorized<require::EventsRead>,
+) -> Envelope {
+    let edb = get_event_db(&database, &auth);
+    let comments = match get_embedded_comments(&edb, &embed, None) {
+        Ok(comments) => comments,
+        Err(envelope) => return envelope,
+    };
+
+    let filter = match event_filter(tags, tagMatch, severity, status, meta) {
+        Ok(filter) => filter,
+        Err(envelope) => return envelope,
+    };
+
+    if from.is_none() && to.is_none() && appName.is_none() && sourceId.is_none() && sourceName.is_none()
+        && filter.tags.is_none() && filter.metadata.is_empty() && filter.severity.is_none() && filter.status.is_none() {
         return match edb.get_events(None) {
-            Ok(events) => envelope::success(model::get_events_payload(events)),
-            Err(err) => envelope::error(1, "what".to_string()),
+            Ok(events) => paged_events(&links, events, comments, page, pageSize, uri),
+            Err(_) => envelope::error(1, "what".to_string()),
         }
     }
     
@@ -55,111 +199,1049 @@ fn get_events(from: Option<i64>, to: Option<i64>, appName: Option<String>) -> En
         from: from,
         to: to,
         app_name: appName,
-        source_id: None,
-        source_name: None
+        source_id: sourceId,
+        source_name: sourceName,
+        ..filter
     };
     
     match edb.get_events(Some(filter)) {
-        Ok(events) => envelope::success(model::get_events_payload(events)),
-        Err(err) => envelope::error(1, "noo".to_string()),
+        Ok(events) => paged_events(&links, events, comments, page, pageSize, uri),
+        Err(_) => envelope::error(1, "noo".to_string()),
     }
 }
 
-#[get("/<id>")]
-fn get_event(id: &RawStr) -> Envelope {
+/// The tag, severity, status and metadata conditions that the list, export,
+/// feed and calendar routes share. The caller fills in times and names.
+fn event_filter(tags: Option<String>, tag_match: Option<String>, severity: Option<String>, status: Option<String>, meta: MetadataQuery) -> Result<EventFilter, Envelope> {
+    let any_tag = match tag_match.as_ref().map(|m| m.as_str()) {
+        None | Some("all") => false,
+        Some("any") => true,
+        Some(_) => return Err(envelope::error(10, "tagMatch must be all or any".to_string())),
+    };
+    let severity = match parse_list(severity, Severity::parse) {
+        Ok(severity) => severity,
+        Err(value) => return Err(envelope::error(10, format!("Unknown severity {}", value))),
+    };
+    let status = match parse_list(status, Status::parse) {
+        Ok(status) => status,
+        Err(value) => return Err(envelope::error(10, format!("Unknown status {}", value))),
+    };
+    let tags: Option<Vec<String>> = tags.map(|tags| {
+        tags.split(',').map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect()
+    });
+    Ok(EventFilter {
+        tags: tags,
+        any_tag: any_tag,
+        metadata: meta.0,
+        severity: severity,
+        status: status,
+        ..Default::default()
+    })
+}
+
+/// Lists events, only one page of them when `page` or `pageSize` is given.
+/// Pages count from 1, and `nextPage` repeats the request with the page
+/// moved on.
+fn paged_events(links: &LinkBuilder, events: Vec<Event>, comments: Option<Vec<Comment>>, page: Option<usize>, page_size: Option<usize>, uri: &Origin) -> Envelope {
+    if page.is_none() && page_size.is_none() {
+        return envelope::success(model::get_events_payload(links, events, comments));
+    }
+    let size = page_size.unwrap_or(PAGE_SIZE).max(1).min(MAX_PAGE_SIZE);
+    let number = page.unwrap_or(1).max(1);
+    let total = ((events.len() + size - 1) / size).max(1);
+    let events: Vec<Event> = events.into_iter().skip((number - 1).saturating_mul(size)).take(size).collect();
+    let mut envelope = envelope::success(model::get_events_payload(links, events, comments));
+    envelope.page_number = Some(number as i32);
+    envelope.total_pages = Some(total as i32);
+    if number < total {
+        let page = format!("page={}", number + 1);
+        let mut pairs: Vec<&str> = uri.query().unwrap_or("")
+            .split('&')
+            .filter(|pair| !pair.is_empty() && !pair.starts_with("page="))
+            .collect();
+        pairs.push(&page);
+        envelope.next_page = Some(links.href(&format!("{}?{}", uri.path(), pairs.join("&"))));
+    }
+    envelope
+}
+
+#[get("/<id>?<embed>")]
+fn get_event(id: &RawStr, embed: Option<String>, links: LinkBuilder, database: State<SharedEventDb>, auth: Authorized<require::EventsRead>) -> Envelope {
     let id_string = id.url_decode().expect("Failed to decode event ID.");
-    match get_event_db().get_event(id_string) {
-        Ok(event) => envelope::success(model::get_event_payload(event)),
-        Err(err) => envelope::error(3, "uh-oh".to_string()),
+    let edb = get_event_db(&database, &auth);
+    let comments = match get_embedded_comments(&edb, &embed, Some(id_string.clone())) {
+        Ok(comments) => comments,
+        Err(envelope) => return envelope,
+    };
+    match edb.get_event(id_string) {
+        Ok(event) => envelope::success(model::get_event_payload(&links, event, comments)),
+        Err(_) => envelope::error(3, "uh-oh".to_string()),
+    }
+}
+
+#[get("/appNames")]
+fn get_app_names(database: State<SharedEventDb>, auth: Authorized<require::EventsRead>) -> Envelope {
+    match get_event_db(&database, &auth).get_events(None) {
+        Ok(events) => {
+            let mut app_names: Vec<String> = events.into_iter().filter_map(|e| e.app_name).collect();
+            app_names.sort();
+            app_names.dedup();
+            envelope::success(Payload {
+                data: json!(app_names),
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Err(_) => envelope::error(1, "Could not read events".to_string()),
+    }
+}
+
+#[get("/tags")]
+fn get_tags(database: State<SharedEventDb>, auth: Authorized<require::EventsRead>) -> Envelope {
+    match get_event_db(&database, &auth).get_tags() {
+        Ok(tags) => {
+            envelope::success(Payload {
+                data: json!(tags),
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Err(_) => envelope::error(1, "Could not read tags".to_string()),
     }
 }
 
 #[post("/", data="<event>")]
-fn create_event(event: Json<Event>) -> Envelope {
-    match get_event_db().create_event(event.0) {
-        Ok(event) => envelope::success(model::get_event_payload(event)),
-        Err(err) => envelope::error(2, "no can do".to_string()),
+fn create_event(event: Limited<Event>, links: LinkBuilder, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::EventsWrite>) -> Envelope {
+    if let Err(error) = model::validate_event(&event) {
+        return envelope::error(10, error);
+    }
+    if !in_tenant(&auth, &event) {
+        return envelope::error(15, "appName does not belong to your tenant".to_string());
+    }
+    let mut event = event.into_inner();
+    event.created_by = auth.subject();
+    match get_event_db(&database, &auth).create_event(event) {
+        Ok(event) => {
+            notify(&webhooks, Operation::EventCreated, &event, None);
+            envelope::success(model::get_event_payload(&links, event, None))
+        },
+        Err(_) => envelope::error(2, "no can do".to_string()),
     }
 }
 
 #[patch("/<_id>", data="<event>")]
-fn update_event(_id: &RawStr, event: Json<Event>) -> Envelope {
-    match get_event_db().update_event(event.0) {
-        Ok(event) => envelope::success(model::get_event_payload(event)),
-        Err(err) => envelope::error(2, "no can doo".to_string()),
+fn update_event(_id: &RawStr, event: Limited<Event>, links: LinkBuilder, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::EventsWrite>) -> Envelope {
+    if let Err(error) = model::validate_event(&event) {
+        return envelope::error(10, error);
+    }
+    if !in_tenant(&auth, &event) {
+        return envelope::error(15, "appName does not belong to your tenant".to_string());
+    }
+    let edb = get_event_db(&database, &auth);
+    let mut event = event.into_inner();
+    event.created_by = created_by(&*edb, &event.id);
+    if let Err(error) = db::check_status(&*edb, &mut event, Utc::now().timestamp_millis()) {
+        return envelope::error(19, error);
+    }
+    match edb.update_event(event) {
+        Ok(event) => {
+            notify(&webhooks, Operation::EventUpdated, &event, None);
+            envelope::success(model::get_event_payload(&links, event, None))
+        },
+        Err(_) => envelope::error(2, "no can doo".to_string()),
     }
 }
 
 #[delete("/<id>")]
-fn delete_event(id: &RawStr) -> Envelope {
+fn delete_event(id: &RawStr, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::EventsWrite>) -> Envelope {
     let id_string = id.url_decode().expect("Failed to decode event ID.");
-    match get_event_db().delete_event(id_string) {
+    let edb = get_event_db(&database, &auth);
+    let existing = edb.get_event(id_string.clone()).ok();
+    match edb.delete_event(id_string) {
         Ok(result) => {
+            if let Some(ref event) = existing {
+                notify(&webhooks, Operation::EventDeleted, event, None);
+            }
             envelope::success(Payload {
                 data: json!(result),
                 links: None,
                 templates: None,
+                embedded: None,
             })
         },
-        Err(err) => envelope::error(4, "Rats".to_string()),
+        Err(_) => envelope::error(4, "Rats".to_string()),
+    }
+}
+
+#[post("/<id>/acknowledge")]
+fn acknowledge_event(id: &RawStr, links: LinkBuilder, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::EventsWrite>) -> Envelope {
+    change_status(id, Status::Acknowledged, &links, &webhooks, &database, &auth)
+}
+
+/// Resolving an event that has no end sets `to` to now.
+#[post("/<id>/resolve")]
+fn resolve_event(id: &RawStr, links: LinkBuilder, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::EventsWrite>) -> Envelope {
+    change_status(id, Status::Resolved, &links, &webhooks, &database, &auth)
+}
+
+fn change_status(id: &RawStr, status: Status, links: &LinkBuilder, webhooks: &Webhooks, database: &SharedEventDb, auth: &Authorized<require::EventsWrite>) -> Envelope {
+    let id_string = match decode_id(id, 3, "Event not found") {
+        Ok(id_string) => id_string,
+        Err(envelope) => return envelope,
+    };
+    let edb = get_event_db(database, auth);
+    let mut event = match edb.get_event(id_string) {
+        Ok(event) => event,
+        Err(_) => return envelope::error(3, "Event not found".to_string()),
+    };
+    if let Err(error) = model::set_status(&mut event, status, Utc::now().timestamp_millis()) {
+        return envelope::error(19, error);
+    }
+    match edb.update_event(event) {
+        Ok(event) => {
+            notify(webhooks, Operation::EventUpdated, &event, None);
+            envelope::success(model::get_event_payload(links, event, None))
+        },
+        Err(_) => envelope::error(2, "Could not update event".to_string()),
+    }
+}
+
+#[post("/batch?<allOrNothing>", data="<operations>")]
+fn batch_events(allOrNothing: Option<bool>, operations: Json<Vec<BatchOperation>>, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::EventsWrite>) -> Envelope {
+    let edb = get_event_db(&database, &auth);
+    let mut operations = operations.into_inner();
+    for operation in operations.iter_mut() {
+        match operation {
+            BatchOperation::Create { event } => event.created_by = auth.subject(),
+            BatchOperation::Update { event } => event.created_by = created_by(&*edb, &event.id),
+            BatchOperation::Delete { .. } => {},
+        }
+    }
+    let mut deleted: HashMap<String, Event> = HashMap::new();
+    for operation in operations.iter() {
+        if let BatchOperation::Delete { id } = operation {
+            if let Ok(event) = edb.get_event(id.clone()) {
+                deleted.insert(id.clone(), event);
+            }
+        }
+    }
+    let kinds: Vec<BatchOperation> = operations.clone();
+    let now = Utc::now().timestamp_millis();
+    let checked = db::batch_checked(&*edb, operations, allOrNothing.unwrap_or(false), |operation| match operation {
+        BatchOperation::Update { event } => db::check_status(&*edb, event, now).map_err(|error| (event.id.clone(), error)),
+        _ => Ok(()),
+    });
+    match checked {
+        Ok(results) => {
+            for result in results.iter().filter(|r| r.success) {
+                let id = result.id.clone().unwrap_or_default();
+                match kinds[result.index] {
+                    BatchOperation::Create { .. } => if let Ok(event) = edb.get_event(id) {
+                        notify(&webhooks, Operation::EventCreated, &event, None);
+                    },
+                    BatchOperation::Update { .. } => if let Ok(event) = edb.get_event(id) {
+                        notify(&webhooks, Operation::EventUpdated, &event, None);
+                    },
+                    BatchOperation::Delete { .. } => if let Some(event) = deleted.get(&id) {
+                        notify(&webhooks, Operation::EventDeleted, event, None);
+                    },
+                }
+            }
+            envelope::success(Payload {
+                data: json!(results),
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Err(_) => envelope::error(6, "Could not run batch".to_string()),
+    }
+}
+
+#[get("/export?<format>&<comments>&<from>&<to>&<appName>&<sourceId>&<sourceName>&<tags>&<tagMatch>&<severity>&<status>&<meta..>")]
+fn export_events(
+    format: Option<String>,
+    comments: Option<bool>,
+    from: Option<i64>,
+    to: Option<i64>,
+    appName: Option<String>,
+    sourceId: Option<String>,
+    sourceName: Option<String>,
+    tags: Option<String>,
+    tagMatch: Option<String>,
+    severity: Option<String>,
+    status: Option<String>,
+    meta: MetadataQuery,
+    links: LinkBuilder,
+    database: State<SharedEventDb>,
+    auth: Authorized<require::EventsRead>,
+) -> Result<Content<Stream<ExportReader>>, Envelope> {
+    let format = match Format::parse(&format.unwrap_or("ndjson".to_string())) {
+        Some(format) => format,
+        None => return Err(envelope::error(7, "Unknown export format".to_string())),
+    };
+    let content_type = match format {
+        Format::Ndjson => ContentType::new("application", "x-ndjson"),
+        Format::Csv => ContentType::CSV,
+        Format::Ical => ContentType::Calendar,
+    };
+
+    let filter = EventFilter {
+        from: from,
+        to: to,
+        app_name: appName,
+        source_id: sourceId,
+        source_name: sourceName,
+        ..event_filter(tags, tagMatch, severity, status, meta)?
+    };
+    let edb = get_event_db(&database, &auth);
+    let events = match edb.get_events(Some(filter)) {
+        Ok(events) => events,
+        Err(_) => return Err(envelope::error(1, "Could not read events".to_string())),
+    };
+
+    let export = if comments.unwrap_or(false) {
+        match edb.get_comments(None) {
+            Ok(all) => {
+                let matching: Vec<Comment> = all
+                    .into_iter()
+                    .filter(|c| events.iter().any(|e| e.id.as_ref() == Some(&c.event_id)))
+                    .collect();
+                transfer::export_comments(matching, format)
+            },
+            Err(_) => return Err(envelope::error(4, "Could not read comments".to_string())),
+        }
+    } else {
+        Ok(transfer::export_events(&links, events, format))
+    };
+
+    match export {
+        Ok(export) => Ok(Content(content_type, Stream::from(export))),
+        Err(_) => Err(envelope::error(7, "Could not export".to_string())),
+    }
+}
+
+#[post("/import?<format>&<comments>&<preserveIds>&<dryRun>", data="<data>")]
+fn import_events(
+    format: Option<String>,
+    comments: Option<bool>,
+    preserveIds: Option<bool>,
+    dryRun: Option<bool>,
+    data: ImportBody,
+    webhooks: State<Webhooks>,
+    database: State<SharedEventDb>,
+    auth: Authorized<require::EventsWrite>,
+) -> Envelope {
+    let format = match Format::parse(&format.unwrap_or("ndjson".to_string())) {
+        Some(format) => format,
+        None => return envelope::error(8, "Unknown import format".to_string()),
+    };
+    let body = data.0;
+
+    let edb = get_event_db(&database, &auth);
+    let preserve_ids = preserveIds.unwrap_or(false);
+    let dry_run = dryRun.unwrap_or(false);
+    let comments = comments.unwrap_or(false);
+    let report = if comments {
+        transfer::import_comments(&edb, &body, format, auth.subject(), preserve_ids, dry_run)
+    } else {
+        transfer::import_events(&edb, &body, format, auth.subject(), preserve_ids, dry_run)
+    };
+
+    match report {
+        Ok(report) => {
+            if !dry_run {
+                notify_imported(&webhooks, &*edb, &report, comments);
+            }
+            envelope::success(Payload {
+                data: json!(report),
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Err(_) => envelope::error(8, "Could not import".to_string()),
     }
 }
 
 #[get("/<id>/comments")]  
-fn get_comments(id: &RawStr) -> Envelope {
+fn get_comments(id: &RawStr, links: LinkBuilder, database: State<SharedEventDb>, auth: Authorized<require::EventsRead>) -> Envelope {
     let id_string = id.url_decode().expect("Failed to decode event ID.");
     let id_copy = id_string.clone();
     let filter = CommentFilter { event_id: Some(id_string), user_id: None };
-    match get_event_db().get_comments(Some(filter)) {
-        Ok(comments) => envelope::success(model::get_comments_payload(id_copy, comments)),
-        Err(err) => envelope::error(4, "huh".to_string()),
+    match get_event_db(&database, &auth).get_comments(Some(filter)) {
+        Ok(comments) => envelope::success(model::get_comments_payload(&links, id_copy, comments)),
+        Err(_) => envelope::error(4, "huh".to_string()),
     }
 }
 
-#[post("/<_id>/comments", data="<comment>")]
-fn create_comment(_id: &RawStr, comment: Json<Comment>) -> Envelope {
-    match get_event_db().create_comment(comment.0) {
-        Ok(comment) => envelope::success(model::get_comment_payload(comment)),
-        Err(err) => envelope::error(5, "oh no".to_string()),
+#[get("/feed.atom?<from>&<to>&<appName>&<sourceId>&<sourceName>&<tags>&<tagMatch>&<severity>&<status>&<limit>&<meta..>")]
+fn get_events_feed(
+    from: Option<i64>,
+    to: Option<i64>,
+    appName: Option<String>,
+    sourceId: Option<String>,
+    sourceName: Option<String>,
+    tags: Option<String>,
+    tagMatch: Option<String>,
+    severity: Option<String>,
+    status: Option<String>,
+    limit: Option<usize>,
+    meta: MetadataQuery,
+    links: LinkBuilder,
+    database: State<SharedEventDb>,
+    auth: Authorized<require::EventsRead>,
+) -> Result<Content<String>, Envelope> {
+    let filter = EventFilter {
+        from: from,
+        to: to,
+        app_name: appName,
+        source_id: sourceId,
+        source_name: sourceName,
+        ..event_filter(tags, tagMatch, severity, status, meta)?
+    };
+    match get_event_db(&database, &auth).get_events(Some(filter)) {
+        Ok(events) => {
+            let atom = feed::write_events_feed(&links, events, limit.unwrap_or(feed::ATOM_LIMIT));
+            Ok(Content(ContentType::new("application", "atom+xml"), atom))
+        },
+        Err(_) => Err(envelope::error(1, "Could not read events".to_string())),
     }
 }
 
-#[patch("/<_e_id>/comments/<_c_id>", data="<comment>")]
-fn update_comment(_e_id: &RawStr, _c_id: &RawStr, comment: Json<Comment>) -> Envelope {
-    match get_event_db().update_comment(comment.0) {
-        Ok(comment) => envelope::success(model::get_comment_payload(comment)),
-        Err(err) => envelope::error(5, "oh noo".to_string()),
+#[get("/<id>/comments/feed.atom?<userId>&<limit>")]
+fn get_comments_feed(id: &RawStr, userId: Option<String>, limit: Option<usize>, links: LinkBuilder, database: State<SharedEventDb>, auth: Authorized<require::EventsRead>) -> Result<Content<String>, Envelope> {
+    let id_string = decode_id(id, 3, "Event not found")?;
+    let filter = CommentFilter { event_id: Some(id_string.clone()), user_id: userId };
+    match get_event_db(&database, &auth).get_comments(Some(filter)) {
+        Ok(comments) => {
+            let atom = feed::write_comments_feed(&links, &id_string, comments, limit.unwrap_or(feed::ATOM_LIMIT));
+            Ok(Content(ContentType::new("application", "atom+xml"), atom))
+        },
+        Err(_) => Err(envelope::error(4, "Could not read comments".to_string())),
+    }
+}
+
+#[post("/<id>/comments", data="<comment>")]
+fn create_comment(id: &RawStr, comment: Limited<Comment>, links: LinkBuilder, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::CommentsWrite>) -> Envelope {
+    let mut comment = comment.into_inner();
+    if id.url_decode().ok().as_ref() != Some(&comment.event_id) {
+        return envelope::error(9, "Comment does not belong to event".to_string());
+    }
+    if let Some(subject) = auth.subject() {
+        comment.user_id = subject;
+    }
+    if let Err(error) = model::validate_comment(&comment) {
+        return envelope::error(10, error);
+    }
+    let edb = get_event_db(&database, &auth);
+    match edb.create_comment(comment) {
+        Ok(comment) => {
+            notify_comment(&webhooks, &*edb, Operation::CommentCreated, &comment);
+            envelope::success(model::get_comment_payload(&links, comment))
+        },
+        Err(_) => envelope::error(5, "oh no".to_string()),
+    }
+}
+
+#[get("/<e_id>/comments/<c_id>", rank = 2)]
+fn get_comment(e_id: &RawStr, c_id: &RawStr, links: LinkBuilder, database: State<SharedEventDb>, auth: Authorized<require::EventsRead>) -> Envelope {
+    let (event_id, comment_id) = match (decode_id(e_id, 9, "Comment not found"), decode_id(c_id, 9, "Comment not found")) {
+        (Ok(event_id), Ok(comment_id)) => (event_id, comment_id),
+        (Err(envelope), _) | (_, Err(envelope)) => return envelope,
+    };
+    match get_event_db(&database, &auth).get_comment(comment_id) {
+        Ok(ref comment) if comment.event_id != event_id => {
+            envelope::error(9, "Comment does not belong to event".to_string())
+        },
+        Ok(comment) => envelope::success(model::get_comment_payload(&links, comment)),
+        Err(_) => envelope::error(9, "Comment not found".to_string()),
+    }
+}
+
+#[get("/?<userId>&<eventId>")]
+fn get_all_comments(userId: Option<String>, eventId: Option<String>, links: LinkBuilder, database: State<SharedEventDb>, auth: Authorized<require::EventsRead>) -> Envelope {
+    let filter = CommentFilter { event_id: eventId, user_id: userId };
+    match get_event_db(&database, &auth).get_comments(Some(filter)) {
+        Ok(comments) => envelope::success(model::get_all_comments_payload(&links, comments)),
+        Err(_) => envelope::error(4, "Could not read comments".to_string()),
+    }
+}
+
+#[patch("/<_e_id>/comments/<c_id>", data="<comment>")]
+fn update_comment(_e_id: &RawStr, c_id: &RawStr, comment: Limited<Comment>, links: LinkBuilder, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::CommentsWrite>) -> Result<Envelope, Custom<Envelope>> {
+    let comment_id = match decode_id(c_id, 9, "Comment not found") {
+        Ok(comment_id) => comment_id,
+        Err(envelope) => return Ok(envelope),
+    };
+    let edb = get_event_db(&database, &auth);
+    let existing = match edb.get_comment(comment_id) {
+        Ok(existing) => existing,
+        Err(_) => return Ok(envelope::error(9, "Comment not found".to_string())),
+    };
+    if !may_change(&auth, &existing) {
+        return Err(forbidden_comment("Only the author or an admin may change this comment"));
+    }
+    let mut comment = comment.into_inner();
+    comment.id = existing.id;
+    comment.event_id = existing.event_id;
+    comment.user_id = existing.user_id;
+    if let Err(error) = model::validate_comment(&comment) {
+        return Ok(envelope::error(10, error));
+    }
+    match edb.update_comment(comment) {
+        Ok(comment) => {
+            notify_comment(&webhooks, &*edb, Operation::CommentUpdated, &comment);
+            Ok(envelope::success(model::get_comment_payload(&links, comment)))
+        },
+        Err(_) => Ok(envelope::error(5, "oh noo".to_string())),
     }
 }
 
 #[delete("/<_e_id>/comments/<id>")]
-fn delete_comment(_e_id: &RawStr, id: &RawStr) -> Envelope {
+fn delete_comment(_e_id: &RawStr, id: &RawStr, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::CommentsWrite>) -> Result<Envelope, Custom<Envelope>> {
     let id_string = id.url_decode().expect("Failed to decode comment ID.");
-    match get_event_db().delete_comment(id_string) {
+    let edb = get_event_db(&database, &auth);
+    let existing = match edb.get_comment(id_string.clone()) {
+        Ok(ref existing) if !may_change(&auth, existing) => {
+            return Err(forbidden_comment("Only the author or an admin may delete this comment"));
+        },
+        Ok(existing) => existing,
+        Err(_) => return Ok(envelope::error(9, "Comment not found".to_string())),
+    };
+    match edb.delete_comment(id_string) {
         Ok(result) => {
-            envelope::success(Payload {
+            notify_comment(&webhooks, &*edb, Operation::CommentDeleted, &existing);
+            Ok(envelope::success(Payload {
                 data: json!(result),
                 links: None,
                 templates: None,
+                embedded: None,
+            }))
+        },
+        Err(_) => Ok(envelope::error(4, "Could not delete comment".to_string())),
+    }
+}
+
+/// Error 14, sent with status 403 like the failures of the auth guard.
+fn forbidden_comment(description: &str) -> Custom<Envelope> {
+    Custom(rocket::http::Status::Forbidden, envelope::error(14, description.to_string()))
+}
+
+#[get("/events.ics?<from>&<to>&<appName>&<sourceId>&<sourceName>&<tags>&<tagMatch>&<severity>&<status>&<meta..>")]
+fn get_events_calendar(
+    from: Option<i64>,
+    to: Option<i64>,
+    appName: Option<String>,
+    sourceId: Option<String>,
+    sourceName: Option<String>,
+    tags: Option<String>,
+    tagMatch: Option<String>,
+    severity: Option<String>,
+    status: Option<String>,
+    meta: MetadataQuery,
+    links: LinkBuilder,
+    database: State<SharedEventDb>,
+    auth: Authorized<require::EventsRead>,
+) -> Result<Content<String>, Envelope> {
+    let filter = EventFilter {
+        from: from,
+        to: to,
+        app_name: appName,
+        source_id: sourceId,
+        source_name: sourceName,
+        ..event_filter(tags, tagMatch, severity, status, meta)?
+    };
+    match get_event_db(&database, &auth).get_events(Some(filter)) {
+        Ok(events) => Ok(Content(ContentType::Calendar, ical::write_calendar(&links, &events))),
+        Err(_) => Err(envelope::error(1, "Could not read events".to_string())),
+    }
+}
+
+/// Queues webhook deliveries for the events ingestion created or closed.
+fn notify_ingested(webhooks: &Webhooks, results: &[IngestResult]) {
+    for result in results.iter() {
+        let operation = match result.action {
+            Action::Created => Operation::EventCreated,
+            Action::Updated => Operation::EventUpdated,
+            Action::Unchanged | Action::Failed => continue,
+        };
+        if let Some(ref event) = result.event {
+            notify(webhooks, operation, event, None);
+        }
+    }
+}
+
+/// Receives Alertmanager webhook notifications.
+#[post("/alertmanager", data="<notification>")]
+fn ingest_alertmanager(
+    notification: Json<Notification>,
+    config: State<AlertmanagerConfig>,
+    webhooks: State<Webhooks>,
+    database: State<SharedEventDb>,
+    auth: Authorized<require::EventsWrite>,
+) -> Envelope {
+    let edb = get_event_db(&database, &auth);
+    let results = alertmanager::ingest(&*edb, notification.into_inner(), &config, auth.subject());
+    notify_ingested(&webhooks, &results);
+    envelope::success(Payload {
+        data: json!(results),
+        links: None,
+        templates: None,
+        embedded: None,
+    })
+}
+
+/// Lets Grafana's JSON data source test the connection and credentials.
+#[get("/")]
+fn grafana_health(_auth: Authorized<require::EventsRead>) -> Json<JsonValue> {
+    Json(json!({ "status": "OK" }))
+}
+
+/// Annotation queries from Grafana's JSON data source.
+#[post("/annotations", data="<query>")]
+fn grafana_annotations(query: Json<AnnotationQuery>, database: State<SharedEventDb>, auth: Authorized<require::EventsRead>) -> Result<Json<Vec<JsonValue>>, Envelope> {
+    let from = grafana::parse_time(&query.range.from).map_err(|error| envelope::error(10, error))?;
+    let to = grafana::parse_time(&query.range.to).map_err(|error| envelope::error(10, error))?;
+    let events = grafana_events(&database, &auth, Some(from))?;
+    let annotations = grafana::find_annotations(events, Some(from), Some(to), &query.tags(), false, grafana::ANNOTATION_LIMIT);
+    Ok(Json(annotations.into_iter()
+        .map(|annotation| json!({
+            "annotation": query.annotation,
+            "time": annotation.time,
+            "timeEnd": annotation.time_end,
+            "isRegion": annotation.is_region,
+            "text": annotation.text,
+            "tags": annotation.tags,
+        }))
+        .collect()))
+}
+
+/// Annotations in the shape of Grafana's HTTP API. `tags` is
+/// comma-separated.
+#[get("/api/annotations?<from>&<to>&<tags>&<matchAny>&<limit>")]
+fn get_grafana_annotations(
+    from: Option<i64>,
+    to: Option<i64>,
+    tags: Option<String>,
+    matchAny: Option<bool>,
+    limit: Option<usize>,
+    database: State<SharedEventDb>,
+    auth: Authorized<require::EventsRead>,
+) -> Result<Json<Vec<Annotation>>, Envelope> {
+    let tags = grafana::split_tags(&tags.unwrap_or_default());
+    let events = grafana_events(&database, &auth, from)?;
+    let limit = limit.unwrap_or(grafana::ANNOTATION_LIMIT);
+    Ok(Json(grafana::find_annotations(events, from, to, &tags, matchAny.unwrap_or(false), limit)))
+}
+
+/// The events that may fall in a Grafana time range. Only `from` goes into
+/// the filter: there, `to` also drops events ending after it, which Grafana
+/// shows as regions running past the range, so `find_annotations` applies it.
+fn grafana_events(database: &SharedEventDb, auth: &Authorized<require::EventsRead>, from: Option<i64>) -> Result<Vec<Event>, Envelope> {
+    let filter = EventFilter { from, ..Default::default() };
+    get_event_db(database, auth)
+        .get_events(Some(filter))
+        .map_err(|_| envelope::error(1, "Could not read events".to_string()))
+}
+
+#[post("/api/annotations", data="<annotation>")]
+fn create_grafana_annotation(annotation: Json<AnnotationChange>, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::EventsWrite>) -> Result<Json<JsonValue>, Envelope> {
+    let mut event = annotation.into_inner().apply(None).map_err(|error| envelope::error(10, error))?;
+    if let Err(error) = model::validate_event(&event) {
+        return Err(envelope::error(10, error));
+    }
+    if !in_tenant(&auth, &event) {
+        return Err(envelope::error(15, "appName does not belong to your tenant".to_string()));
+    }
+    event.created_by = auth.subject();
+    match get_event_db(&database, &auth).create_event(event) {
+        Ok(event) => {
+            notify(&webhooks, Operation::EventCreated, &event, None);
+            Ok(Json(json!({ "message": "Annotation added", "id": event.id })))
+        },
+        Err(_) => Err(envelope::error(2, "Could not create annotation".to_string())),
+    }
+}
+
+#[patch("/api/annotations/<id>", data="<annotation>")]
+fn update_grafana_annotation(id: &RawStr, annotation: Json<AnnotationChange>, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::EventsWrite>) -> Result<Json<JsonValue>, Envelope> {
+    let id_string = decode_id(id, 3, "Annotation not found")?;
+    let edb = get_event_db(&database, &auth);
+    let existing = match edb.get_event(id_string) {
+        Ok(existing) => existing,
+        Err(_) => return Err(envelope::error(3, "Annotation not found".to_string())),
+    };
+    let event = annotation.into_inner().apply(Some(existing)).map_err(|error| envelope::error(10, error))?;
+    if let Err(error) = model::validate_event(&event) {
+        return Err(envelope::error(10, error));
+    }
+    if !in_tenant(&auth, &event) {
+        return Err(envelope::error(15, "appName does not belong to your tenant".to_string()));
+    }
+    match edb.update_event(event) {
+        Ok(event) => {
+            notify(&webhooks, Operation::EventUpdated, &event, None);
+            Ok(Json(json!({ "message": "Annotation patched" })))
+        },
+        Err(_) => Err(envelope::error(2, "Could not update annotation".to_string())),
+    }
+}
+
+#[delete("/api/annotations/<id>")]
+fn delete_grafana_annotation(id: &RawStr, webhooks: State<Webhooks>, database: State<SharedEventDb>, auth: Authorized<require::EventsWrite>) -> Result<Json<JsonValue>, Envelope> {
+    let id_string = decode_id(id, 3, "Annotation not found")?;
+    let edb = get_event_db(&database, &auth);
+    let existing = match edb.get_event(id_string.clone()) {
+        Ok(existing) => existing,
+        Err(_) => return Err(envelope::error(3, "Annotation not found".to_string())),
+    };
+    match edb.delete_event(id_string) {
+        Ok(_) => {
+            notify(&webhooks, Operation::EventDeleted, &existing, None);
+            Ok(Json(json!({ "message": "Annotation deleted" })))
+        },
+        Err(_) => Err(envelope::error(4, "Could not delete annotation".to_string())),
+    }
+}
+
+#[get("/openapi.json")]
+fn get_openapi(document: State<OpenApiDocument>) -> Json<JsonValue> {
+    Json(document.0.clone())
+}
+
+#[get("/docs")]
+fn get_docs() -> Html<&'static str> {
+    Html(include_str!("openapi/docs.html"))
+}
+
+#[get("/")]
+fn get_api_keys(config: State<AuthConfig>, auth: Authorized<require::Admin>) -> Envelope {
+    match config.key_store().list() {
+        Ok(keys) => {
+            let keys: Vec<ApiKey> = keys.iter()
+                .filter(|k| manages_key(&auth, k))
+                .map(|k| k.redacted())
+                .collect();
+            envelope::success(Payload {
+                data: json!(keys),
+                links: None,
+                templates: None,
+                embedded: None,
             })
         },
-        Err(err) => envelope::error(4, "Could not delete comment".to_string()),
+        Err(_) => envelope::error(13, "Could not read API keys".to_string()),
     }
 }
 
+/// Creates a key. The token is only ever returned by this call. Admins bound
+/// to a tenant can only create keys for that tenant.
+#[post("/", data="<key>")]
+fn create_api_key(key: Json<NewApiKey>, config: State<AuthConfig>, auth: Authorized<require::Admin>) -> Envelope {
+    if key.name.trim().is_empty() || key.scopes.is_empty() {
+        return envelope::error(13, "A key needs a name and at least one scope".to_string());
+    }
+    let tenant = match auth.tenant {
+        Some(ref tenant) => Some(tenant.id.clone()),
+        None => key.tenant.clone(),
+    };
+    if let Some(ref tenant_id) = tenant {
+        if config.tenant(tenant_id).is_none() {
+            return envelope::error(13, format!("Unknown tenant {}", tenant_id));
+        }
+    }
+    match config.key_store().create(&key.name, key.scopes.clone(), tenant) {
+        Ok((created, token)) => {
+            let mut data = json!(created.redacted());
+            data["token"] = json!(token).0;
+            envelope::success(Payload {
+                data: data,
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Err(_) => envelope::error(13, "Could not create API key".to_string()),
+    }
+}
+
+#[delete("/<id>")]
+fn delete_api_key(id: &RawStr, config: State<AuthConfig>, auth: Authorized<require::Admin>) -> Envelope {
+    let store = config.key_store();
+    let owned = store.list().map(|keys| keys.iter().any(|k| k.id == id.as_str() && manages_key(&auth, k)));
+    if let Ok(false) = owned {
+        return envelope::error(13, "API key not found".to_string());
+    }
+    match store.revoke(id.as_str()) {
+        Ok(true) => {
+            envelope::success(Payload {
+                data: json!(true),
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Ok(false) => envelope::error(13, "API key not found".to_string()),
+        Err(_) => envelope::error(13, "Could not delete API key".to_string()),
+    }
+}
+
+#[get("/")]
+fn get_webhooks(webhooks: State<Webhooks>, auth: Authorized<require::Admin>) -> Envelope {
+    match webhooks.list() {
+        Ok(subscriptions) => {
+            let subscriptions: Vec<Subscription> = subscriptions.iter()
+                .filter(|s| manages_webhook(&auth, s))
+                .map(|s| s.redacted())
+                .collect();
+            envelope::success(Payload {
+                data: json!(subscriptions),
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Err(_) => envelope::error(18, "Could not read webhooks".to_string()),
+    }
+}
+
+/// Creates a subscription. The secret is only ever returned by this call.
+/// Subscriptions created by admins bound to a tenant only receive that
+/// tenant's changes.
+#[post("/", data="<subscription>")]
+fn create_webhook(subscription: Json<NewSubscription>, webhooks: State<Webhooks>, auth: Authorized<require::Admin>) -> Envelope {
+    let tenant = auth.tenant.as_ref().map(|tenant| tenant.id.clone());
+    match webhooks.create(subscription.into_inner(), tenant) {
+        Ok(created) => {
+            envelope::success(Payload {
+                data: json!(created),
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Err(err) => envelope::error(18, format!("Could not create webhook: {}", err)),
+    }
+}
+
+#[delete("/<id>")]
+fn delete_webhook(id: &RawStr, webhooks: State<Webhooks>, auth: Authorized<require::Admin>) -> Envelope {
+    let owned = webhooks.list().map(|subscriptions| subscriptions.iter().any(|s| s.id == id.as_str() && manages_webhook(&auth, s)));
+    if let Ok(false) = owned {
+        return envelope::error(18, "Webhook not found".to_string());
+    }
+    match webhooks.delete(id.as_str()) {
+        Ok(true) => {
+            envelope::success(Payload {
+                data: json!(true),
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Ok(false) => envelope::error(18, "Webhook not found".to_string()),
+        Err(_) => envelope::error(18, "Could not delete webhook".to_string()),
+    }
+}
+
+/// The delivery log of a subscription, newest attempt first.
+#[get("/<id>/deliveries?<limit>")]
+fn get_webhook_deliveries(id: &RawStr, limit: Option<usize>, webhooks: State<Webhooks>, auth: Authorized<require::Admin>) -> Envelope {
+    let owned = webhooks.list().map(|subscriptions| subscriptions.iter().any(|s| s.id == id.as_str() && manages_webhook(&auth, s)));
+    if let Ok(false) = owned {
+        return envelope::error(18, "Webhook not found".to_string());
+    }
+    match webhooks.deliveries(id.as_str()) {
+        Ok(mut attempts) => {
+            attempts.truncate(limit.unwrap_or(100));
+            envelope::success(Payload {
+                data: json!(attempts),
+                links: None,
+                templates: None,
+                embedded: None,
+            })
+        },
+        Err(_) => envelope::error(18, "Could not read webhook deliveries".to_string()),
+    }
+}
+
+/// Admins bound to a tenant only see and delete that tenant's webhooks.
+fn manages_webhook<S: RequiredScope>(auth: &Authorized<S>, subscription: &Subscription) -> bool {
+    match auth.tenant {
+        Some(ref tenant) => subscription.tenant.as_ref() == Some(&tenant.id),
+        None => true,
+    }
+}
+
+/// Admins bound to a tenant only see and revoke that tenant's keys.
+fn manages_key<S: RequiredScope>(auth: &Authorized<S>, key: &ApiKey) -> bool {
+    match auth.tenant {
+        Some(ref tenant) => key.tenant.as_ref() == Some(&tenant.id),
+        None => true,
+    }
+}
+
+#[catch(401)]
+fn unauthorized(request: &Request) -> Envelope {
+    envelope::error(11, auth::failure_description(request))
+}
+
+#[catch(403)]
+fn forbidden(request: &Request) -> Envelope {
+    envelope::error(12, auth::failure_description(request))
+}
+
+#[catch(413)]
+fn payload_too_large(request: &Request) -> Envelope {
+    envelope::error(17, limits::failure_description(request))
+}
+
+#[catch(422)]
+fn unprocessable_entity(request: &Request) -> Envelope {
+    envelope::error(10, limits::failure_description(request))
+}
+
+#[catch(429)]
+fn too_many_requests(request: &Request) -> TooManyRequests {
+    let retry_after = request.local_cache(|| RetryAfter(None)).0;
+    TooManyRequests(envelope::error(16, auth::failure_description(request)), retry_after)
+}
+
 fn rocket() -> rocket::Rocket {
-    rocket::ignite().attach(CORS()).mount(
+    rocket::ignite().attach(AdHoc::on_attach("Link configuration", |rocket| {
+        let config = LinkConfig::from_rocket(&rocket);
+        Ok(rocket.manage(config))
+    })).attach(AdHoc::on_attach("Authentication configuration", |rocket| {
+        match AuthConfig::from_rocket(&rocket) {
+            Ok(config) => Ok(rocket.manage(config)),
+            Err(err) => {
+                eprintln!("Invalid authentication configuration: {}", err);
+                Err(rocket)
+            },
+        }
+    })).attach(AdHoc::on_attach("Limits", |rocket| {
+        match RateLimiter::from_rocket(&rocket) {
+            Ok(limiter) => {
+                let text_limits = TextLimits::from_rocket(&rocket);
+                Ok(rocket.manage(limiter).manage(text_limits))
+            },
+            Err(err) => {
+                eprintln!("Invalid rate limit configuration: {}", err);
+                Err(rocket)
+            },
+        }
+    })).attach(AdHoc::on_attach("Alertmanager configuration", |rocket| {
+        let config = AlertmanagerConfig::from_rocket(&rocket);
+        Ok(rocket.manage(config))
+    })).attach(AdHoc::on_attach("Webhook configuration", |rocket| {
+        let tenants = rocket.state::<AuthConfig>().map(|config| config.tenants.clone()).unwrap_or_default();
+        let webhooks = Webhooks::from_rocket(&rocket, tenants);
+        Ok(rocket.manage(webhooks))
+    })).attach(AdHoc::on_launch("Webhook delivery", |rocket| {
+        if let Some(webhooks) = rocket.state::<Webhooks>() {
+            webhooks.start();
+        }
+    })).attach(AdHoc::on_attach("Syslog configuration", |rocket| {
+        match SyslogConfig::from_rocket(&rocket) {
+            Ok(config) => Ok(rocket.manage(config)),
+            Err(err) => {
+                eprintln!("Invalid syslog configuration: {}", err);
+                Err(rocket)
+            },
+        }
+    })).attach(AdHoc::on_launch("Syslog listener", |rocket| {
+        let state = (rocket.state::<SyslogConfig>(), rocket.state::<SharedEventDb>(), rocket.state::<Webhooks>());
+        if let (Some(config), Some(database), Some(webhooks)) = state {
+            if let Err(err) = syslog::start(config.clone(), database.clone(), webhooks.clone()) {
+                eprintln!("Could not start syslog listener: {}", err);
+            }
+        }
+    })).register(catchers![
+        unauthorized,
+        forbidden,
+        payload_too_large,
+        unprocessable_entity,
+        too_many_requests,
+    ]).mount(
         "/events",
         routes![
             get_events,
             get_event,
+            get_app_names,
             create_event,
             update_event,
             delete_event,
+            acknowledge_event,
+            resolve_event,
+            batch_events,
+            export_events,
+            import_events,
+            get_events_feed,
             get_comments,
+            get_comments_feed,
+            get_comment,
             create_comment,
             update_comment,
             delete_comment,
         ],
-    )
+    ).mount(
+        "/comments",
+        routes![
+            get_all_comments,
+        ],
+    ).mount(
+        "/keys",
+        routes![
+            get_api_keys,
+            create_api_key,
+            delete_api_key,
+        ],
+    ).mount(
+        "/ingest",
+        routes![
+            ingest_alertmanager,
+        ],
+    ).mount(
+        "/grafana",
+        routes![
+            grafana_health,
+            grafana_annotations,
+            get_grafana_annotations,
+            create_grafana_annotation,
+            update_grafana_annotation,
+            delete_grafana_annotation,
+        ],
+    ).mount(
+        "/webhooks",
+        routes![
+            get_webhooks,
+            create_webhook,
+            delete_webhook,
+            get_webhook_deliveries,
+        ],
+    ).mount(
+        "/",
+        routes![
+            get_events_calendar,
+            get_tags,
+            get_openapi,
+            get_docs,
+        ],
+    ).attach(AdHoc::on_attach("OpenAPI document", |rocket| {
+        let document = OpenApiDocument(JsonValue(openapi::generate(rocket.routes())));
+        Ok(rocket.manage(document))
+    })).attach(Cors)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn every_request_body_is_described() {
+        let rocket = rocket();
+        assert_eq!(openapi::undescribed_bodies(rocket.routes()), Vec::<&str>::new());
+    }
 }
@@ -0,0 +1,222 @@
+use std::collections::HashMap;
+
+use rocket::{Data, Request, Response, Route, State};
+use rocket::fairing::{Fairing, Info, Kind};
+use rocket::handler::{self, Handler};
+use rocket::http::{Header, Method, Status};
+
+/// CORS settings read from the `[global]` section of `Rocket.toml`:
+///
+/// * `cors_allowed_origins`: comma-separated origins allowed to call the
+///   API, e.g. `"https://grafana.example.com"`, or `"*"` for any. Defaults
+///   to `"*"`.
+/// * `cors_allowed_methods`: defaults to `"GET, POST, PATCH, DELETE, OPTIONS"`.
+/// * `cors_allowed_headers`: defaults to `"Accept, Authorization, Content-Type"`.
+/// * `cors_allow_credentials`: defaults to `false`. Credentials are only
+///   allowed for origins listed explicitly, never through `"*"`.
+/// * `cors_max_age`: seconds browsers may cache a preflight. Defaults to
+///   `86400`.
+#[derive(Debug, Clone)]
+pub struct CorsConfig {
+    pub allowed_origins: Vec<String>,
+    pub allowed_methods: Vec<String>,
+    pub allowed_headers: Vec<String>,
+    pub allow_credentials: bool,
+    pub max_age: Option<i64>,
+}
+
+impl CorsConfig {
+    pub fn from_rocket(rocket: &rocket::Rocket) -> CorsConfig {
+        let config = rocket.config();
+        let list = |key: &str, default: &str| -> Vec<String> {
+            config.get_string(key)
+                .unwrap_or(default.to_string())
+                .split(',')
+                .map(|value| value.trim().to_string())
+                .filter(|value| !value.is_empty())
+                .collect()
+        };
+        CorsConfig {
+            allowed_origins: list("cors_allowed_origins", "*"),
+            allowed_methods: list("cors_allowed_methods", "GET, POST, PATCH, DELETE, OPTIONS"),
+            allowed_headers: list("cors_allowed_headers", "Accept, Authorization, Content-Type"),
+            allow_credentials: config.get_bool("cors_allow_credentials").unwrap_or(false),
+            max_age: config.get_int("cors_max_age").ok().or(Some(86400)).filter(|age| *age >= 0),
+        }
+    }
+
+    /// The `Access-Control-Allow-Origin` value for a request origin, if it
+    /// is allowed. With credentials allowed, `*` matches nothing, as echoing
+    /// every origin would let any site make credentialed reads.
+    pub fn allow_origin(&self, origin: &str) -> Option<String> {
+        if self.allowed_origins.iter().any(|allowed| allowed == origin) {
+            return Some(origin.to_string());
+        }
+        if !self.allow_credentials && self.allowed_origins.iter().any(|allowed| allowed == "*") {
+            return Some("*".to_string());
+        }
+        None
+    }
+}
+
+/// Adds CORS headers to responses, and answers preflight `OPTIONS` requests
+/// for every route mounted before it is attached, so attach it last.
+pub struct Cors;
+
+impl Fairing for Cors {
+    fn info(&self) -> Info {
+        Info {
+            name: "CORS",
+            kind: Kind::Attach | Kind::Response,
+        }
+    }
+
+    fn on_attach(&self, rocket: rocket::Rocket) -> Result<rocket::Rocket, rocket::Rocket> {
+        let config = CorsConfig::from_rocket(&rocket);
+        let routes = preflight_routes(rocket.routes());
+        Ok(rocket.manage(config).mount("/", routes))
+    }
+
+    fn on_response(&self, request: &Request, response: &mut Response) {
+        let config = match request.guard::<State<CorsConfig>>() {
+            rocket::Outcome::Success(config) => config,
+            _ => return,
+        };
+        let origin = match request.headers().get_one("Origin") {
+            Some(origin) => origin,
+            None => return,
+        };
+        response.adjoin_header(Header::new("Vary", "Origin"));
+        let allow_origin = match config.allow_origin(origin) {
+            Some(allow_origin) => allow_origin,
+            None => return,
+        };
+        response.set_header(Header::new("Access-Control-Allow-Origin", allow_origin));
+        if config.allow_credentials {
+            response.set_header(Header::new("Access-Control-Allow-Credentials", "true"));
+        }
+
+        let preflight = request.method() == Method::Options
+            && request.headers().contains("Access-Control-Request-Method");
+        if preflight {
+            response.set_header(Header::new("Access-Control-Allow-Methods", config.allowed_methods.join(", ")));
+            response.set_header(Header::new("Access-Control-Allow-Headers", config.allowed_headers.join(", ")));
+            if let Some(max_age) = config.max_age {
+                response.set_header(Header::new("Access-Control-Max-Age", max_age.to_string()));
+            }
+        }
+    }
+}
+
+/// Answers `OPTIONS` with 204 and the methods the path supports.
+#[derive(Clone)]
+struct Preflight {
+    allow: String,
+}
+
+impl Handler for Preflight {
+    fn handle<'r>(&self, _request: &'r Request, _data: Data) -> handler::Outcome<'r> {
+        let response = Response::build()
+            .status(Status::NoContent)
+            .header(Header::new("Allow", self.allow.clone()))
+            .finalize();
+        handler::Outcome::Success(response)
+    }
+}
+
+/// One `OPTIONS` route per distinct path. Paths that only differ in the
+/// names of their dynamic segments, like `/events/<id>` and
+/// `/events/<_id>`, share a route, since Rocket would see them collide. The
+/// route takes the highest rank among them, so that ranks set to avoid
+/// collisions carry over.
+fn preflight_routes<'a>(routes: impl Iterator<Item = &'a Route>) -> Vec<Route> {
+    let mut paths: Vec<(String, String, isize)> = Vec::new();
+    let mut methods: HashMap<String, Vec<Method>> = HashMap::new();
+    for route in routes.filter(|r| r.method != Method::Options) {
+        let path = route.uri.path().to_string();
+        let shape = shape(&path);
+        match paths.iter_mut().find(|(s, _, _)| *s == shape) {
+            Some(entry) => entry.2 = entry.2.max(route.rank),
+            None => paths.push((shape.clone(), path, route.rank)),
+        }
+        let allowed = methods.entry(shape).or_insert_with(|| vec![Method::Options]);
+        if !allowed.contains(&route.method) {
+            allowed.push(route.method);
+        }
+    }
+
+    paths.into_iter()
+        .map(|(shape, path, rank)| {
+            let allow = methods[&shape].iter().map(|m| m.as_str()).collect::<Vec<&str>>().join(", ");
+            Route::ranked(rank, Method::Options, path, Preflight { allow })
+        })
+        .collect()
+}
+
+fn shape(path: &str) -> String {
+    path.split('/')
+        .map(|segment| if segment.starts_with('<') { "<>" } else { segment })
+        .collect::<Vec<&str>>()
+        .join("/")
+}
+
+#[cfg(test)]
+mod tests {
+    use rocket::config::{Config, Environment};
+    use rocket::local::Client;
+
+    use super::*;
+
+    fn client(origins: &str, credentials: bool) -> Client {
+        let config = Config::build(Environment::Development)
+            .extra("cors_allowed_origins", origins)
+            .extra("cors_allow_credentials", credentials)
+            .finalize()
+            .unwrap();
+        let handler = Preflight { allow: String::new() };
+        let routes = vec![
+            Route::new(Method::Get, "/events", handler.clone()),
+            Route::new(Method::Get, "/events/<id>", handler.clone()),
+            Route::ranked(2, Method::Patch, "/events/<_id>", handler.clone()),
+            Route::new(Method::Delete, "/events/<id>", handler),
+        ];
+        Client::new(rocket::custom(config).mount("/", routes).attach(Cors)).unwrap()
+    }
+
+    #[test]
+    fn shapes_ignore_segment_names() {
+        assert_eq!(shape("/events/<id>/comments/<c_id>"), shape("/events/<_e_id>/comments/<id>"));
+        assert_ne!(shape("/events/<id>"), shape("/events/import"));
+    }
+
+    #[test]
+    fn preflights_list_the_methods_of_a_path() {
+        let client = client("https://grafana.example.com", false);
+        let response = client.options("/events/42")
+            .header(Header::new("Origin", "https://grafana.example.com"))
+            .header(Header::new("Access-Control-Request-Method", "PATCH"))
+            .dispatch();
+        assert_eq!(response.status(), Status::NoContent);
+        assert_eq!(response.headers().get_one("Allow"), Some("OPTIONS, GET, PATCH, DELETE"));
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some("https://grafana.example.com"));
+        assert_eq!(response.headers().get_one("Access-Control-Max-Age"), Some("86400"));
+        assert!(response.headers().get_one("Access-Control-Allow-Credentials").is_none());
+
+        let response = client.options("/events").header(Header::new("Origin", "https://evil.example.com")).dispatch();
+        assert_eq!(response.headers().get_one("Allow"), Some("OPTIONS, GET"));
+        assert!(response.headers().get_one("Access-Control-Allow-Origin").is_none());
+    }
+
+    #[test]
+    fn credentials_are_only_allowed_for_listed_origins() {
+        let client = client("*, https://a.example.com", true);
+        let response = client.get("/events").header(Header::new("Origin", "https://a.example.com")).dispatch();
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Origin"), Some("https://a.example.com"));
+        assert_eq!(response.headers().get_one("Access-Control-Allow-Credentials"), Some("true"));
+        assert!(response.headers().get_one("Access-Control-Allow-Methods").is_none());
+
+        let response = client.get("/events").header(Header::new("Origin", "https://evil.example.com")).dispatch();
+        assert!(response.headers().get_one("Access-Control-Allow-Origin").is_none());
+        assert!(response.headers().get_one("Access-Control-Allow-Credentials").is_none());
+    }
+}
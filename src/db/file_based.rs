@@ -1,42 +1,104 @@
+use std::collections::HashSet;
 use std::fs;
 use std::error::Error;
+use std::path::{Path, PathBuf};
 
-use crate::model::{Event, EventFilter, Comment, CommentFilter};
+use crate::model::{Event, EventFilter, Comment, CommentFilter, BatchOperation, BatchResult};
 use super::EventDb;
 
-static EVENTS_JSON: &str = "data/events.json";
-static COMMENTS_JSON: &str = "data/comments.json";
+static DATA_DIR: &str = "data";
+static EVENTS_JSON: &str = "events.json";
+static COMMENTS_JSON: &str = "comments.json";
 
-pub struct FileBasedEventDb {}
+pub struct FileBasedEventDb {
+    pub events_path: PathBuf,
+    pub comments_path: PathBuf,
+}
+
+impl FileBasedEventDb {
+    /// Opens the store kept as `events.json` and `comments.json` in `dir`.
+    pub fn new<P: AsRef<Path>>(dir: P) -> FileBasedEventDb {
+        FileBasedEventDb {
+            events_path: dir.as_ref().join(EVENTS_JSON),
+            comments_path: dir.as_ref().join(COMMENTS_JSON),
+        }
+    }
+
+    fn read_events(&self) -> Result<Vec<Event>, Box<dyn Error>> {
+        if !self.events_path.exists() {
+            return Ok(Vec::new());
+        }
+        let data = fs::read_to_string(&self.events_path).expect("Error reading from events file.");
+        let events: Vec<Event> = serde_json::from_str(&data)?;
+        Ok(events)
+    }
+
+    fn write_events(&self, events: Vec<Event>) {
+        let data = serde_json::to_string(&events).expect("Failed to serialize events.");
+        create_parent(&self.events_path);
+        fs::write(&self.events_path, data).expect("Failed to write events file.");
+    }
+
+    fn read_comments(&self) -> Result<Vec<Comment>, Box<dyn Error>> {
+        if !self.comments_path.exists() {
+            return Ok(Vec::new());
+        }
+        let data = fs::read_to_string(&self.comments_path).expect("Error reading from comments file.");
+        let comments: Vec<Comment> = serde_json::from_str(&data)?;
+        Ok(comments)
+    }
+
+    fn write_comments(&self, comments: Vec<Comment>) {
+        let data = serde_json::to_string(&comments).expect("Failed to serialize comments.");
+        create_parent(&self.comments_path);
+        fs::write(&self.comments_path, data).expect("Failed to write comments file.");
+    }
+}
+
+fn create_parent(path: &Path) {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir).expect("Failed to create data directory.");
+    }
+}
+
+impl Default for FileBasedEventDb {
+    fn default() -> FileBasedEventDb {
+        FileBasedEventDb::new(DATA_DIR)
+    }
+}
 
 impl EventDb for FileBasedEventDb {
 
     fn get_events(&self, filter: Option<EventFilter>) -> Result<Vec<Event>, Box<dyn Error>> {
-        filter_events(filter)
+        let events = self.read_events()?;
+        match filter {
+            Some(filter) => Ok(events.into_iter().filter(|e| super::event_matches(&filter, e)).collect()),
+            None => Ok(events),
+        }
     }
 
     fn get_event(&self, event_id: String) -> Result<Event, Box<dyn Error>> {
-        let events = read_events()?;
+        let events = self.read_events()?;
         let matches: Vec<Event> = events
             .into_iter()
             .filter(|e| e.id == Some(event_id.to_string()))
             .collect();
-        
-        Ok(matches.first().unwrap().clone())
+
+        matches.first().cloned().ok_or("Event not found".into())
     }
 
     fn create_event(&self, event: Event) -> Result<Event, Box<dyn Error>> {
-        let mut events = read_events()?;
+        let mut events = self.read_events()?;
         let mut new_event = event.clone();
         new_event.id = Some(super::create_uuid());
         events.push(new_event.clone());
-        write_events(events);
+        self.write_events(events);
         Ok(new_event)
     }
 
     fn update_event(&self, event: Event) -> Result<Event, Box<dyn Error>> {
         // TODO: Do not allow updating without event.id, throw error
-        let events = read_events()?;
+        let events = self.read_events()?;
         let updates = events
             .into_iter()
             .map(|e| {
@@ -47,64 +109,125 @@ impl EventDb for FileBasedEventDb {
                 }
             })
             .collect();
-        write_events(updates);
+        self.write_events(updates);
         Ok(event)
     }
 
     fn delete_event(&self, event_id: String) -> Result<bool, Box<dyn Error>> {
         let e_id = Some(event_id);
-        let mut events = read_events()?;
-        let index = events.iter().position(|e| e.id == e_id).unwrap();
+        let mut events = self.read_events()?;
+        let index = events.iter().position(|e| e.id == e_id).ok_or("Event not found")?;
         events.remove(index);
-        write_events(events);
+        self.write_events(events);
         Ok(true)
     }
-    
-    fn get_comments(&self, filter: Option<CommentFilter>) -> Result<Vec<Comment>, Box<dyn Error>> {
-        let comments = read_comments()?;
-        if filter.is_none() {
-            return Ok(comments);
+
+    fn batch_events(&self, operations: Vec<BatchOperation>, all_or_nothing: bool) -> Result<Vec<BatchResult>, Box<dyn Error>> {
+        let mut events = self.read_events()?;
+        let mut results: Vec<BatchResult> = Vec::new();
+        // Indexes of the creates, whose ids are withdrawn on rollback.
+        let mut created: Vec<usize> = Vec::new();
+
+        for (index, operation) in operations.into_iter().enumerate() {
+            if let Err(result) = super::validate_operation(index, &operation) {
+                results.push(result);
+                continue;
+            }
+            let outcome = match operation {
+                BatchOperation::Create { event } => {
+                    created.push(index);
+                    let mut new_event = event.clone();
+                    new_event.id = Some(super::create_uuid());
+                    events.push(new_event.clone());
+                    Ok(new_event.id)
+                },
+                BatchOperation::Update { event } => {
+                    match events.iter().position(|e| event.id.is_some() && e.id == event.id) {
+                        Some(position) => {
+                            events[position] = event.clone();
+                            Ok(event.id)
+                        },
+                        None => Err((event.id, "Event not found".to_string())),
+                    }
+                },
+                BatchOperation::Delete { id } => {
+                    let e_id = Some(id);
+                    match events.iter().position(|e| e.id == e_id) {
+                        Some(position) => {
+                            events.remove(position);
+                            Ok(e_id)
+                        },
+                        None => Err((e_id, "Event not found".to_string())),
+                    }
+                },
+            };
+
+            results.push(match outcome {
+                Ok(id) => BatchResult { index, id, success: true, error: None },
+                Err((id, error)) => BatchResult { index, id, success: false, error: Some(error) },
+            });
         }
 
-        let filter = filter.unwrap();
-        let event_id = filter.event_id.unwrap_or("".to_string());
-        let user_id = filter.user_id.unwrap_or("".to_string());
-            
-        Ok(comments
-            .into_iter()
-            .filter(|comment| {
-                if event_id != "" && event_id != comment.event_id {
-                    return false;
+        if all_or_nothing && results.iter().any(|r| !r.success) {
+            for result in results.iter_mut().filter(|r| r.success) {
+                result.success = false;
+                result.error = Some("Not applied, batch was rolled back".to_string());
+                if created.contains(&result.index) {
+                    result.id = None;
                 }
-                if user_id != "" && user_id != comment.user_id {
-                    return false;
-                }
-                return true;
-            })
-            .collect())
+            }
+            return Ok(results);
+        }
+
+        self.write_events(events);
+        Ok(results)
+    }
+
+    fn import_events(&self, events: Vec<Event>, preserve_ids: bool) -> Result<Vec<Event>, Box<dyn Error>> {
+        let mut existing = self.read_events()?;
+        let mut imported: Vec<Event> = Vec::new();
+        for mut event in events.into_iter() {
+            if !preserve_ids || event.id.is_none() {
+                event.id = Some(super::create_uuid());
+            }
+            match existing.iter().position(|e| e.id == event.id) {
+                Some(position) => existing[position] = event.clone(),
+                None => existing.push(event.clone()),
+            }
+            imported.push(event);
+        }
+        self.write_events(existing);
+        Ok(imported)
+    }
+    
+    fn get_comments(&self, filter: Option<CommentFilter>) -> Result<Vec<Comment>, Box<dyn Error>> {
+        let comments = self.read_comments()?;
+        match filter {
+            Some(filter) => Ok(comments.into_iter().filter(|c| super::comment_matches(&filter, c)).collect()),
+            None => Ok(comments),
+        }
     }
 
     fn get_comment(&self, comment_id: String) -> Result<Comment, Box<dyn Error>> {
         // TODO: Call self.get_comments with id in filter
-        let comments = read_comments()?;
-        let matches: Vec<Comment> = comments
-            .into_iter()
-            .filter(|c| c.id == Some(comment_id.to_string()))
-            .collect();
-        Ok(matches.first().unwrap().clone())
+        let comments = self.read_comments()?;
+        match comments.into_iter().find(|c| c.id == Some(comment_id.to_string())) {
+            Some(comment) => Ok(comment),
+            None => Err("Comment not found".into()),
+        }
     }
 
     fn create_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>> {
-        let mut comments = read_comments()?;
+        let mut comments = self.read_comments()?;
         let mut new_comment = comment.clone();
         new_comment.id = Some(super::create_uuid());
         comments.push(new_comment.clone());
-        write_comments(comments);
+        self.write_comments(comments);
         Ok(new_comment)
     }
 
     fn update_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>> {
-        let comments = read_comments()?;
+        let comments = self.read_comments()?;
         let updates = comments
             .into_iter()
             .map(|c| {
@@ -115,72 +238,109 @@ impl EventDb for FileBasedEventDb {
                 }
             })
             .collect();
-        write_comments(updates);
+        self.write_comments(updates);
         Ok(comment)
     }
 
     fn delete_comment(&self, comment_id: String) -> Result<bool, Box<dyn Error>> {
         let c_id = Some(comment_id);
-        let mut comments = read_comments()?;
-        let index = comments.iter().position(|c| c.id == c_id).unwrap();
+        let mut comments = self.read_comments()?;
+        let index = comments.iter().position(|c| c.id == c_id).ok_or("Comment not found")?;
         comments.remove(index);
-        write_comments(comments);
+        self.write_comments(comments);
         Ok(true)
     }
-}
 
-fn read_events() -> Result<Vec<Event>, Box<dyn Error>> {
-    let data = fs::read_to_string(EVENTS_JSON).expect("Error reading from events file.");
-    let events: Vec<Event> = serde_json::from_str(&data)?;
-    Ok(events)
-}
+    fn import_comments(&self, comments: Vec<Comment>, preserve_ids: bool) -> Result<Vec<Comment>, Box<dyn Error>> {
+        let event_ids: HashSet<String> = self.read_events()?.into_iter().filter_map(|e| e.id).collect();
+        if let Some(comment) = comments.iter().find(|c| !event_ids.contains(&c.event_id)) {
+            return Err(format!("Event {} not found", comment.event_id).into());
+        }
+        let mut existing = self.read_comments()?;
+        let mut imported: Vec<Comment> = Vec::new();
+        for mut comment in comments.into_iter() {
+            if !preserve_ids || comment.id.is_none() {
+                comment.id = Some(super::create_uuid());
+            }
+            match existing.iter().position(|c| c.id == comment.id) {
+                Some(position) => existing[position] = comment.clone(),
+                None => existing.push(comment.clone()),
+            }
+            imported.push(comment);
+        }
+        self.write_comments(existing);
+        Ok(imported)
+    }
 
-fn write_events(events: Vec<Event>) {
-    let data = serde_json::to_string(&events).expect("Failed to serialize events.");
-    fs::write(EVENTS_JSON, data).expect("Failed to write events file.");
-}
+    fn compact(&self) -> Result<(), Box<dyn Error>> {
+        let mut seen: HashSet<Option<String>> = HashSet::new();
+        let mut events: Vec<Event> = self.read_events()?
+            .into_iter()
+            .rev()
+            .filter(|e| seen.insert(e.id.clone()))
+            .collect();
+        events.reverse();
+        self.write_events(events);
 
-fn filter_events(filter: Option<EventFilter>) -> Result<Vec<Event>, Box<dyn Error>> {
-    let events = read_events()?;
-    if filter.is_none() {
-        return Ok(events);
-    }
-    let filter = filter.unwrap();
-    Ok(events
-       .into_iter()
-       .filter(|event| {
-           if filter.app_name.is_some() && event.app_name != filter.app_name {
-               return false;
-           }
-           if filter.source_id.is_some() && event.source_id != filter.source_id {
-               return false;
-           }
-           if filter.source_name.is_some() && event.source_name != filter.source_name {
-               return false;
-           }
-           if filter.from.is_some() {
-               if event.from < filter.from.unwrap() && event.to.is_some() && event.to.unwrap() < filter.from.unwrap() {
-                   return false;
-               }
-           }
-           if filter.to.is_some() {
-               if event.from > filter.to.unwrap() || (event.to.is_some() && event.to.unwrap() > filter.to.unwrap()) {
-                   return false;
-               }
-           }
-           return true;
-       })
-       .collect())
+        let mut seen: HashSet<Option<String>> = HashSet::new();
+        let mut comments: Vec<Comment> = self.read_comments()?
+            .into_iter()
+            .rev()
+            .filter(|c| seen.insert(c.id.clone()))
+            .collect();
+        comments.reverse();
+        self.write_comments(comments);
+        Ok(())
+    }
 }
 
-fn read_comments() -> Result<Vec<Comment>, Box<dyn Error>> {
-    let data = fs::read_to_string(COMMENTS_JSON).expect("Error reading from comments file.");
-    let comments: Vec<Comment> = serde_json::from_str(&data)?;
-    Ok(comments)
-}
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
 
-fn write_comments(comments: Vec<Comment>) {
-    let data = serde_json::to_string(&comments).expect("Failed to serialize comments.");
-    fs::write(COMMENTS_JSON, data).expect("Failed to write comments file.");
-}
+    use super::*;
+
+    fn temp_db() -> FileBasedEventDb {
+        FileBasedEventDb::new(std::env::temp_dir().join(format!("event-api-test-{}", super::super::create_uuid())))
+    }
 
+    fn event(value: serde_json::Value) -> Event {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn batch_reports_invalid_events_by_index() {
+        let edb = temp_db();
+        let operations = vec![
+            BatchOperation::Create { event: event(json!({ "from": 10, "to": 5, "text": "backwards" })) },
+            BatchOperation::Create { event: event(json!({ "from": 10, "text": "fine" })) },
+        ];
+        let results = edb.batch_events(operations, false).unwrap();
+        assert!(!results[0].success);
+        assert_eq!(results[0].error.as_deref(), Some("Event cannot end before it starts"));
+        assert!(results[1].success);
+        assert_eq!(edb.get_events(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn batch_rolls_back_when_all_or_nothing() {
+        let edb = temp_db();
+        let operations = vec![
+            BatchOperation::Create { event: event(json!({ "from": 10, "text": "fine" })) },
+            BatchOperation::Delete { id: "missing".to_string() },
+        ];
+        let results = edb.batch_events(operations, true).unwrap();
+        assert!(results.iter().all(|r| !r.success));
+        assert_eq!(results[0].id, None);
+        assert!(edb.get_events(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn deleting_missing_records_is_an_error() {
+        let edb = temp_db();
+        let err = edb.delete_event("missing".to_string()).unwrap_err();
+        assert_eq!(err.to_string(), "Event not found");
+        let err = edb.delete_comment("missing".to_string()).unwrap_err();
+        assert_eq!(err.to_string(), "Comment not found");
+    }
+}
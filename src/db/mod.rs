@@ -1,9 +1,14 @@
+use std::collections::BTreeMap;
 use std::error::Error;
+use std::sync::{Arc, Mutex, MutexGuard};
 use uuid::Uuid;
 
-use crate::model::{Event, EventFilter, Comment, CommentFilter};
+use crate::model::{self, Event, EventFilter, Comment, CommentFilter, BatchOperation, BatchResult, TagCount};
 
 pub mod file_based;
+#[cfg(feature = "sqlite")]
+pub mod sqlite;
+pub mod tenant;
 
 pub trait EventDb {
     fn get_events(&self, filter: Option<EventFilter>) -> Result<Vec<Event>, Box<dyn Error>>;
@@ -11,13 +16,330 @@ pub trait EventDb {
     fn create_event(&self, event: Event) -> Result<Event, Box<dyn Error>>;
     fn update_event(&self, event: Event) -> Result<Event, Box<dyn Error>>;
     fn delete_event(&self, event_id: String) -> Result<bool, Box<dyn Error>>;
+    fn batch_events(&self, operations: Vec<BatchOperation>, all_or_nothing: bool) -> Result<Vec<BatchResult>, Box<dyn Error>>;
+    fn import_events(&self, events: Vec<Event>, preserve_ids: bool) -> Result<Vec<Event>, Box<dyn Error>>;
     fn get_comments(&self, filter: Option<CommentFilter>) -> Result<Vec<Comment>, Box<dyn Error>>;
     fn get_comment(&self, comment_id: String) -> Result<Comment, Box<dyn Error>>;
     fn create_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>>;
     fn update_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>>;
     fn delete_comment(&self, comment_id: String) -> Result<bool, Box<dyn Error>>;
+    fn import_comments(&self, comments: Vec<Comment>, preserve_ids: bool) -> Result<Vec<Comment>, Box<dyn Error>>;
+
+    /// Every tag in use, with the number of events carrying it, ordered by
+    /// tag.
+    fn get_tags(&self) -> Result<Vec<TagCount>, Box<dyn Error>> {
+        let mut counts: BTreeMap<String, usize> = BTreeMap::new();
+        for event in self.get_events(None)? {
+            for tag in event.tags {
+                *counts.entry(tag).or_insert(0) += 1;
+            }
+        }
+        Ok(counts.into_iter().map(|(tag, count)| TagCount { tag, count }).collect())
+    }
+
+    /// Reclaims space and drops duplicate records. A no-op unless the
+    /// backend has something to compact.
+    fn compact(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+
+    /// Rebuilds the backend's indexes. A no-op for backends without indexes.
+    fn reindex(&self) -> Result<(), Box<dyn Error>> {
+        Ok(())
+    }
+}
+
+/// Opens a backend from a URL: `file:<directory>` or `sqlite:<path>`. The
+/// latter needs the `sqlite` feature.
+pub fn open(url: &str) -> Result<Box<dyn EventDb + Send>, Box<dyn Error>> {
+    let mut parts = url.splitn(2, ':');
+    let scheme = parts.next().unwrap_or("");
+    let location = parts.next().unwrap_or("");
+    match scheme {
+        "file" => Ok(Box::new(file_based::FileBasedEventDb::new(location))),
+        #[cfg(feature = "sqlite")]
+        "sqlite" => Ok(Box::new(sqlite::SqliteEventDb::open(location)?)),
+        _ => Err(format!("Unsupported database URL: {}", url).into()),
+    }
+}
+
+/// A backend opened once and shared by every request and listener. Calls
+/// take turns, so that writers do not overwrite each other.
+#[derive(Clone)]
+pub struct SharedEventDb {
+    inner: Arc<Mutex<Box<dyn EventDb + Send>>>,
+}
+
+impl SharedEventDb {
+    pub fn open(url: &str) -> Result<SharedEventDb, Box<dyn Error>> {
+        Ok(SharedEventDb::new(open(url)?))
+    }
+
+    pub fn new(edb: Box<dyn EventDb + Send>) -> SharedEventDb {
+        SharedEventDb { inner: Arc::new(Mutex::new(edb)) }
+    }
+
+    /// A panic in one call leaves the backend usable for the next.
+    fn lock(&self) -> MutexGuard<Box<dyn EventDb + Send>> {
+        self.inner.lock().unwrap_or_else(|poisoned| poisoned.into_inner())
+    }
+}
+
+impl EventDb for SharedEventDb {
+    fn get_events(&self, filter: Option<EventFilter>) -> Result<Vec<Event>, Box<dyn Error>> {
+        self.lock().get_events(filter)
+    }
+
+    fn get_event(&self, event_id: String) -> Result<Event, Box<dyn Error>> {
+        self.lock().get_event(event_id)
+    }
+
+    fn create_event(&self, event: Event) -> Result<Event, Box<dyn Error>> {
+        self.lock().create_event(event)
+    }
+
+    fn update_event(&self, event: Event) -> Result<Event, Box<dyn Error>> {
+        self.lock().update_event(event)
+    }
+
+    fn delete_event(&self, event_id: String) -> Result<bool, Box<dyn Error>> {
+        self.lock().delete_event(event_id)
+    }
+
+    fn batch_events(&self, operations: Vec<BatchOperation>, all_or_nothing: bool) -> Result<Vec<BatchResult>, Box<dyn Error>> {
+        self.lock().batch_events(operations, all_or_nothing)
+    }
+
+    fn import_events(&self, events: Vec<Event>, preserve_ids: bool) -> Result<Vec<Event>, Box<dyn Error>> {
+        self.lock().import_events(events, preserve_ids)
+    }
+
+    fn get_comments(&self, filter: Option<CommentFilter>) -> Result<Vec<Comment>, Box<dyn Error>> {
+        self.lock().get_comments(filter)
+    }
+
+    fn get_comment(&self, comment_id: String) -> Result<Comment, Box<dyn Error>> {
+        self.lock().get_comment(comment_id)
+    }
+
+    fn create_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>> {
+        self.lock().create_comment(comment)
+    }
+
+    fn update_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>> {
+        self.lock().update_comment(comment)
+    }
+
+    fn delete_comment(&self, comment_id: String) -> Result<bool, Box<dyn Error>> {
+        self.lock().delete_comment(comment_id)
+    }
+
+    fn import_comments(&self, comments: Vec<Comment>, preserve_ids: bool) -> Result<Vec<Comment>, Box<dyn Error>> {
+        self.lock().import_comments(comments, preserve_ids)
+    }
+
+    fn get_tags(&self) -> Result<Vec<TagCount>, Box<dyn Error>> {
+        self.lock().get_tags()
+    }
+
+    fn compact(&self) -> Result<(), Box<dyn Error>> {
+        self.lock().compact()
+    }
+
+    fn reindex(&self) -> Result<(), Box<dyn Error>> {
+        self.lock().reindex()
+    }
+}
+
+/// Whether an event matches a filter. Shared by the backends so that they
+/// agree on what a filter means.
+pub fn event_matches(filter: &EventFilter, event: &Event) -> bool {
+    if filter.app_name.is_some() && event.app_name != filter.app_name {
+        return false;
+    }
+    if filter.source_id.is_some() && event.source_id != filter.source_id {
+        return false;
+    }
+    if filter.source_name.is_some() && event.source_name != filter.source_name {
+        return false;
+    }
+    if let Some(ref tags) = filter.tags {
+        let matches = |tag: &String| event.tags.contains(tag);
+        let tagged = if filter.any_tag { tags.iter().any(matches) } else { tags.iter().all(matches) };
+        if !tags.is_empty() && !tagged {
+            return false;
+        }
+    }
+    if !filter.metadata.iter().all(|condition| condition.matches(&event.metadata)) {
+        return false;
+    }
+    if filter.severity.as_ref().map_or(false, |severity| !severity.contains(&event.severity)) {
+        return false;
+    }
+    if filter.status.as_ref().map_or(false, |status| !status.contains(&event.status())) {
+        return false;
+    }
+    if filter.from.is_some() {
+        if event.from < filter.from.unwrap() && event.to.is_some() && event.to.unwrap() < filter.from.unwrap() {
+            return false;
+        }
+    }
+    if filter.to.is_some() {
+        if event.from > filter.to.unwrap() || (event.to.is_some() && event.to.unwrap() > filter.to.unwrap()) {
+            return false;
+        }
+    }
+    return true;
+}
+
+/// Puts an updated event through the lifecycle rules, from the status it
+/// has in the database. An event without a status keeps the stored one, and
+/// new events may start in any status.
+pub fn check_status(edb: &dyn EventDb, event: &mut Event, now: i64) -> Result<(), String> {
+    let existing = match event.id.as_ref().and_then(|id| edb.get_event(id.clone()).ok()) {
+        Some(existing) => existing,
+        None => return Ok(()),
+    };
+    let status = event.status.unwrap_or(existing.status());
+    event.status = existing.status;
+    model::set_status(event, status, now)
+}
+
+/// Runs the operations that pass `check` as one batch and reports the others
+/// as failed at their own index. With `all_or_nothing`, a failed check rolls
+/// back the whole batch.
+pub fn batch_checked<F>(edb: &dyn EventDb, operations: Vec<BatchOperation>, all_or_nothing: bool, mut check: F) -> Result<Vec<BatchResult>, Box<dyn Error>>
+    where F: FnMut(&mut BatchOperation) -> Result<(), (Option<String>, String)>
+{
+    let mut rejected: Vec<BatchResult> = Vec::new();
+    let mut allowed: Vec<(usize, BatchOperation)> = Vec::new();
+    for (index, mut operation) in operations.into_iter().enumerate() {
+        match check(&mut operation) {
+            Ok(()) => allowed.push((index, operation)),
+            Err((id, error)) => rejected.push(BatchResult { index, id, success: false, error: Some(error) }),
+        }
+    }
+
+    if all_or_nothing && !rejected.is_empty() {
+        for (index, operation) in allowed.into_iter() {
+            let id = match operation {
+                BatchOperation::Create { .. } => None,
+                BatchOperation::Update { event } => event.id,
+                BatchOperation::Delete { id } => Some(id),
+            };
+            rejected.push(BatchResult {
+                index,
+                id,
+                success: false,
+                error: Some("Not applied, batch was rolled back".to_string()),
+            });
+        }
+        rejected.sort_by_key(|r| r.index);
+        return Ok(rejected);
+    }
+
+    let indexes: Vec<usize> = allowed.iter().map(|(index, _)| *index).collect();
+    let operations: Vec<BatchOperation> = allowed.into_iter().map(|(_, operation)| operation).collect();
+    let mut results = edb.batch_events(operations, all_or_nothing)?;
+    for result in results.iter_mut() {
+        result.index = indexes[result.index];
+    }
+    results.extend(rejected);
+    results.sort_by_key(|r| r.index);
+    Ok(results)
+}
+
+/// Checks the event of a create or update operation the way POST and PATCH
+/// do. Failures are reported as the operation's result.
+fn validate_operation(index: usize, operation: &BatchOperation) -> Result<(), BatchResult> {
+    let event = match operation {
+        BatchOperation::Create { event } | BatchOperation::Update { event } => event,
+        BatchOperation::Delete { .. } => return Ok(()),
+    };
+    model::validate_event(event).map_err(|error| BatchResult {
+        index,
+        id: event.id.clone(),
+        success: false,
+        error: Some(error),
+    })
+}
+
+pub fn comment_matches(filter: &CommentFilter, comment: &Comment) -> bool {
+    let event_id = filter.event_id.as_ref().map_or("", |id| id.as_str());
+    let user_id = filter.user_id.as_ref().map_or("", |id| id.as_str());
+    if event_id != "" && event_id != comment.event_id {
+        return false;
+    }
+    if user_id != "" && user_id != comment.user_id {
+        return false;
+    }
+    return true;
 }
 
 fn create_uuid() -> String {
     Uuid::new_v4().to_hyphenated().to_string()
 }
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use crate::model::Status;
+    use super::*;
+
+    #[test]
+    fn shared_handles_see_each_others_writes() {
+        let dir = std::env::temp_dir().join(format!("event-api-test-{}", create_uuid()));
+        let database = SharedEventDb::new(Box::new(file_based::FileBasedEventDb::new(dir)));
+        let other = database.clone();
+        let event: Event = serde_json::from_value(json!({ "from": 10, "text": "shared" })).unwrap();
+        let created = database.create_event(event).unwrap();
+        let stored = other.get_event(created.id.clone().unwrap()).unwrap();
+        assert_eq!(stored.text, "shared");
+    }
+
+    #[test]
+    fn missing_status_keeps_the_stored_one() {
+        let edb = file_based::FileBasedEventDb::new(std::env::temp_dir().join(format!("event-api-test-{}", create_uuid())));
+        let created = edb.create_event(serde_json::from_value(json!({ "from": 10, "text": "a", "status": "acknowledged" })).unwrap()).unwrap();
+        let id = created.id.clone().unwrap();
+
+        let mut patch: Event = serde_json::from_value(json!({ "id": id, "from": 10, "text": "b" })).unwrap();
+        check_status(&edb, &mut patch, 20).unwrap();
+        assert_eq!(patch.status, Some(Status::Acknowledged));
+
+        let mut patch: Event = serde_json::from_value(json!({ "id": id, "from": 10, "text": "b", "status": "resolved" })).unwrap();
+        check_status(&edb, &mut patch, 20).unwrap();
+        assert_eq!(patch.to, Some(20));
+        edb.update_event(patch).unwrap();
+
+        let mut patch: Event = serde_json::from_value(json!({ "id": id, "from": 10, "text": "b", "status": "acknowledged" })).unwrap();
+        assert!(check_status(&edb, &mut patch, 30).is_err());
+    }
+
+    #[test]
+    fn failed_checks_are_reported_at_their_index() {
+        let edb = file_based::FileBasedEventDb::new(std::env::temp_dir().join(format!("event-api-test-{}", create_uuid())));
+        let event = |text: &str| -> Event { serde_json::from_value(json!({ "from": 10, "text": text })).unwrap() };
+        let operations = || vec![
+            BatchOperation::Create { event: event("a") },
+            BatchOperation::Delete { id: "rejected".to_string() },
+            BatchOperation::Create { event: event("c") },
+        ];
+        let reject = |operation: &mut BatchOperation| match operation {
+            BatchOperation::Delete { id } => Err((Some(id.clone()), "no".to_string())),
+            _ => Ok(()),
+        };
+
+        let results = batch_checked(&edb, operations(), true, reject).unwrap();
+        assert!(results.iter().all(|r| !r.success));
+        assert_eq!(results[1].error.as_deref(), Some("no"));
+        assert!(edb.get_events(None).unwrap().is_empty());
+
+        let results = batch_checked(&edb, operations(), false, reject).unwrap();
+        let indexes: Vec<usize> = results.iter().map(|r| r.index).collect();
+        assert_eq!(indexes, vec![0, 1, 2]);
+        assert!(results[0].success && !results[1].success && results[2].success);
+        assert_eq!(edb.get_events(None).unwrap().len(), 2);
+    }
+}
@@ -0,0 +1,386 @@
+use std::error::Error;
+
+use rusqlite::{params, Connection, OptionalExtension, Transaction};
+
+use crate::model::metadata;
+use crate::model::{Event, EventFilter, Comment, CommentFilter, BatchOperation, BatchResult, TagCount};
+use super::EventDb;
+
+/// Events and comments are stored as JSON documents, with the fields that
+/// filters and lookups use copied into indexed columns.
+static SCHEMA: &str = "
+    CREATE TABLE IF NOT EXISTS events (
+        id TEXT PRIMARY KEY,
+        from_ts INTEGER NOT NULL,
+        to_ts INTEGER,
+        app_name TEXT,
+        source_id TEXT,
+        source_name TEXT,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS events_app_name ON events (app_name);
+    CREATE INDEX IF NOT EXISTS events_source_id ON events (source_id);
+    CREATE INDEX IF NOT EXISTS events_from_ts ON events (from_ts);
+    CREATE TABLE IF NOT EXISTS event_tags (
+        event_id TEXT NOT NULL,
+        tag TEXT NOT NULL,
+        PRIMARY KEY (event_id, tag)
+    );
+    CREATE INDEX IF NOT EXISTS event_tags_tag ON event_tags (tag);
+    CREATE TABLE IF NOT EXISTS comments (
+        id TEXT PRIMARY KEY,
+        event_id TEXT NOT NULL,
+        user_id TEXT NOT NULL,
+        timestamp INTEGER NOT NULL,
+        data TEXT NOT NULL
+    );
+    CREATE INDEX IF NOT EXISTS comments_event_id ON comments (event_id);
+    CREATE INDEX IF NOT EXISTS comments_user_id ON comments (user_id);
+";
+
+pub struct SqliteEventDb {
+    connection: Connection,
+}
+
+impl SqliteEventDb {
+    pub fn open(path: &str) -> Result<SqliteEventDb, Box<dyn Error>> {
+        let connection = Connection::open(path)?;
+        connection.execute_batch(SCHEMA)?;
+        Ok(SqliteEventDb { connection })
+    }
+
+    fn query_events(&self, sql: &str, values: &[&dyn rusqlite::ToSql]) -> Result<Vec<Event>, Box<dyn Error>> {
+        let mut statement = self.connection.prepare(sql)?;
+        let rows = statement.query_map(values, |row| row.get::<_, String>(0))?;
+        let mut events = Vec::new();
+        for data in rows {
+            events.push(serde_json::from_str(&data?)?);
+        }
+        Ok(events)
+    }
+
+    fn query_comments(&self, sql: &str, values: &[&dyn rusqlite::ToSql]) -> Result<Vec<Comment>, Box<dyn Error>> {
+        let mut statement = self.connection.prepare(sql)?;
+        let rows = statement.query_map(values, |row| row.get::<_, String>(0))?;
+        let mut comments = Vec::new();
+        for data in rows {
+            comments.push(serde_json::from_str(&data?)?);
+        }
+        Ok(comments)
+    }
+}
+
+fn upsert_event(tx: &Transaction, event: &Event) -> Result<(), Box<dyn Error>> {
+    tx.execute(
+        "INSERT OR REPLACE INTO events (id, from_ts, to_ts, app_name, source_id, source_name, data)
+         VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7)",
+        params![
+            event.id,
+            event.from,
+            event.to,
+            event.app_name,
+            event.source_id,
+            event.source_name,
+            serde_json::to_string(event)?,
+        ],
+    )?;
+    tx.execute("DELETE FROM event_tags WHERE event_id = ?1", params![event.id])?;
+    for tag in event.tags.iter() {
+        tx.execute("INSERT OR IGNORE INTO event_tags (event_id, tag) VALUES (?1, ?2)", params![event.id, tag])?;
+    }
+    Ok(())
+}
+
+fn delete_event(tx: &Transaction, event_id: &str) -> Result<bool, Box<dyn Error>> {
+    tx.execute("DELETE FROM event_tags WHERE event_id = ?1", params![event_id])?;
+    Ok(tx.execute("DELETE FROM events WHERE id = ?1", params![event_id])? > 0)
+}
+
+fn upsert_comment(tx: &Transaction, comment: &Comment) -> Result<(), Box<dyn Error>> {
+    tx.execute(
+        "INSERT OR REPLACE INTO comments (id, event_id, user_id, timestamp, data)
+         VALUES (?1, ?2, ?3, ?4, ?5)",
+        params![
+            comment.id,
+            comment.event_id,
+            comment.user_id,
+            comment.timestamp,
+            serde_json::to_string(comment)?,
+        ],
+    )?;
+    Ok(())
+}
+
+fn event_exists(tx: &Transaction, event_id: &Option<String>) -> Result<bool, Box<dyn Error>> {
+    let found: Option<i64> = tx
+        .query_row("SELECT 1 FROM events WHERE id = ?1", params![event_id], |row| row.get(0))
+        .optional()?;
+    Ok(found.is_some())
+}
+
+impl EventDb for SqliteEventDb {
+
+    fn get_events(&self, filter: Option<EventFilter>) -> Result<Vec<Event>, Box<dyn Error>> {
+        let filter = match filter {
+            Some(filter) => filter,
+            None => return self.query_events("SELECT data FROM events ORDER BY from_ts", &[]),
+        };
+        // Narrow down on the indexed columns, then apply the full filter.
+        // When every tag must match, any one of them narrows the search.
+        let tag = match filter.tags {
+            Some(ref tags) if !filter.any_tag => tags.first().cloned(),
+            _ => None,
+        };
+        // Likewise any one metadata key, which must be present.
+        let metadata_path = filter.metadata.iter()
+            .find(|condition| metadata::is_valid_key(&condition.key))
+            .map(|condition| format!("$.metadata.\"{}\"", condition.key));
+        let events = self.query_events(
+            "SELECT data FROM events
+             WHERE (?1 IS NULL OR app_name = ?1)
+               AND (?2 IS NULL OR source_id = ?2)
+               AND (?3 IS NULL OR source_name = ?3)
+               AND (?4 IS NULL OR id IN (SELECT event_id FROM event_tags WHERE tag = ?4))
+               AND (?5 IS NULL OR json_type(data, ?5) IS NOT NULL)
+             ORDER BY from_ts",
+            &[&filter.app_name, &filter.source_id, &filter.source_name, &tag, &metadata_path],
+        )?;
+        Ok(events.into_iter().filter(|e| super::event_matches(&filter, e)).collect())
+    }
+
+    fn get_event(&self, event_id: String) -> Result<Event, Box<dyn Error>> {
+        let mut events = self.query_events("SELECT data FROM events WHERE id = ?1", &[&event_id])?;
+        events.pop().ok_or("Event not found".into())
+    }
+
+    fn create_event(&self, event: Event) -> Result<Event, Box<dyn Error>> {
+        let mut new_event = event.clone();
+        new_event.id = Some(super::create_uuid());
+        let tx = self.connection.unchecked_transaction()?;
+        upsert_event(&tx, &new_event)?;
+        tx.commit()?;
+        Ok(new_event)
+    }
+
+    fn update_event(&self, event: Event) -> Result<Event, Box<dyn Error>> {
+        let tx = self.connection.unchecked_transaction()?;
+        if event_exists(&tx, &event.id)? {
+            upsert_event(&tx, &event)?;
+        }
+        tx.commit()?;
+        Ok(event)
+    }
+
+    fn delete_event(&self, event_id: String) -> Result<bool, Box<dyn Error>> {
+        let tx = self.connection.unchecked_transaction()?;
+        if !delete_event(&tx, &event_id)? {
+            return Err("Event not found".into());
+        }
+        tx.commit()?;
+        Ok(true)
+    }
+
+    fn batch_events(&self, operations: Vec<BatchOperation>, all_or_nothing: bool) -> Result<Vec<BatchResult>, Box<dyn Error>> {
+        let tx = self.connection.unchecked_transaction()?;
+        let mut results: Vec<BatchResult> = Vec::new();
+        // Indexes of the creates, whose ids are withdrawn on rollback.
+        let mut created: Vec<usize> = Vec::new();
+
+        for (index, operation) in operations.into_iter().enumerate() {
+            if let Err(result) = super::validate_operation(index, &operation) {
+                results.push(result);
+                continue;
+            }
+            let outcome = match operation {
+                BatchOperation::Create { event } => {
+                    created.push(index);
+                    let mut new_event = event.clone();
+                    new_event.id = Some(super::create_uuid());
+                    upsert_event(&tx, &new_event)?;
+                    Ok(new_event.id)
+                },
+                BatchOperation::Update { event } => {
+                    if event.id.is_some() && event_exists(&tx, &event.id)? {
+                        upsert_event(&tx, &event)?;
+                        Ok(event.id)
+                    } else {
+                        Err((event.id, "Event not found".to_string()))
+                    }
+                },
+                BatchOperation::Delete { id } => {
+                    if delete_event(&tx, &id)? {
+                        Ok(Some(id))
+                    } else {
+                        Err((Some(id), "Event not found".to_string()))
+                    }
+                },
+            };
+
+            results.push(match outcome {
+                Ok(id) => BatchResult { index, id, success: true, error: None },
+                Err((id, error)) => BatchResult { index, id, success: false, error: Some(error) },
+            });
+        }
+
+        if all_or_nothing && results.iter().any(|r| !r.success) {
+            for result in results.iter_mut().filter(|r| r.success) {
+                result.success = false;
+                result.error = Some("Not applied, batch was rolled back".to_string());
+                if created.contains(&result.index) {
+                    result.id = None;
+                }
+            }
+            tx.rollback()?;
+            return Ok(results);
+        }
+
+        tx.commit()?;
+        Ok(results)
+    }
+
+    fn import_events(&self, events: Vec<Event>, preserve_ids: bool) -> Result<Vec<Event>, Box<dyn Error>> {
+        let tx = self.connection.unchecked_transaction()?;
+        let mut imported: Vec<Event> = Vec::new();
+        for mut event in events.into_iter() {
+            if !preserve_ids || event.id.is_none() {
+                event.id = Some(super::create_uuid());
+            }
+            upsert_event(&tx, &event)?;
+            imported.push(event);
+        }
+        tx.commit()?;
+        Ok(imported)
+    }
+
+    fn get_comments(&self, filter: Option<CommentFilter>) -> Result<Vec<Comment>, Box<dyn Error>> {
+        let filter = filter.unwrap_or_default();
+        let comments = self.query_comments(
+            "SELECT data FROM comments
+             WHERE (?1 IS NULL OR event_id = ?1)
+               AND (?2 IS NULL OR user_id = ?2)
+             ORDER BY timestamp",
+            &[&filter.event_id, &filter.user_id],
+        )?;
+        Ok(comments.into_iter().filter(|c| super::comment_matches(&filter, c)).collect())
+    }
+
+    fn get_comment(&self, comment_id: String) -> Result<Comment, Box<dyn Error>> {
+        let mut comments = self.query_comments("SELECT data FROM comments WHERE id = ?1", &[&comment_id])?;
+        comments.pop().ok_or("Comment not found".into())
+    }
+
+    fn create_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>> {
+        let mut new_comment = comment.clone();
+        new_comment.id = Some(super::create_uuid());
+        let tx = self.connection.unchecked_transaction()?;
+        upsert_comment(&tx, &new_comment)?;
+        tx.commit()?;
+        Ok(new_comment)
+    }
+
+    fn update_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>> {
+        let tx = self.connection.unchecked_transaction()?;
+        let found: Option<i64> = tx
+            .query_row("SELECT 1 FROM comments WHERE id = ?1", params![comment.id], |row| row.get(0))
+            .optional()?;
+        if found.is_some() {
+            upsert_comment(&tx, &comment)?;
+        }
+        tx.commit()?;
+        Ok(comment)
+    }
+
+    fn delete_comment(&self, comment_id: String) -> Result<bool, Box<dyn Error>> {
+        let deleted = self.connection.execute("DELETE FROM comments WHERE id = ?1", params![comment_id])?;
+        if deleted == 0 {
+            return Err("Comment not found".into());
+        }
+        Ok(true)
+    }
+
+    fn import_comments(&self, comments: Vec<Comment>, preserve_ids: bool) -> Result<Vec<Comment>, Box<dyn Error>> {
+        let tx = self.connection.unchecked_transaction()?;
+        let mut imported: Vec<Comment> = Vec::new();
+        for mut comment in comments.into_iter() {
+            if !event_exists(&tx, &Some(comment.event_id.clone()))? {
+                return Err(format!("Event {} not found", comment.event_id).into());
+            }
+            if !preserve_ids || comment.id.is_none() {
+                comment.id = Some(super::create_uuid());
+            }
+            upsert_comment(&tx, &comment)?;
+            imported.push(comment);
+        }
+        tx.commit()?;
+        Ok(imported)
+    }
+
+    fn get_tags(&self) -> Result<Vec<TagCount>, Box<dyn Error>> {
+        let mut statement = self.connection.prepare("SELECT tag, COUNT(*) FROM event_tags GROUP BY tag ORDER BY tag")?;
+        let rows = statement.query_map(params![], |row| {
+            Ok(TagCount { tag: row.get(0)?, count: row.get::<_, i64>(1)? as usize })
+        })?;
+        let mut tags = Vec::new();
+        for tag in rows {
+            tags.push(tag?);
+        }
+        Ok(tags)
+    }
+
+    fn compact(&self) -> Result<(), Box<dyn Error>> {
+        self.connection.execute_batch("VACUUM")?;
+        Ok(())
+    }
+
+    /// Also rebuilds the tag table from the stored events, for stores
+    /// written before events had tags.
+    fn reindex(&self) -> Result<(), Box<dyn Error>> {
+        self.connection.execute_batch(SCHEMA)?;
+        let tx = self.connection.unchecked_transaction()?;
+        for event in self.get_events(None)? {
+            upsert_event(&tx, &event)?;
+        }
+        tx.commit()?;
+        self.connection.execute_batch("REINDEX")?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(value: serde_json::Value) -> Event {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn batch_rolls_back_when_all_or_nothing() {
+        let edb = SqliteEventDb::open(":memory:").unwrap();
+        let operations = vec![
+            BatchOperation::Create { event: event(json!({ "from": 10, "text": "fine" })) },
+            BatchOperation::Update { event: event(json!({ "id": "missing", "from": 10, "text": "gone" })) },
+        ];
+        let results = edb.batch_events(operations, true).unwrap();
+        assert!(results.iter().all(|r| !r.success));
+        assert_eq!(results[0].id, None);
+        assert!(edb.get_events(None).unwrap().is_empty());
+    }
+
+    #[test]
+    fn batch_applies_valid_operations_and_rejects_invalid_ones() {
+        let edb = SqliteEventDb::open(":memory:").unwrap();
+        let operations = vec![
+            BatchOperation::Create { event: event(json!({ "from": 10, "text": "" })) },
+            BatchOperation::Create { event: event(json!({ "from": 10, "text": "fine", "tags": ["a"] })) },
+        ];
+        let results = edb.batch_events(operations, false).unwrap();
+        assert!(!results[0].success);
+        assert!(results[1].success);
+        let tags = edb.get_tags().unwrap();
+        assert_eq!(tags.len(), 1);
+        assert_eq!(tags[0].count, 1);
+    }
+}
@@ -0,0 +1,258 @@
+use std::collections::HashSet;
+use std::error::Error;
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use crate::model::{Event, EventFilter, Comment, CommentFilter, BatchOperation, BatchResult};
+use super::EventDb;
+
+/// A team sharing the instance, owning the events of a set of `appName`s.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Tenant {
+    pub id: String,
+    #[serde(rename = "appNames")]
+    pub app_names: Vec<String>,
+}
+
+impl Tenant {
+    pub fn owns(&self, event: &Event) -> bool {
+        event.app_name.as_ref().map_or(false, |app_name| self.app_names.contains(app_name))
+    }
+}
+
+/// Reads tenants from a JSON file holding a list of tenants. A missing file
+/// means no tenants.
+pub fn read_tenants<P: AsRef<Path>>(path: P) -> Result<Vec<Tenant>, Box<dyn Error>> {
+    if !path.as_ref().exists() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+/// Wraps a backend so that only a tenant's events, and the comments on them,
+/// can be seen or changed. Everything else looks as if it did not exist.
+pub struct TenantEventDb {
+    inner: Box<dyn EventDb>,
+    tenant: Tenant,
+}
+
+impl TenantEventDb {
+    pub fn new(inner: Box<dyn EventDb>, tenant: Tenant) -> TenantEventDb {
+        TenantEventDb { inner, tenant }
+    }
+
+    /// The tenant's stored event, or the error a missing event gives.
+    fn owned_event(&self, event_id: &str) -> Result<Event, Box<dyn Error>> {
+        match self.inner.get_event(event_id.to_string()) {
+            Ok(event) if self.tenant.owns(&event) => Ok(event),
+            _ => Err("Event not found".into()),
+        }
+    }
+
+    fn owns_event_id(&self, event_id: &str) -> bool {
+        self.owned_event(event_id).is_ok()
+    }
+
+    fn owned_comment(&self, comment_id: &str) -> Result<Comment, Box<dyn Error>> {
+        match self.inner.get_comment(comment_id.to_string()) {
+            Ok(comment) if self.owns_event_id(&comment.event_id) => Ok(comment),
+            _ => Err("Comment not found".into()),
+        }
+    }
+
+    /// Fails ids taken by other tenants' events with an error that does not
+    /// say whose they are. Free ids and the tenant's own are allowed.
+    fn check_event_id(&self, event_id: &str) -> Result<(), Box<dyn Error>> {
+        match self.inner.get_event(event_id.to_string()) {
+            Ok(event) if !self.tenant.owns(&event) => Err("Event id unavailable".into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_comment_id(&self, comment_id: &str) -> Result<(), Box<dyn Error>> {
+        match self.inner.get_comment(comment_id.to_string()) {
+            Ok(comment) if !self.owns_event_id(&comment.event_id) => Err("Comment id unavailable".into()),
+            _ => Ok(()),
+        }
+    }
+
+    fn check_operation(&self, operation: &BatchOperation) -> Result<(), (Option<String>, String)> {
+        match operation {
+            BatchOperation::Create { event } if !self.tenant.owns(event) => {
+                Err((None, "appName does not belong to the tenant".to_string()))
+            },
+            BatchOperation::Update { event } => {
+                let owned = event.id.as_ref().map_or(false, |id| self.owns_event_id(id));
+                if !owned {
+                    Err((event.id.clone(), "Event not found".to_string()))
+                } else if !self.tenant.owns(event) {
+                    Err((event.id.clone(), "appName does not belong to the tenant".to_string()))
+                } else {
+                    Ok(())
+                }
+            },
+            BatchOperation::Delete { id } if !self.owns_event_id(id) => {
+                Err((Some(id.clone()), "Event not found".to_string()))
+            },
+            _ => Ok(()),
+        }
+    }
+}
+
+impl EventDb for TenantEventDb {
+
+    fn get_events(&self, filter: Option<EventFilter>) -> Result<Vec<Event>, Box<dyn Error>> {
+        Ok(self.inner.get_events(filter)?
+            .into_iter()
+            .filter(|e| self.tenant.owns(e))
+            .collect())
+    }
+
+    fn get_event(&self, event_id: String) -> Result<Event, Box<dyn Error>> {
+        self.owned_event(&event_id)
+    }
+
+    fn create_event(&self, event: Event) -> Result<Event, Box<dyn Error>> {
+        if !self.tenant.owns(&event) {
+            return Err("appName does not belong to the tenant".into());
+        }
+        self.inner.create_event(event)
+    }
+
+    fn update_event(&self, event: Event) -> Result<Event, Box<dyn Error>> {
+        let event_id = event.id.clone().unwrap_or_default();
+        self.owned_event(&event_id)?;
+        if !self.tenant.owns(&event) {
+            return Err("appName does not belong to the tenant".into());
+        }
+        self.inner.update_event(event)
+    }
+
+    fn delete_event(&self, event_id: String) -> Result<bool, Box<dyn Error>> {
+        self.owned_event(&event_id)?;
+        self.inner.delete_event(event_id)
+    }
+
+    /// Operations on other tenants' events fail as if the events did not
+    /// exist; the rest are passed on.
+    fn batch_events(&self, operations: Vec<BatchOperation>, all_or_nothing: bool) -> Result<Vec<BatchResult>, Box<dyn Error>> {
+        super::batch_checked(&*self.inner, operations, all_or_nothing, |operation| self.check_operation(operation))
+    }
+
+    /// With `preserve_ids`, ids may be new or those of the tenant's own
+    /// events. Ids taken by other tenants fail as unavailable, without
+    /// revealing who holds them.
+    fn import_events(&self, events: Vec<Event>, preserve_ids: bool) -> Result<Vec<Event>, Box<dyn Error>> {
+        for event in events.iter() {
+            if !self.tenant.owns(event) {
+                return Err("Imported events must belong to the tenant".into());
+            }
+            if let (true, Some(id)) = (preserve_ids, event.id.as_ref()) {
+                self.check_event_id(id)?;
+            }
+        }
+        self.inner.import_events(events, preserve_ids)
+    }
+
+    fn get_comments(&self, filter: Option<CommentFilter>) -> Result<Vec<Comment>, Box<dyn Error>> {
+        let event_ids: HashSet<String> = self.get_events(None)?
+            .into_iter()
+            .filter_map(|e| e.id)
+            .collect();
+        Ok(self.inner.get_comments(filter)?
+            .into_iter()
+            .filter(|c| event_ids.contains(&c.event_id))
+            .collect())
+    }
+
+    fn get_comment(&self, comment_id: String) -> Result<Comment, Box<dyn Error>> {
+        self.owned_comment(&comment_id)
+    }
+
+    fn create_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>> {
+        self.owned_event(&comment.event_id)?;
+        self.inner.create_comment(comment)
+    }
+
+    fn update_comment(&self, comment: Comment) -> Result<Comment, Box<dyn Error>> {
+        self.owned_comment(&comment.id.clone().unwrap_or_default())?;
+        self.owned_event(&comment.event_id)?;
+        self.inner.update_comment(comment)
+    }
+
+    fn delete_comment(&self, comment_id: String) -> Result<bool, Box<dyn Error>> {
+        self.owned_comment(&comment_id)?;
+        self.inner.delete_comment(comment_id)
+    }
+
+    /// Preserved ids follow the rule of `import_events`.
+    fn import_comments(&self, comments: Vec<Comment>, preserve_ids: bool) -> Result<Vec<Comment>, Box<dyn Error>> {
+        for comment in comments.iter() {
+            if !self.owns_event_id(&comment.event_id) {
+                return Err("Imported comments must be on the tenant's events".into());
+            }
+            if let (true, Some(id)) = (preserve_ids, comment.id.as_ref()) {
+                self.check_comment_id(id)?;
+            }
+        }
+        self.inner.import_comments(comments, preserve_ids)
+    }
+
+    fn compact(&self) -> Result<(), Box<dyn Error>> {
+        Err("Only unscoped callers may compact the store".into())
+    }
+
+    fn reindex(&self) -> Result<(), Box<dyn Error>> {
+        Err("Only unscoped callers may reindex the store".into())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::file_based::FileBasedEventDb;
+
+    fn event(value: serde_json::Value) -> Event {
+        serde_json::from_value(value).unwrap()
+    }
+
+    fn tenant_db(dir: &Path, app_name: &str) -> TenantEventDb {
+        let tenant = Tenant { id: app_name.to_string(), app_names: vec![app_name.to_string()] };
+        TenantEventDb::new(Box::new(FileBasedEventDb::new(dir)), tenant)
+    }
+
+    #[test]
+    fn other_tenants_events_look_missing() {
+        let dir = std::env::temp_dir().join(format!("event-api-test-{}", uuid::Uuid::new_v4()));
+        let blue = tenant_db(&dir, "blue");
+        let red = tenant_db(&dir, "red");
+        let created = blue.create_event(event(json!({ "from": 1, "text": "blue", "appName": "blue" }))).unwrap();
+        let id = created.id.unwrap();
+
+        assert!(red.get_events(None).unwrap().is_empty());
+        assert_eq!(red.get_event(id.clone()).unwrap_err().to_string(), "Event not found");
+        assert_eq!(red.delete_event(id.clone()).unwrap_err().to_string(), "Event not found");
+        assert!(red.create_event(event(json!({ "from": 1, "text": "x", "appName": "blue" }))).is_err());
+        assert_eq!(blue.get_events(None).unwrap().len(), 1);
+    }
+
+    #[test]
+    fn preserved_ids_of_other_tenants_are_unavailable() {
+        let dir = std::env::temp_dir().join(format!("event-api-test-{}", uuid::Uuid::new_v4()));
+        let blue = tenant_db(&dir, "blue");
+        let red = tenant_db(&dir, "red");
+        let seeded = vec![event(json!({ "id": "taken", "from": 1, "text": "blue", "appName": "blue" }))];
+        FileBasedEventDb::new(&dir).import_events(seeded, true).unwrap();
+
+        let taken = red.import_events(vec![event(json!({ "id": "taken", "from": 1, "text": "red", "appName": "red" }))], true);
+        let free = red.import_events(vec![event(json!({ "id": "free", "from": 1, "text": "red", "appName": "red" }))], true);
+        assert_eq!(taken.unwrap_err().to_string(), "Event id unavailable");
+        assert!(free.is_ok());
+        assert_eq!(blue.get_event("taken".to_string()).unwrap().text, "blue");
+        assert_eq!(red.get_event("free".to_string()).unwrap().text, "red");
+    }
+}
@@ -1,8 +1,12 @@
+use std::cmp::Ordering;
 use std::collections::HashMap;
-use serde::{Deserialize, Serialize};
+use serde::{Deserialize, Serialize, Serializer};
+use serde::ser::SerializeMap;
+use serde_json::{Map, Value};
 use rocket::request::Request;
 use rocket::response::{self, Response, Responder};
 use rocket::http::ContentType;
+use rocket_contrib::json;
 use rocket_contrib::json::{Json, JsonValue};
 
 #[derive(Debug, Serialize, Deserialize)]
@@ -30,26 +34,230 @@ pub struct Envelope {
     pub total_pages: Option<i32>,
     pub _links: Option<HashMap<String, Link>>,
     pub _templates: Option<HashMap<String, Template>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub _embedded: Option<HashMap<String, JsonValue>>,
+}
+
+/// The representations an `Envelope` can be rendered as, picked from the
+/// request's `Accept` header. Plain JSON is the fallback.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Representation {
+    Json,
+    Hal,
+    HalForms,
+}
+
+impl Representation {
+    pub fn from_request(req: &Request) -> Representation {
+        let accept = match req.accept() {
+            Some(accept) => accept,
+            None => return Representation::Json,
+        };
+        let mut media_types: Vec<_> = accept.iter().collect();
+        media_types.sort_by(|a, b| {
+            b.weight_or(1.0).partial_cmp(&a.weight_or(1.0)).unwrap_or(Ordering::Equal)
+        });
+        for media_type in media_types {
+            if media_type.top() != "application" {
+                continue;
+            }
+            match media_type.sub().as_str() {
+                "prs.hal-forms+json" => return Representation::HalForms,
+                "hal+json" => return Representation::Hal,
+                "json" => return Representation::Json,
+                _ => continue,
+            }
+        }
+        Representation::Json
+    }
+
+    pub fn content_type(&self) -> ContentType {
+        match self {
+            Representation::Json => ContentType::JSON,
+            Representation::Hal => ContentType::new("application", "hal+json"),
+            Representation::HalForms => ContentType::new("application", "prs.hal-forms+json"),
+        }
+    }
 }
 
 impl<'a> Responder<'a> for Envelope {
     fn respond_to(self, req: &Request) -> response::Result<'a> {
-        Response::build_from(Json(self).respond_to(req).unwrap())
-            .header(ContentType::JSON)
+        let representation = match self.status {
+            Status::OK => Representation::from_request(req),
+            Status::Error => Representation::Json,
+        };
+        let body = match representation {
+            Representation::Json => json!(self),
+            Representation::Hal => self.to_hal(false),
+            Representation::HalForms => self.to_hal(true),
+        };
+        Response::build_from(Json(body).respond_to(req).unwrap())
+            .header(representation.content_type())
             .ok()
     }
 }
 
+impl Envelope {
+    /// Renders the envelope as a HAL resource: object data is merged with
+    /// `_links`, while list data is placed under `_embedded.items` next to
+    /// any resources embedded by the payload.
+    /// `_templates` is only included for HAL-FORMS, and gets a `default`
+    /// template when none of the templates has that key. Links and templates
+    /// lose the `key` they carry in plain JSON, as HAL keys them by relation.
+    pub fn to_hal(&self, with_templates: bool) -> JsonValue {
+        let mut resource = Map::new();
+        match self.data.as_ref().map(|data| &data.0) {
+            Some(Value::Object(fields)) => {
+                for (key, value) in fields.iter() {
+                    resource.insert(key.clone(), value.clone());
+                }
+            },
+            Some(Value::Array(items)) => {
+                let mut embedded = Map::new();
+                embedded.insert("items".to_string(), Value::Array(items.clone()));
+                resource.insert("_embedded".to_string(), Value::Object(embedded));
+            },
+            Some(value) => {
+                resource.insert("value".to_string(), value.clone());
+            },
+            None => {},
+        }
+
+        if let Some(ref embedded) = self._embedded {
+            let mut merged = match resource.remove("_embedded") {
+                Some(Value::Object(existing)) => existing,
+                _ => Map::new(),
+            };
+            for (key, value) in embedded.iter() {
+                merged.insert(key.clone(), value.0.clone());
+            }
+            resource.insert("_embedded".to_string(), Value::Object(merged));
+        }
+
+        if !with_templates {
+            strip_templates(&mut resource);
+        }
+        if let Some(ref links) = self._links {
+            resource.insert("_links".to_string(), json!(links).0);
+        }
+        if with_templates {
+            if let Some(ref templates) = self._templates {
+                if !templates.is_empty() {
+                    resource.insert("_templates".to_string(), json!(with_default(templates)).0);
+                }
+            }
+        }
+        strip_keys(&mut resource);
+        JsonValue(Value::Object(resource))
+    }
+}
+
+fn strip_templates(resource: &mut Map<String, Value>) {
+    resource.remove("_templates");
+    if let Some(Value::Object(embedded)) = resource.get_mut("_embedded") {
+        for (_, items) in embedded.iter_mut() {
+            if let Value::Array(items) = items {
+                for item in items.iter_mut() {
+                    if let Value::Object(item) = item {
+                        strip_templates(item);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn strip_keys(resource: &mut Map<String, Value>) {
+    if let Some(Value::Object(links)) = resource.get_mut("_links") {
+        for (_, link) in links.iter_mut() {
+            remove_key(link);
+        }
+    }
+    if let Some(Value::Object(templates)) = resource.get_mut("_templates") {
+        for (_, template) in templates.iter_mut() {
+            remove_key(template);
+            if let Some(Value::Array(properties)) = template.get_mut("properties") {
+                for property in properties.iter_mut() {
+                    if let Some(link) = property.pointer_mut("/options/link") {
+                        remove_key(link);
+                    }
+                }
+            }
+        }
+    }
+    if let Some(Value::Object(embedded)) = resource.get_mut("_embedded") {
+        for (_, items) in embedded.iter_mut() {
+            if let Value::Array(items) = items {
+                for item in items.iter_mut() {
+                    if let Value::Object(item) = item {
+                        strip_keys(item);
+                    }
+                }
+            }
+        }
+    }
+}
+
+fn remove_key(value: &mut Value) {
+    if let Value::Object(fields) = value {
+        fields.remove("key");
+    }
+}
+
+fn with_default(templates: &HashMap<String, Template>) -> HashMap<String, Template> {
+    let mut templates = templates.clone();
+    if !templates.contains_key("default") {
+        let mut keys: Vec<&String> = templates.keys().collect();
+        keys.sort();
+        let key = if templates.contains_key("update") { "update".to_string() } else { keys[0].clone() };
+        let default = templates[&key].clone();
+        templates.insert("default".to_string(), default);
+    }
+    templates
+}
+
+/// Serializes a list of links as the HAL `_links` object keyed by relation.
+pub fn serialize_links<S: Serializer>(links: &Option<Vec<Link>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match links {
+        Some(links) => {
+            let mut map = serializer.serialize_map(Some(links.len()))?;
+            for link in links.iter() {
+                map.serialize_entry(&link.key, link)?;
+            }
+            map.end()
+        },
+        None => serializer.serialize_none(),
+    }
+}
+
+/// Serializes a list of templates as the HAL-FORMS `_templates` object.
+pub fn serialize_templates<S: Serializer>(templates: &Option<Vec<Template>>, serializer: S) -> Result<S::Ok, S::Error> {
+    match templates {
+        Some(templates) => {
+            let mut map = serializer.serialize_map(Some(templates.len()))?;
+            for template in templates.iter() {
+                map.serialize_entry(&template.key, template)?;
+            }
+            map.end()
+        },
+        None => serializer.serialize_none(),
+    }
+}
+
 pub struct Payload {
     pub data: JsonValue,
     pub links: Option<Vec<Link>>,
     pub templates: Option<Vec<Template>>,
+    pub embedded: Option<HashMap<String, JsonValue>>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Link {
+    #[serde(default)]
     pub key: String,
     pub href: String,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub templated: Option<bool>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -62,8 +270,11 @@ pub enum MethodType {
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Template {
+    #[serde(default)]
     pub key: String,
     pub method: MethodType,
+    #[serde(rename = "contentType", skip_serializing_if = "Option::is_none")]
+    pub content_type: Option<String>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub properties: Option<Vec<Property>>,
     #[serde(skip_serializing_if = "Option::is_none")]
@@ -77,12 +288,37 @@ pub struct Property {
     pub name: String,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub prompt: Option<String>,
+    #[serde(rename = "readOnly")]
     pub read_only: bool,
     pub required: bool,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub templated: Option<bool>,
     #[serde(skip_serializing_if = "Option::is_none")]
     pub value: Option<String>,
+    #[serde(rename = "type", skip_serializing_if = "Option::is_none")]
+    pub property_type: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub min: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub max: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub regex: Option<String>,
+    #[serde(rename = "maxLength", skip_serializing_if = "Option::is_none")]
+    pub max_length: Option<usize>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<PropertyOptions>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PropertyOptions {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub inline: Option<Vec<String>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub link: Option<Link>,
+    #[serde(rename = "valueField", skip_serializing_if = "Option::is_none")]
+    pub value_field: Option<String>,
+    #[serde(rename = "maxItems", skip_serializing_if = "Option::is_none")]
+    pub max_items: Option<usize>,
 }
 
 pub fn error(code: i32, description: String) -> Envelope {
@@ -96,6 +332,7 @@ pub fn error(code: i32, description: String) -> Envelope {
         total_pages: None,
         _links: None,
         _templates: None,
+        _embedded: None,
     }
 }
 
@@ -123,6 +360,7 @@ pub fn success(payload: Payload) -> Envelope {
         total_pages: None,
         _links: Some(links.clone()),
         _templates: Some(templates.clone()),
+        _embedded: payload.embedded,
     }
 }
 
@@ -134,5 +372,43 @@ pub fn create_property(name: &str, read_only: bool, required: bool) -> Property
         required: required,
         templated: None,
         value: None,
+        property_type: None,
+        min: None,
+        max: None,
+        regex: None,
+        max_length: None,
+        options: None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keys_are_only_stripped_for_hal() {
+        let envelope = success(Payload {
+            data: json!({ "id": "a", "_links": { "self": { "key": "self", "href": "/events/a" } } }),
+            links: Some(vec![Link { key: "self".to_string(), href: "/events".to_string(), templated: None }]),
+            templates: Some(vec![Template {
+                key: "default".to_string(),
+                method: MethodType::POST,
+                content_type: None,
+                properties: None,
+                target: None,
+                title: None,
+            }]),
+            embedded: None,
+        });
+
+        let plain = json!(envelope).0;
+        assert_eq!(plain["_links"]["self"]["key"], "self");
+        assert_eq!(plain["_templates"]["default"]["key"], "default");
+        assert_eq!(plain["data"]["_links"]["self"]["key"], "self");
+
+        let hal = envelope.to_hal(true).0;
+        assert!(hal["_links"]["self"].get("key").is_none());
+        assert!(hal["_templates"]["default"].get("key").is_none());
+        assert_eq!(hal["_links"]["self"]["href"], "/events");
     }
 }
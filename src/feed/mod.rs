@@ -0,0 +1,158 @@
+use chrono::{SecondsFormat, TimeZone, Utc};
+
+use crate::links::LinkBuilder;
+use crate::model::{Event, Comment};
+
+pub const ATOM_LIMIT: usize = 50;
+
+/// Renders the most recent events as an Atom feed. Entries are ordered by
+/// `Event::from`, newest first, and an entry is considered updated when the
+/// event ends. Entries are identified by their canonical URL, and events
+/// with times that cannot be shown as dates are left out.
+pub fn write_events_feed(links: &LinkBuilder, mut events: Vec<Event>, limit: usize) -> String {
+    events.sort_by(|a, b| b.from.cmp(&a.from));
+    events.truncate(limit);
+
+    let entries: Vec<String> = events
+        .iter()
+        .filter_map(|event| {
+            let id = event.id.as_ref()?;
+            let updated = format_timestamp(event.to.unwrap_or(event.from))?;
+            let published = format_timestamp(event.from)?;
+            let mut categories = String::new();
+            for category in event.app_name.iter().chain(event.tags.iter()) {
+                categories.push_str(&format!("<category term=\"{}\"/>", escape(category)));
+            }
+            Some(format!(
+                "<entry><id>{}</id><title>{}</title><updated>{}</updated><published>{}</published>\
+                 <link rel=\"alternate\" href=\"{}\"/><link rel=\"replies\" href=\"{}/feed.atom\"/>\
+                 {}<author><name>{}</name></author><content type=\"text\">{}</content></entry>",
+                escape(&links.event(id)),
+                escape(&title(&event.text)),
+                updated,
+                published,
+                escape(&links.event(id)),
+                escape(&links.event_comments(id)),
+                categories,
+                escape(event.source_name.as_ref().or(event.app_name.as_ref()).map(|s| s.as_str()).unwrap_or("event-api")),
+                escape(&event.text),
+            ))
+        })
+        .collect();
+
+    let updated = events.iter().map(|e| e.to.unwrap_or(e.from)).max();
+    write_feed(
+        "urn:event-api:events",
+        "Events",
+        &escape(&links.events()),
+        &escape(&links.href("/events/feed.atom")),
+        updated,
+        entries,
+    )
+}
+
+/// Renders the comments on one event as an Atom feed, newest first.
+pub fn write_comments_feed(links: &LinkBuilder, event_id: &str, mut comments: Vec<Comment>, limit: usize) -> String {
+    comments.sort_by(|a, b| b.timestamp.cmp(&a.timestamp));
+    comments.truncate(limit);
+
+    let entries: Vec<String> = comments
+        .iter()
+        .filter_map(|comment| {
+            let id = comment.id.as_ref()?;
+            let updated = format_timestamp(comment.timestamp)?;
+            Some(format!(
+                "<entry><id>{}</id><title>{}</title><updated>{}</updated>\
+                 <link rel=\"alternate\" href=\"{}\"/><link rel=\"related\" href=\"{}\"/>\
+                 <author><name>{}</name></author><content type=\"text\">{}</content></entry>",
+                escape(&links.comment(event_id, id)),
+                escape(&title(&comment.comment)),
+                updated,
+                escape(&links.comment(event_id, id)),
+                escape(&links.event(event_id)),
+                escape(&comment.user_id),
+                escape(&comment.comment),
+            ))
+        })
+        .collect();
+
+    let updated = comments.iter().map(|c| c.timestamp).max();
+    write_feed(
+        &escape(&links.event_comments(event_id)),
+        "Comments",
+        &escape(&links.event(event_id)),
+        &escape(&format!("{}/feed.atom", links.event_comments(event_id))),
+        updated,
+        entries,
+    )
+}
+
+fn write_feed(id: &str, title: &str, alternate: &str, href: &str, updated: Option<i64>, entries: Vec<String>) -> String {
+    let updated = updated
+        .and_then(format_timestamp)
+        .unwrap_or_else(|| Utc::now().to_rfc3339_opts(SecondsFormat::Secs, true));
+    format!(
+        "<?xml version=\"1.0\" encoding=\"utf-8\"?>\n\
+         <feed xmlns=\"http://www.w3.org/2005/Atom\"><id>{}</id><title>{}</title><updated>{}</updated>\
+         <link rel=\"self\" href=\"{}\"/><link rel=\"alternate\" href=\"{}\"/><generator>event-api</generator>{}</feed>\n",
+        id,
+        title,
+        updated,
+        href,
+        alternate,
+        entries.join(""),
+    )
+}
+
+/// Uses the first line of a text, shortened, as entry title.
+fn title(text: &str) -> String {
+    let first = text.lines().next().unwrap_or("");
+    if first.chars().count() > 80 {
+        format!("{}…", first.chars().take(79).collect::<String>())
+    } else {
+        first.to_string()
+    }
+}
+
+/// `None` for times chrono cannot represent.
+fn format_timestamp(millis: i64) -> Option<String> {
+    Utc.timestamp_millis_opt(millis).single().map(|time| time.to_rfc3339_opts(SecondsFormat::Secs, true))
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&apos;")
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::{json, Value};
+
+    use super::*;
+
+    fn event(value: Value) -> Event {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn entries_are_identified_by_their_url() {
+        let links = LinkBuilder::default();
+        let feed = write_events_feed(&links, vec![event(json!({ "id": "imported-1", "from": 1000, "text": "deploy" }))], ATOM_LIMIT);
+        assert!(feed.contains(&format!("<entry><id>{}</id>", links.event("imported-1"))));
+        assert!(!feed.contains("urn:uuid"));
+    }
+
+    #[test]
+    fn unrepresentable_times_are_left_out() {
+        let events = vec![
+            event(json!({ "id": "far", "from": 10_000_000_000_000_000i64, "text": "far" })),
+            event(json!({ "id": "near", "from": 1000, "text": "near" })),
+        ];
+        let feed = write_events_feed(&LinkBuilder::default(), events, ATOM_LIMIT);
+        assert_eq!(feed.matches("<entry>").count(), 1);
+        assert!(feed.contains("<updated>1970-01-01T00:00:01Z</updated>"));
+    }
+}
@@ -0,0 +1,224 @@
+use chrono::DateTime;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+
+use crate::model::{Event, Severity, Status};
+
+pub const ANNOTATION_LIMIT: usize = 100;
+
+/// Prefixes of the tags standing for `appName` and `sourceName`.
+pub const APP_TAG_PREFIX: &str = "app:";
+pub const SOURCE_TAG_PREFIX: &str = "source:";
+
+/// An event as a Grafana annotation. `time` and `timeEnd` are `Event::from`
+/// and `Event::to`; the tags are those of `tags`.
+#[derive(Debug, Clone, Serialize)]
+pub struct Annotation {
+    pub id: Option<String>,
+    pub time: i64,
+    #[serde(rename = "timeEnd")]
+    pub time_end: i64,
+    #[serde(rename = "isRegion")]
+    pub is_region: bool,
+    pub text: String,
+    pub tags: Vec<String>,
+}
+
+impl Annotation {
+    pub fn from_event(event: &Event) -> Annotation {
+        Annotation {
+            id: event.id.clone(),
+            time: event.from,
+            time_end: event.to.unwrap_or(event.from),
+            is_region: event.to.map_or(false, |to| to > event.from),
+            text: event.text.clone(),
+            tags: tags(event),
+        }
+    }
+}
+
+/// Request body for creating or patching an annotation through the
+/// Grafana HTTP API. Tags are read back as `tags` writes them: `app:<name>`
+/// sets `appName`, `source:<name>` sets `sourceName`, and the rest are the
+/// event's tags.
+#[derive(Debug, Clone, Default, Deserialize)]
+pub struct AnnotationChange {
+    pub time: Option<i64>,
+    #[serde(rename = "timeEnd")]
+    pub time_end: Option<i64>,
+    pub text: Option<String>,
+    pub tags: Option<Vec<String>>,
+}
+
+impl AnnotationChange {
+    /// Applies the change to an event, or to a new event when `event` is
+    /// `None`, in which case `time` and `text` are required.
+    pub fn apply(self, event: Option<Event>) -> Result<Event, String> {
+        let mut event = match event {
+            Some(event) => event,
+            None => Event {
+                id: None,
+                from: self.time.ok_or("time is required")?,
+                to: None,
+                text: self.text.clone().ok_or("text is required")?,
+                app_name: None,
+                source_id: None,
+                source_name: None,
+                tags: Vec::new(),
+                metadata: Map::new(),
+                severity: Severity::Info,
+                status: Some(Status::Open),
+                created_by: None,
+                _links: None,
+                _templates: None,
+                _embedded: None,
+            },
+        };
+        if let Some(time) = self.time {
+            event.from = time;
+        }
+        if let Some(time_end) = self.time_end {
+            event.to = if time_end > event.from { Some(time_end) } else { None };
+        }
+        if let Some(text) = self.text {
+            event.text = text;
+        }
+        if let Some(tags) = self.tags {
+            event.app_name = None;
+            event.source_name = None;
+            event.tags = Vec::new();
+            for tag in tags.into_iter().filter(|t| !t.trim().is_empty()) {
+                if tag.starts_with(APP_TAG_PREFIX) {
+                    event.app_name = Some(tag[APP_TAG_PREFIX.len()..].to_string());
+                } else if tag.starts_with(SOURCE_TAG_PREFIX) {
+                    event.source_name = Some(tag[SOURCE_TAG_PREFIX.len()..].to_string());
+                } else {
+                    event.tags.push(tag);
+                }
+            }
+        }
+        Ok(event)
+    }
+}
+
+/// Body of an annotation query from Grafana's JSON data source. The
+/// annotation's `query` lists the tags to match, separated by commas or
+/// spaces.
+#[derive(Debug, Clone, Deserialize)]
+pub struct AnnotationQuery {
+    pub range: Range,
+    #[serde(default)]
+    pub annotation: Value,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Range {
+    pub from: String,
+    pub to: String,
+}
+
+impl AnnotationQuery {
+    pub fn tags(&self) -> Vec<String> {
+        split_tags(self.annotation.get("query").and_then(|q| q.as_str()).unwrap_or(""))
+    }
+}
+
+/// The tags of an event: `app:<appName>` and `source:<sourceName>`, when
+/// set, and its own tags. Queries match against these.
+pub fn tags(event: &Event) -> Vec<String> {
+    event.app_name.iter().map(|app_name| format!("{}{}", APP_TAG_PREFIX, app_name))
+        .chain(event.source_name.iter().map(|source_name| format!("{}{}", SOURCE_TAG_PREFIX, source_name)))
+        .chain(event.tags.iter().cloned())
+        .collect()
+}
+
+pub fn split_tags(tags: &str) -> Vec<String> {
+    tags.split(|c: char| c == ',' || c.is_whitespace())
+        .filter(|t| !t.is_empty())
+        .map(|t| t.to_string())
+        .collect()
+}
+
+/// Parses a Grafana time, either RFC 3339 or milliseconds since the epoch.
+pub fn parse_time(time: &str) -> Result<i64, String> {
+    if let Ok(millis) = time.parse::<i64>() {
+        return Ok(millis);
+    }
+    DateTime::parse_from_rfc3339(time)
+        .map(|date_time| date_time.timestamp_millis())
+        .map_err(|_| format!("Invalid time: {}", time))
+}
+
+/// Events overlapping `from`..`to` and carrying the tags, newest first. All
+/// tags must match unless `match_any` is set.
+pub fn find_annotations(events: Vec<Event>, from: Option<i64>, to: Option<i64>, tags: &[String], match_any: bool, limit: usize) -> Vec<Annotation> {
+    let mut events: Vec<Event> = events
+        .into_iter()
+        .filter(|e| from.map_or(true, |from| e.to.unwrap_or(e.from) >= from))
+        .filter(|e| to.map_or(true, |to| e.from <= to))
+        .filter(|e| {
+            let event_tags = self::tags(e);
+            if tags.is_empty() {
+                true
+            } else if match_any {
+                tags.iter().any(|t| event_tags.contains(t))
+            } else {
+                tags.iter().all(|t| event_tags.contains(t))
+            }
+        })
+        .collect();
+    events.sort_by(|a, b| b.from.cmp(&a.from));
+    events.truncate(limit);
+    events.iter().map(Annotation::from_event).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(value: Value) -> Event {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn tags_round_trip() {
+        let original = event(json!({ "from": 1, "text": "deploy", "appName": "shop", "sourceName": "ci", "tags": ["release"] }));
+        let annotation = Annotation::from_event(&original);
+        assert_eq!(annotation.tags, vec!["app:shop", "source:ci", "release"]);
+
+        let change = AnnotationChange { tags: Some(annotation.tags), ..Default::default() };
+        let applied = change.apply(Some(original.clone())).unwrap();
+        assert_eq!(applied.app_name, original.app_name);
+        assert_eq!(applied.source_name, original.source_name);
+        assert_eq!(applied.tags, original.tags);
+    }
+
+    #[test]
+    fn unprefixed_tags_stay_tags() {
+        let change = AnnotationChange {
+            time: Some(1),
+            text: Some("note".to_string()),
+            tags: Some(vec!["release".to_string(), "app:shop".to_string(), " ".to_string()]),
+            ..Default::default()
+        };
+        let created = change.apply(None).unwrap();
+        assert_eq!(created.app_name.as_deref(), Some("shop"));
+        assert_eq!(created.source_name, None);
+        assert_eq!(created.tags, vec!["release"]);
+    }
+
+    #[test]
+    fn queries_match_prefixed_tags() {
+        let events = vec![
+            event(json!({ "id": "a", "from": 10, "text": "a", "appName": "shop", "tags": ["release"] })),
+            event(json!({ "id": "b", "from": 20, "text": "b", "appName": "billing" })),
+        ];
+        let found = find_annotations(events.clone(), None, None, &["app:shop".to_string()], false, ANNOTATION_LIMIT);
+        assert_eq!(found.len(), 1);
+        assert_eq!(found[0].id.as_deref(), Some("a"));
+        let found = find_annotations(events, Some(15), None, &[], false, ANNOTATION_LIMIT);
+        assert_eq!(found[0].id.as_deref(), Some("b"));
+    }
+}
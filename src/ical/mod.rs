@@ -0,0 +1,337 @@
+use chrono::{NaiveDate, NaiveDateTime, TimeZone, Utc};
+use serde_json::Map;
+
+use crate::links::LinkBuilder;
+use crate::model::{Event, Severity, Status};
+
+static PRODID: &str = "-//killie.org//event-api//EN";
+static DATE_TIME_FORMAT: &str = "%Y%m%dT%H%M%SZ";
+
+/// Renders events as a VCALENDAR with one VEVENT per event. Timestamps on
+/// `Event` are milliseconds since the epoch and are written in UTC.
+pub fn write_calendar(links: &LinkBuilder, events: &[Event]) -> String {
+    let stamp = stamp();
+    let mut calendar = calendar_start();
+    for event in events {
+        calendar.push_str(&write_vevent(links, event, &stamp));
+    }
+    calendar.push_str(&calendar_end());
+    calendar
+}
+
+/// The folded lines that open a VCALENDAR, for writing one piece at a time.
+pub fn calendar_start() -> String {
+    let lines = [
+        "BEGIN:VCALENDAR".to_string(),
+        "VERSION:2.0".to_string(),
+        format!("PRODID:{}", PRODID),
+        "CALSCALE:GREGORIAN".to_string(),
+        "X-WR-CALNAME:Events".to_string(),
+    ];
+    lines.iter().map(|line| fold_line(line)).collect()
+}
+
+pub fn calendar_end() -> String {
+    fold_line("END:VCALENDAR")
+}
+
+/// One event as a folded VEVENT, stamped with `stamp`. Events without an id,
+/// or starting at a time that cannot be written, are left out.
+pub fn write_vevent(links: &LinkBuilder, event: &Event, stamp: &str) -> String {
+    let id = match event.id {
+        Some(ref id) => id,
+        None => return String::new(),
+    };
+    let mut lines: Vec<String> = Vec::new();
+    lines.push("BEGIN:VEVENT".to_string());
+    lines.push(format!("UID:{}", id));
+    lines.push(format!("DTSTAMP:{}", stamp));
+    let from = match format_timestamp(event.from) {
+        Some(from) => from,
+        None => return String::new(),
+    };
+    lines.push(format!("DTSTART:{}", from));
+    if let Some(to) = event.to.and_then(format_timestamp) {
+        lines.push(format!("DTEND:{}", to));
+    }
+    lines.push(format!("SUMMARY:{}", escape_text(&event.text)));
+    let categories: Vec<String> = event.app_name.iter().chain(event.tags.iter()).map(|c| escape_text(c)).collect();
+    if !categories.is_empty() {
+        lines.push(format!("CATEGORIES:{}", categories.join(",")));
+    }
+    if let Some(ref source_name) = event.source_name {
+        lines.push(format!("LOCATION:{}", escape_text(source_name)));
+    }
+    lines.push(format!("URL:{}", links.event(id)));
+    lines.push("END:VEVENT".to_string());
+    lines.iter().map(|line| fold_line(line)).collect()
+}
+
+/// The current time as a DTSTAMP value.
+pub fn stamp() -> String {
+    Utc::now().format(DATE_TIME_FORMAT).to_string()
+}
+
+/// Parses every VEVENT in `data` into an `Event`. The UID becomes `sourceId`,
+/// the first CATEGORIES value (or the calendar's X-WR-CALNAME) becomes
+/// `appName` and the other values tags, and LOCATION becomes `sourceName`.
+pub fn read_calendar(data: &str) -> Vec<Result<Event, String>> {
+    let mut results: Vec<Result<Event, String>> = Vec::new();
+    let mut calendar_name: Option<String> = None;
+    let mut current: Option<Vec<Property>> = None;
+
+    for line in unfold_lines(data) {
+        let Property { name, parameters, value } = match split_property(&line) {
+            Some(property) => property,
+            None => continue,
+        };
+        if current.is_none() {
+            if name == "BEGIN" && value == "VEVENT" {
+                current = Some(Vec::new());
+            } else if name == "X-WR-CALNAME" {
+                calendar_name = Some(unescape_text(&value));
+            }
+        } else if name == "END" && value == "VEVENT" {
+            let properties = current.take().unwrap();
+            results.push(to_event(&properties, &calendar_name));
+        } else {
+            current.as_mut().unwrap().push(Property { name, parameters, value });
+        }
+    }
+
+    results
+}
+
+/// A content line: `NAME;PARAM=value:VALUE`.
+struct Property {
+    name: String,
+    parameters: Vec<(String, String)>,
+    value: String,
+}
+
+fn to_event(properties: &[Property], calendar_name: &Option<String>) -> Result<Event, String> {
+    let find = |key: &str| properties.iter().find(|p| p.name == key);
+    let get = |key: &str| find(key).map(|p| p.value.clone());
+
+    let from = match find("DTSTART") {
+        Some(property) => parse_time_property(property)?,
+        None => return Err("VEVENT is missing DTSTART".to_string()),
+    };
+    let to = match find("DTEND") {
+        Some(property) => Some(parse_time_property(property)?),
+        None => None,
+    };
+    let text = get("SUMMARY")
+        .or_else(|| get("DESCRIPTION"))
+        .map(|value| unescape_text(&value))
+        .unwrap_or_default();
+    let mut categories: Vec<String> = properties.iter()
+        .filter(|p| p.name == "CATEGORIES")
+        .flat_map(|p| split_list(&p.value))
+        .map(|value| unescape_text(&value))
+        .filter(|value| !value.is_empty())
+        .collect();
+    let app_name = if categories.is_empty() { calendar_name.clone() } else { Some(categories.remove(0)) };
+
+    Ok(Event {
+        id: None,
+        from,
+        to,
+        text,
+        app_name,
+        source_id: get("UID"),
+        source_name: get("LOCATION").map(|value| unescape_text(&value)),
+        tags: categories,
+        metadata: Map::new(),
+        severity: Severity::Info,
+        status: Some(Status::Open),
+        created_by: None,
+        _links: None,
+        _templates: None,
+        _embedded: None,
+    })
+}
+
+/// `None` for times chrono cannot represent.
+fn format_timestamp(millis: i64) -> Option<String> {
+    Utc.timestamp_millis_opt(millis).single().map(|time| time.format(DATE_TIME_FORMAT).to_string())
+}
+
+/// Times are read as UTC. Without a time zone database, a TZID other than
+/// UTC cannot be converted, so such a record is rejected rather than read
+/// with the wrong offset.
+fn parse_time_property(property: &Property) -> Result<i64, String> {
+    let tzid = property.parameters.iter().find(|(name, _)| name == "TZID").map(|(_, value)| value.as_str());
+    match tzid {
+        None | Some("UTC") | Some("Etc/UTC") | Some("GMT") => parse_timestamp(&property.value),
+        Some(tzid) => Err(format!("{} has unsupported TZID {}, use UTC times", property.name, tzid)),
+    }
+}
+
+fn parse_timestamp(value: &str) -> Result<i64, String> {
+    let value = value.trim_end_matches('Z');
+    if let Ok(date_time) = NaiveDateTime::parse_from_str(value, "%Y%m%dT%H%M%S") {
+        return Ok(date_time.timestamp_millis());
+    }
+    if let Ok(date) = NaiveDate::parse_from_str(value, "%Y%m%d") {
+        return Ok(date.and_hms(0, 0, 0).timestamp_millis());
+    }
+    Err(format!("Invalid date: {}", value))
+}
+
+/// Splits a content line at the first colon outside a quoted parameter
+/// value.
+fn split_property(line: &str) -> Option<Property> {
+    let mut quoted = false;
+    let colon = line.char_indices()
+        .find(|(_, c)| {
+            if *c == '"' {
+                quoted = !quoted;
+            }
+            *c == ':' && !quoted
+        })
+        .map(|(index, _)| index)?;
+    let mut head = line[..colon].split(';');
+    let name = head.next().unwrap_or("").to_uppercase();
+    let parameters = head
+        .filter_map(|parameter| {
+            let mut parts = parameter.splitn(2, '=');
+            let key = parts.next()?.to_uppercase();
+            let value = parts.next()?.trim_matches('"').to_string();
+            Some((key, value))
+        })
+        .collect();
+    Some(Property { name, parameters, value: line[colon + 1..].to_string() })
+}
+
+fn unfold_lines(data: &str) -> Vec<String> {
+    let mut lines: Vec<String> = Vec::new();
+    for line in data.lines() {
+        if (line.starts_with(' ') || line.starts_with('\t')) && !lines.is_empty() {
+            lines.last_mut().unwrap().push_str(&line[1..]);
+        } else if !line.is_empty() {
+            lines.push(line.to_string());
+        }
+    }
+    lines
+}
+
+/// Folds a content line at 75 octets as RFC 5545 requires, never splitting
+/// a multi-byte character.
+fn fold_line(line: &str) -> String {
+    let mut folded = String::new();
+    let mut length = 0;
+    for c in line.chars() {
+        if length + c.len_utf8() > 75 {
+            folded.push_str("\r\n ");
+            length = 1;
+        }
+        folded.push(c);
+        length += c.len_utf8();
+    }
+    folded.push_str("\r\n");
+    folded
+}
+
+fn escape_text(text: &str) -> String {
+    text.replace('\\', "\\\\")
+        .replace(';', "\\;")
+        .replace(',', "\\,")
+        .replace('\n', "\\n")
+}
+
+/// Splits a list value on the commas that are not escaped.
+fn split_list(value: &str) -> Vec<String> {
+    let mut values: Vec<String> = vec![String::new()];
+    let mut escaped = false;
+    for c in value.chars() {
+        if c == ',' && !escaped {
+            values.push(String::new());
+        } else {
+            values.last_mut().unwrap().push(c);
+        }
+        escaped = c == '\\' && !escaped;
+    }
+    values
+}
+
+fn unescape_text(text: &str) -> String {
+    let mut unescaped = String::new();
+    let mut chars = text.chars();
+    while let Some(c) = chars.next() {
+        if c == '\\' {
+            match chars.next() {
+                Some('n') | Some('N') => unescaped.push('\n'),
+                Some(other) => unescaped.push(other),
+                None => {},
+            }
+        } else {
+            unescaped.push(c);
+        }
+    }
+    unescaped
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(value: serde_json::Value) -> Event {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn calendar_round_trips_events() {
+        let text = "A long summary, with commas; semicolons and more text than fits on one folded line";
+        let original = event(json!({
+            "id": "e1",
+            "from": 1_600_000_000_000i64,
+            "to": 1_600_000_060_000i64,
+            "text": text,
+            "appName": "api",
+            "sourceName": "ci",
+            "tags": ["a,b", "c"],
+        }));
+        let calendar = write_calendar(&LinkBuilder::default(), &[original]);
+        assert!(calendar.lines().all(|line| line.trim_end_matches('\r').len() <= 75));
+        let events: Vec<Event> = read_calendar(&calendar).into_iter().map(|e| e.unwrap()).collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].from, 1_600_000_000_000);
+        assert_eq!(events[0].to, Some(1_600_000_060_000));
+        assert_eq!(events[0].text, text);
+        assert_eq!(events[0].app_name.as_deref(), Some("api"));
+        assert_eq!(events[0].source_name.as_deref(), Some("ci"));
+        assert_eq!(events[0].source_id.as_deref(), Some("e1"));
+        assert_eq!(events[0].tags, vec!["a,b", "c"]);
+    }
+
+    #[test]
+    fn tzid_other_than_utc_is_rejected_per_record() {
+        let data = "BEGIN:VCALENDAR\r\n\
+            BEGIN:VEVENT\r\nDTSTART;TZID=Europe/Oslo:20200101T120000\r\nSUMMARY:Local\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nDTSTART;TZID=\"UTC\":20200101T120000\r\nSUMMARY:Quoted\r\nEND:VEVENT\r\n\
+            BEGIN:VEVENT\r\nDTSTART;VALUE=DATE:20200101\r\nSUMMARY:Date\r\nEND:VEVENT\r\n\
+            END:VCALENDAR\r\n";
+        let results = read_calendar(data);
+        assert_eq!(results.len(), 3);
+        assert!(results[0].as_ref().err().unwrap().contains("Europe/Oslo"));
+        assert_eq!(results[1].as_ref().unwrap().from, 1_577_880_000_000);
+        assert_eq!(results[2].as_ref().unwrap().from, 1_577_836_800_000);
+    }
+
+    #[test]
+    fn colons_in_quoted_parameters_do_not_split_the_value() {
+        let property = split_property("DTSTART;TZID=\"/example.org:Oslo\":20200101T120000Z").unwrap();
+        assert_eq!(property.name, "DTSTART");
+        assert_eq!(property.parameters, vec![("TZID".to_string(), "/example.org:Oslo".to_string())]);
+        assert_eq!(property.value, "20200101T120000Z");
+    }
+
+    #[test]
+    fn events_at_unrepresentable_times_are_left_out() {
+        let out_of_range = event(json!({ "id": "e1", "from": i64::MAX, "text": "far" }));
+        assert_eq!(write_vevent(&LinkBuilder::default(), &out_of_range, "20200101T000000Z"), "");
+    }
+}
@@ -0,0 +1,143 @@
+use std::collections::HashMap;
+use std::error::Error;
+
+use chrono::DateTime;
+use serde::Deserialize;
+use serde_json::Value;
+
+use crate::db::EventDb;
+use crate::model::{self, Event, EventFilter, Severity, Status};
+use super::{Action, IngestResult};
+
+/// Alertmanager settings read from the `[global]` section of `Rocket.toml`:
+///
+/// * `alertmanager_app_label`: alert label used as `appName`. Defaults to
+///   `job`.
+/// * `alertmanager_source_name`: `sourceName` of the events created.
+///   Defaults to `alertmanager`.
+#[derive(Debug, Clone)]
+pub struct AlertmanagerConfig {
+    pub app_label: String,
+    pub source_name: String,
+}
+
+impl AlertmanagerConfig {
+    pub fn from_rocket(rocket: &rocket::Rocket) -> AlertmanagerConfig {
+        let config = rocket.config();
+        AlertmanagerConfig {
+            app_label: config.get_string("alertmanager_app_label").unwrap_or("job".to_string()),
+            source_name: config.get_string("alertmanager_source_name").unwrap_or("alertmanager".to_string()),
+        }
+    }
+}
+
+/// The body Alertmanager posts to webhook receivers, version 4.
+#[derive(Debug, Clone, Deserialize)]
+pub struct Notification {
+    pub alerts: Vec<Alert>,
+}
+
+#[derive(Debug, Clone, Deserialize)]
+pub struct Alert {
+    pub status: String,
+    #[serde(default)]
+    pub labels: HashMap<String, String>,
+    #[serde(default)]
+    pub annotations: HashMap<String, String>,
+    #[serde(rename = "startsAt")]
+    pub starts_at: String,
+    #[serde(rename = "endsAt", default)]
+    pub ends_at: String,
+    pub fingerprint: String,
+}
+
+impl Alert {
+    fn text(&self) -> String {
+        ["summary", "description"].iter()
+            .filter_map(|key| self.annotations.get(*key))
+            .chain(self.labels.get("alertname"))
+            .find(|text| !text.trim().is_empty())
+            .cloned()
+            .unwrap_or(format!("Alert {}", self.fingerprint))
+    }
+
+    /// From the `severity` label, which is free-form by convention.
+    fn severity(&self) -> Severity {
+        match self.labels.get("severity").map(|severity| severity.to_lowercase()).as_deref() {
+            Some("critical") | Some("page") | Some("high") => Severity::Critical,
+            Some("warning") | Some("warn") | Some("medium") => Severity::Warning,
+            _ => Severity::Info,
+        }
+    }
+}
+
+/// Turns each firing alert into an open-ended event, and closes it when the
+/// alert resolves. Alerts are matched to events by fingerprint, kept as
+/// `sourceId`, and their labels are kept as metadata. Closing an event
+/// resolves it. Repeated notifications leave the event alone, and a resolved
+/// alert without an open event is stored as a closed one.
+pub fn ingest(edb: &dyn EventDb, notification: Notification, config: &AlertmanagerConfig, created_by: Option<String>) -> Vec<IngestResult> {
+    notification.alerts
+        .into_iter()
+        .map(|alert| {
+            let fingerprint = alert.fingerprint.clone();
+            match ingest_alert(edb, alert, config, created_by.clone()) {
+                Ok(result) => result,
+                Err(err) => IngestResult::failed(Some(fingerprint), err.to_string()),
+            }
+        })
+        .collect()
+}
+
+fn ingest_alert(edb: &dyn EventDb, alert: Alert, config: &AlertmanagerConfig, created_by: Option<String>) -> Result<IngestResult, Box<dyn Error>> {
+    let filter = EventFilter {
+        source_id: Some(alert.fingerprint.clone()),
+        source_name: Some(config.source_name.clone()),
+        ..Default::default()
+    };
+    let events = edb.get_events(Some(filter))?;
+    let from = parse_time(&alert.starts_at)?;
+    let resolved = alert.status == "resolved";
+    let ends_at = if resolved { Some(parse_time(&alert.ends_at)?) } else { None };
+
+    if let Some(closed) = events.iter().find(|e| resolved && e.to.is_some() && e.from == from) {
+        return Ok(IngestResult::stored(Action::Unchanged, closed.clone()));
+    }
+    match events.into_iter().find(|e| e.to.is_none()) {
+        Some(mut event) if resolved => {
+            event.to = ends_at.map(|to| to.max(event.from));
+            model::set_status(&mut event, Status::Resolved, from)?;
+            let event = edb.update_event(event)?;
+            Ok(IngestResult::stored(Action::Updated, event))
+        },
+        Some(event) => Ok(IngestResult::stored(Action::Unchanged, event)),
+        None => {
+            let event = Event {
+                id: None,
+                from,
+                to: ends_at.map(|to| to.max(from)),
+                text: alert.text().chars().take(10000).collect(),
+                app_name: alert.labels.get(&config.app_label).and_then(|value| super::sanitize_app_name(value)),
+                source_id: Some(alert.fingerprint.clone()),
+                source_name: Some(config.source_name.clone()),
+                tags: Vec::new(),
+                metadata: alert.labels.iter().map(|(name, value)| (name.clone(), Value::String(value.clone()))).collect(),
+                severity: alert.severity(),
+                status: Some(if resolved { Status::Resolved } else { Status::Open }),
+                created_by,
+                _links: None,
+                _templates: None,
+                _embedded: None,
+            };
+            model::validate_event(&event)?;
+            let event = edb.create_event(event)?;
+            Ok(IngestResult::stored(Action::Created, event))
+        },
+    }
+}
+
+fn parse_time(time: &str) -> Result<i64, String> {
+    DateTime::parse_from_rfc3339(time)
+        .map(|date_time| date_time.timestamp_millis().max(0))
+        .map_err(|_| format!("Invalid time: {}", time))
+}
@@ -0,0 +1,51 @@
+use serde::Serialize;
+
+use crate::model::Event;
+
+pub mod alertmanager;
+pub mod syslog;
+
+/// What ingesting one item did to the store.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Action {
+    Created,
+    Updated,
+    Unchanged,
+    Failed,
+}
+
+/// The outcome for one ingested item. `event` is the stored event, kept for
+/// notifying webhooks.
+#[derive(Clone, Serialize)]
+pub struct IngestResult {
+    #[serde(rename = "sourceId")]
+    pub source_id: Option<String>,
+    pub id: Option<String>,
+    pub action: Action,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+    #[serde(skip)]
+    pub event: Option<Event>,
+}
+
+impl IngestResult {
+    pub fn stored(action: Action, event: Event) -> IngestResult {
+        IngestResult { source_id: event.source_id.clone(), id: event.id.clone(), action, error: None, event: Some(event) }
+    }
+
+    pub fn failed(source_id: Option<String>, error: String) -> IngestResult {
+        IngestResult { source_id, id: None, action: Action::Failed, error: Some(error), event: None }
+    }
+}
+
+/// Makes a value usable as an `appName`, which only allows letters, digits,
+/// `_`, `.` and `-`.
+pub fn sanitize_app_name(value: &str) -> Option<String> {
+    let app_name: String = value.trim()
+        .chars()
+        .map(|c| if c.is_ascii_alphanumeric() || c == '_' || c == '.' || c == '-' { c } else { '_' })
+        .take(100)
+        .collect();
+    if app_name.is_empty() { None } else { Some(app_name) }
+}
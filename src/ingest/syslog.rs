@@ -0,0 +1,462 @@
+use std::error::Error;
+use std::io::{self, BufRead, BufReader, Read};
+use std::net::{TcpListener, TcpStream, UdpSocket};
+use std::thread;
+
+use chrono::{DateTime, Datelike, Duration, NaiveDateTime, Utc};
+use serde_json::{Map, Value};
+
+use crate::db::{EventDb, SharedEventDb};
+use crate::limits::TextLimits;
+use crate::model::{self, Event, Severity, Status};
+use crate::webhooks::{Operation, Webhooks};
+
+/// Longest message accepted over TCP. Longer newline-framed lines are cut
+/// off, and the rest of them dropped.
+const MAX_FRAME: usize = 64 * 1024;
+
+static FACILITIES: [&str; 24] = [
+    "kern", "user", "mail", "daemon", "auth", "syslog", "lpr", "news",
+    "uucp", "cron", "authpriv", "ftp", "ntp", "security", "console", "solaris-cron",
+    "local0", "local1", "local2", "local3", "local4", "local5", "local6", "local7",
+];
+
+static SEVERITIES: [&str; 8] = ["emerg", "alert", "crit", "err", "warning", "notice", "info", "debug"];
+
+/// Syslog listener settings read from the `[global]` section of
+/// `Rocket.toml`. The listener is off unless an address is set:
+///
+/// * `syslog_udp`: address to receive datagrams on, e.g. `"0.0.0.0:5514"`.
+/// * `syslog_tcp`: address to accept connections on. Messages are framed by
+///   newlines or by octet counting, as in RFC 6587.
+/// * `syslog_facilities`: comma-separated facilities to keep, by name or
+///   number, e.g. `"daemon,local0"`. Defaults to all.
+/// * `syslog_severity`: least severe level to keep, e.g. `"warning"`.
+///   Defaults to `debug`, keeping all.
+///
+/// Texts are cut at `max_event_text`, as for events posted to the API.
+/// Events are stored unscoped, whatever their `appName`.
+#[derive(Debug, Clone)]
+pub struct SyslogConfig {
+    pub udp: Option<String>,
+    pub tcp: Option<String>,
+    pub facilities: Vec<u8>,
+    pub max_severity: u8,
+    pub max_text: usize,
+}
+
+impl SyslogConfig {
+    pub fn from_rocket(rocket: &rocket::Rocket) -> Result<SyslogConfig, String> {
+        let config = rocket.config();
+        let facilities = match config.get_string("syslog_facilities") {
+            Ok(facilities) => facilities.split(',')
+                .filter(|f| !f.trim().is_empty())
+                .map(|f| parse_level(&FACILITIES, f).ok_or(format!("Unknown syslog facility {}", f)))
+                .collect::<Result<Vec<u8>, String>>()?,
+            Err(_) => Vec::new(),
+        };
+        let max_severity = match config.get_string("syslog_severity") {
+            Ok(severity) => parse_level(&SEVERITIES, &severity).ok_or(format!("Unknown syslog severity {}", severity))?,
+            Err(_) => 7,
+        };
+        Ok(SyslogConfig {
+            udp: config.get_string("syslog_udp").ok(),
+            tcp: config.get_string("syslog_tcp").ok(),
+            facilities,
+            max_severity,
+            max_text: TextLimits::from_rocket(rocket).event,
+        })
+    }
+
+    pub fn accepts(&self, message: &Message) -> bool {
+        message.severity <= self.max_severity
+            && (self.facilities.is_empty() || self.facilities.contains(&message.facility))
+    }
+}
+
+/// A level by name or number.
+fn parse_level(names: &[&str], level: &str) -> Option<u8> {
+    let level = level.trim().to_lowercase();
+    if let Ok(number) = level.parse::<u8>() {
+        return if (number as usize) < names.len() { Some(number) } else { None };
+    }
+    let level = match level.as_str() {
+        "emergency" | "panic" => "emerg",
+        "critical" => "crit",
+        "error" => "err",
+        "warn" => "warning",
+        other => other,
+    };
+    names.iter().position(|name| *name == level).map(|position| position as u8)
+}
+
+/// A parsed syslog message.
+#[derive(Debug, Clone)]
+pub struct Message {
+    pub facility: u8,
+    pub severity: u8,
+    pub timestamp: Option<i64>,
+    pub hostname: Option<String>,
+    pub app_name: Option<String>,
+    pub text: String,
+}
+
+impl Message {
+    /// The message as an event, starting at its timestamp, or at `now` when
+    /// it has none. The text is cut at `max_text` characters.
+    pub fn to_event(&self, now: i64, max_text: usize) -> Event {
+        Event {
+            id: None,
+            from: self.timestamp.unwrap_or(now).max(0),
+            to: None,
+            text: self.text.chars().take(max_text).collect(),
+            app_name: self.app_name.as_ref().and_then(|app_name| super::sanitize_app_name(app_name)),
+            source_id: None,
+            source_name: self.hostname.as_ref().map(|hostname| hostname.chars().take(200).collect()),
+            tags: Vec::new(),
+            metadata: self.metadata(),
+            severity: match self.severity {
+                0..=2 => Severity::Critical,
+                3..=4 => Severity::Warning,
+                _ => Severity::Info,
+            },
+            status: Some(Status::Open),
+            created_by: None,
+            _links: None,
+            _templates: None,
+            _embedded: None,
+        }
+    }
+
+    /// The facility and severity by name, so that events can be filtered
+    /// with e.g. `meta.severity=err`.
+    fn metadata(&self) -> Map<String, Value> {
+        let mut metadata = Map::new();
+        metadata.insert("facility".to_string(), Value::String(FACILITIES[self.facility as usize % FACILITIES.len()].to_string()));
+        metadata.insert("severity".to_string(), Value::String(SEVERITIES[self.severity as usize % SEVERITIES.len()].to_string()));
+        metadata
+    }
+}
+
+/// Parses an RFC 5424 message, or failing that an RFC 3164 one.
+pub fn parse(line: &str) -> Result<Message, String> {
+    let line = line.trim_end_matches(|c| c == '\r' || c == '\n' || c == '\0');
+    let end = line.find('>').filter(|end| line.starts_with('<') && *end > 1 && *end <= 4).ok_or("Missing priority")?;
+    let priority: u8 = line[1..end].parse().map_err(|_| "Invalid priority")?;
+    if priority > 191 {
+        return Err("Invalid priority".to_string());
+    }
+    let rest = &line[end + 1..];
+    let mut message = if rest.starts_with("1 ") {
+        parse_rfc5424(&rest[2..])?
+    } else {
+        parse_rfc3164(rest, Utc::now())
+    };
+    message.facility = priority / 8;
+    message.severity = priority % 8;
+    Ok(message)
+}
+
+/// `TIMESTAMP HOSTNAME APP-NAME PROCID MSGID STRUCTURED-DATA [MSG]`
+fn parse_rfc5424(rest: &str) -> Result<Message, String> {
+    let mut fields = rest.splitn(6, ' ');
+    let timestamp = match fields.next().and_then(nil) {
+        Some(timestamp) => Some(DateTime::parse_from_rfc3339(&timestamp)
+            .map_err(|_| format!("Invalid timestamp {}", timestamp))?
+            .timestamp_millis()),
+        None => None,
+    };
+    let hostname = fields.next().and_then(nil);
+    let app_name = fields.next().and_then(nil);
+    let _proc_id = fields.next();
+    let _msg_id = fields.next();
+    let text = skip_structured_data(fields.next().unwrap_or(""));
+    Ok(Message {
+        facility: 0,
+        severity: 0,
+        timestamp,
+        hostname,
+        app_name,
+        text: text.trim_start_matches('\u{feff}').to_string(),
+    })
+}
+
+/// `Mmm dd hh:mm:ss HOSTNAME TAG: MSG`. The timestamp has no year, so the
+/// one that puts it closest before `now` is used.
+fn parse_rfc3164(rest: &str, now: DateTime<Utc>) -> Message {
+    let timestamp = rest.get(..15).and_then(|timestamp| {
+        let parsed = NaiveDateTime::parse_from_str(&format!("{} {}", now.year(), timestamp), "%Y %b %e %H:%M:%S").ok()?;
+        if parsed > now.naive_utc() + Duration::days(1) {
+            parsed.with_year(now.year() - 1)
+        } else {
+            Some(parsed)
+        }
+    });
+    let (hostname, rest) = match timestamp {
+        Some(_) => {
+            let (hostname, rest) = split_word(rest[15..].trim_start());
+            (nil(hostname), rest)
+        },
+        None => (None, rest),
+    };
+    let (tag, after_tag) = split_word(rest);
+    let (app_name, text) = if tag.ends_with(':') {
+        let tag = tag.trim_end_matches(':');
+        (nil(tag.split('[').next().unwrap_or(tag)), after_tag)
+    } else {
+        (None, rest)
+    };
+    Message {
+        facility: 0,
+        severity: 0,
+        timestamp: timestamp.map(|timestamp| timestamp.timestamp_millis()),
+        hostname,
+        app_name,
+        text: text.to_string(),
+    }
+}
+
+fn nil(value: &str) -> Option<String> {
+    if value.is_empty() || value == "-" { None } else { Some(value.to_string()) }
+}
+
+fn split_word(text: &str) -> (&str, &str) {
+    match text.find(' ') {
+        Some(space) => (&text[..space], &text[space + 1..]),
+        None => (text, ""),
+    }
+}
+
+/// Skips `-` or the `[...]` elements before the message.
+fn skip_structured_data(rest: &str) -> &str {
+    if rest == "-" || rest.starts_with("- ") {
+        return rest.get(2..).unwrap_or("");
+    }
+    let mut in_element = false;
+    let mut escaped = false;
+    for (index, c) in rest.char_indices() {
+        if escaped {
+            escaped = false;
+            continue;
+        }
+        match c {
+            '\\' if in_element => escaped = true,
+            '[' if !in_element => in_element = true,
+            ']' if in_element => in_element = false,
+            ' ' if !in_element => return &rest[index + 1..],
+            _ if !in_element => return &rest[index..],
+            _ => {},
+        }
+    }
+    ""
+}
+
+/// Binds the configured addresses and receives messages on background
+/// threads. Events are stored in `database` and announced to `webhooks`.
+pub fn start(config: SyslogConfig, database: SharedEventDb, webhooks: Webhooks) -> Result<(), Box<dyn Error>> {
+    if let Some(ref address) = config.udp {
+        let socket = UdpSocket::bind(address)?;
+        let receiver = Receiver { config: config.clone(), database: database.clone(), webhooks: webhooks.clone() };
+        thread::spawn(move || receiver.listen_udp(socket));
+    }
+    if let Some(ref address) = config.tcp {
+        let listener = TcpListener::bind(address)?;
+        let receiver = Receiver { config: config.clone(), database, webhooks };
+        thread::spawn(move || receiver.listen_tcp(listener));
+    }
+    Ok(())
+}
+
+#[derive(Clone)]
+struct Receiver {
+    config: SyslogConfig,
+    database: SharedEventDb,
+    webhooks: Webhooks,
+}
+
+impl Receiver {
+    fn listen_udp(&self, socket: UdpSocket) {
+        let mut buffer = vec![0u8; MAX_FRAME];
+        loop {
+            match socket.recv_from(&mut buffer) {
+                Ok((size, _)) => self.receive(&String::from_utf8_lossy(&buffer[..size])),
+                Err(err) => eprintln!("Could not receive syslog datagram: {}", err),
+            }
+        }
+    }
+
+    fn listen_tcp(&self, listener: TcpListener) {
+        for stream in listener.incoming() {
+            match stream {
+                Ok(stream) => {
+                    let receiver = self.clone();
+                    thread::spawn(move || receiver.read_stream(stream));
+                },
+                Err(err) => eprintln!("Could not accept syslog connection: {}", err),
+            }
+        }
+    }
+
+    fn read_stream(&self, stream: TcpStream) {
+        let mut reader = BufReader::new(stream);
+        loop {
+            match read_frame(&mut reader) {
+                Ok(Some(frame)) => self.receive(&frame),
+                Ok(None) => return,
+                Err(err) => {
+                    eprintln!("Could not read syslog stream: {}", err);
+                    return;
+                },
+            }
+        }
+    }
+
+    fn receive(&self, data: &str) {
+        if data.trim().is_empty() {
+            return;
+        }
+        if let Err(err) = self.store(data) {
+            eprintln!("Could not ingest syslog message: {}", err);
+        }
+    }
+
+    fn store(&self, data: &str) -> Result<(), Box<dyn Error>> {
+        let message = parse(data)?;
+        if !self.config.accepts(&message) {
+            return Ok(());
+        }
+        let event = message.to_event(Utc::now().timestamp_millis(), self.config.max_text);
+        model::validate_event(&event)?;
+        let event = self.database.create_event(event)?;
+        self.webhooks.notify(Operation::EventCreated, &event, None)
+    }
+}
+
+/// Reads one message, framed by octet counting or by a newline.
+fn read_frame<R: BufRead>(reader: &mut R) -> io::Result<Option<String>> {
+    let first = match reader.fill_buf()?.first() {
+        Some(first) => *first,
+        None => return Ok(None),
+    };
+    let mut bytes: Vec<u8> = Vec::new();
+    if first.is_ascii_digit() {
+        reader.by_ref().take(8).read_until(b' ', &mut bytes)?;
+        let length: usize = String::from_utf8_lossy(&bytes).trim().parse()
+            .ok()
+            .filter(|length| *length <= MAX_FRAME)
+            .ok_or(io::Error::new(io::ErrorKind::InvalidData, "Invalid frame length"))?;
+        bytes = vec![0u8; length];
+        reader.read_exact(&mut bytes)?;
+    } else {
+        reader.by_ref().take(MAX_FRAME as u64).read_until(b'\n', &mut bytes)?;
+        if bytes.len() == MAX_FRAME && bytes.last() != Some(&b'\n') {
+            skip_line(reader)?;
+        }
+    }
+    Ok(Some(String::from_utf8_lossy(&bytes).into_owned()))
+}
+
+/// Drops everything up to and including the next newline.
+fn skip_line<R: BufRead>(reader: &mut R) -> io::Result<()> {
+    loop {
+        let (done, length) = {
+            let buffer = reader.fill_buf()?;
+            match buffer.iter().position(|byte| *byte == b'\n') {
+                Some(position) => (true, position + 1),
+                None => (buffer.is_empty(), buffer.len()),
+            }
+        };
+        reader.consume(length);
+        if done {
+            return Ok(());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Cursor;
+
+    use super::*;
+
+    fn utc(timestamp: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(timestamp).unwrap().with_timezone(&Utc)
+    }
+
+    #[test]
+    fn parses_rfc5424() {
+        let line = "<165>1 2003-10-11T22:14:15.003Z mymachine.example.com evntslog - ID47 [exampleSDID@32473 iut=\"3\"] An application event\n";
+        let message = parse(line).unwrap();
+        assert_eq!(message.facility, 20);
+        assert_eq!(message.severity, 5);
+        assert_eq!(message.timestamp, Some(1065910455003));
+        assert_eq!(message.hostname.as_deref(), Some("mymachine.example.com"));
+        assert_eq!(message.app_name.as_deref(), Some("evntslog"));
+        assert_eq!(message.text, "An application event");
+    }
+
+    #[test]
+    fn rejects_bad_priorities() {
+        assert!(parse("no priority").is_err());
+        assert!(parse("<192>1 - - - - - -").is_err());
+        assert!(parse("<>hello").is_err());
+        assert!(parse("<1234>hello").is_err());
+    }
+
+    #[test]
+    fn parses_rfc3164() {
+        let message = parse_rfc3164("Oct 11 22:14:15 mymachine su[42]: 'su root' failed", utc("2003-10-12T00:00:00Z"));
+        assert_eq!(message.timestamp, Some(utc("2003-10-11T22:14:15Z").timestamp_millis()));
+        assert_eq!(message.hostname.as_deref(), Some("mymachine"));
+        assert_eq!(message.app_name.as_deref(), Some("su"));
+        assert_eq!(message.text, "'su root' failed");
+    }
+
+    #[test]
+    fn rfc3164_timestamps_fall_before_now() {
+        let message = parse_rfc3164("Dec 31 23:59:59 host app: late", utc("2004-01-01T00:00:10Z"));
+        assert_eq!(message.timestamp, Some(utc("2003-12-31T23:59:59Z").timestamp_millis()));
+    }
+
+    #[test]
+    fn rfc3164_without_header_keeps_the_text() {
+        let message = parse_rfc3164("just some text", utc("2004-01-01T00:00:00Z"));
+        assert_eq!(message.timestamp, None);
+        assert_eq!(message.hostname, None);
+        assert_eq!(message.app_name, None);
+        assert_eq!(message.text, "just some text");
+    }
+
+    #[test]
+    fn skips_structured_data() {
+        assert_eq!(skip_structured_data("-"), "");
+        assert_eq!(skip_structured_data("- hello"), "hello");
+        assert_eq!(skip_structured_data("[a x=\"1\"][b] hello"), "hello");
+        assert_eq!(skip_structured_data("[a x=\"\\]\"] hello"), "hello");
+        assert_eq!(skip_structured_data("[a x=\"1\"]"), "");
+    }
+
+    #[test]
+    fn reads_counted_and_newline_frames() {
+        let mut reader = Cursor::new(b"5 hello<13>hi\n<13>last".to_vec());
+        assert_eq!(read_frame(&mut reader).unwrap().as_deref(), Some("hello"));
+        assert_eq!(read_frame(&mut reader).unwrap().as_deref(), Some("<13>hi\n"));
+        assert_eq!(read_frame(&mut reader).unwrap().as_deref(), Some("<13>last"));
+        assert_eq!(read_frame(&mut reader).unwrap(), None);
+    }
+
+    #[test]
+    fn drops_the_rest_of_overlong_lines() {
+        let mut data = vec![b'x'; MAX_FRAME + 100];
+        data.extend_from_slice(b"\n<13>next\n");
+        let mut reader = Cursor::new(data);
+        assert_eq!(read_frame(&mut reader).unwrap().map(|frame| frame.len()), Some(MAX_FRAME));
+        assert_eq!(read_frame(&mut reader).unwrap().as_deref(), Some("<13>next\n"));
+    }
+
+    #[test]
+    fn rejects_oversized_frames() {
+        let mut reader = Cursor::new(b"99999999 x".to_vec());
+        assert!(read_frame(&mut reader).is_err());
+    }
+}
@@ -1,3 +1,14 @@
+pub mod auth;
+pub mod cors;
 pub mod db;
 pub mod model;
 pub mod envelope;
+pub mod feed;
+pub mod grafana;
+pub mod ical;
+pub mod ingest;
+pub mod limits;
+pub mod links;
+pub mod openapi;
+pub mod transfer;
+pub mod webhooks;
@@ -0,0 +1,366 @@
+use std::collections::HashMap;
+use std::io::Read;
+use std::ops::Deref;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use rocket::{Outcome, Request, State};
+use rocket::data::{self, Data, FromDataSimple};
+use rocket::http::{Header, Status};
+use rocket::response::{self, Responder, Response};
+use serde::de::DeserializeOwned;
+
+use crate::auth::Scope;
+use crate::envelope::Envelope;
+use crate::model::{Comment, Event};
+
+/// Buckets are dropped once this many clients are tracked and they have
+/// refilled, so that the map does not grow without bound.
+const MAX_TRACKED_CLIENTS: usize = 10000;
+
+/// Requests allowed per period, e.g. `"120/min"`. The bucket holds up to
+/// `capacity` requests and refills evenly over the period.
+#[derive(Debug, Clone, Copy)]
+pub struct Rate {
+    pub capacity: f64,
+    pub per_second: f64,
+}
+
+impl Rate {
+    /// Parses `<count>/<s|sec|min|h|hour>`.
+    pub fn parse(rate: &str) -> Result<Rate, String> {
+        let mut parts = rate.splitn(2, '/');
+        let count: f64 = parts.next()
+            .and_then(|count| count.trim().parse().ok())
+            .filter(|count| *count > 0.0)
+            .ok_or(format!("Invalid rate {}", rate))?;
+        let seconds = match parts.next().map(|unit| unit.trim()) {
+            Some("s") | Some("sec") => 1.0,
+            Some("min") => 60.0,
+            Some("h") | Some("hour") => 3600.0,
+            _ => return Err(format!("Invalid rate {}, expected e.g. 120/min", rate)),
+        };
+        Ok(Rate { capacity: count, per_second: count / seconds })
+    }
+}
+
+/// Which limit a route counts against.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub enum RouteGroup {
+    Reads,
+    Writes,
+}
+
+impl RouteGroup {
+    pub fn for_scope(scope: Scope) -> RouteGroup {
+        match scope {
+            Scope::EventsRead => RouteGroup::Reads,
+            _ => RouteGroup::Writes,
+        }
+    }
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token-bucket rate limits per client, read from the `[global]` section of
+/// `Rocket.toml`:
+///
+/// * `rate_limit_reads`: limit for routes needing `events:read`, e.g.
+///   `"600/min"`. Unlimited when unset.
+/// * `rate_limit_writes`: limit for every other route, e.g. `"60/min"`.
+///   Unlimited when unset.
+///
+/// Clients are told apart by API key or token subject, and by IP address
+/// when anonymous.
+pub struct RateLimiter {
+    reads: Option<Rate>,
+    writes: Option<Rate>,
+    buckets: Mutex<HashMap<(RouteGroup, String), Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(reads: Option<Rate>, writes: Option<Rate>) -> RateLimiter {
+        RateLimiter { reads, writes, buckets: Mutex::new(HashMap::new()) }
+    }
+
+    pub fn from_rocket(rocket: &rocket::Rocket) -> Result<RateLimiter, String> {
+        let rate = |key: &str| rocket.config().get_string(key).ok().map(|rate| Rate::parse(&rate)).transpose();
+        Ok(RateLimiter::new(rate("rate_limit_reads")?, rate("rate_limit_writes")?))
+    }
+
+    fn rate(&self, group: RouteGroup) -> Option<Rate> {
+        match group {
+            RouteGroup::Reads => self.reads,
+            RouteGroup::Writes => self.writes,
+        }
+    }
+
+    /// Takes a token from the client's bucket. When it is empty, returns the
+    /// number of seconds until the next token.
+    pub fn take(&self, group: RouteGroup, client: &str) -> Result<(), u64> {
+        let rate = match self.rate(group) {
+            Some(rate) => rate,
+            None => return Ok(()),
+        };
+
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if buckets.len() >= MAX_TRACKED_CLIENTS {
+            buckets.retain(|(group, _), bucket| match self.rate(*group) {
+                Some(rate) => bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate.per_second < rate.capacity,
+                None => false,
+            });
+        }
+        let bucket = buckets
+            .entry((group, client.to_string()))
+            .or_insert(Bucket { tokens: rate.capacity, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate.per_second).min(rate.capacity);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(())
+        } else {
+            Err(((1.0 - bucket.tokens) / rate.per_second).ceil() as u64)
+        }
+    }
+}
+
+/// Seconds to wait, kept for the 429 catcher.
+pub struct RetryAfter(pub Option<u64>);
+
+/// A 429 envelope with a `Retry-After` header.
+pub struct TooManyRequests(pub Envelope, pub Option<u64>);
+
+impl<'r> Responder<'r> for TooManyRequests {
+    fn respond_to(self, request: &Request) -> response::Result<'r> {
+        let mut builder = Response::build_from(self.0.respond_to(request)?);
+        builder.status(Status::TooManyRequests);
+        if let Some(seconds) = self.1 {
+            builder.header(Header::new("Retry-After", seconds.to_string()));
+        }
+        builder.ok()
+    }
+}
+
+/// Maximum text lengths in characters, read from the `[global]` section of
+/// `Rocket.toml` as `max_event_text` and `max_comment_text`. Both default to
+/// 10000, which is also the cap in `model::schema`, so they can only be
+/// lowered.
+///
+/// Body sizes in bytes come from Rocket's own `limits` table, under the
+/// `event`, `comment` and `import` keys, e.g. `limits = { event = 65536 }`.
+#[derive(Debug, Clone)]
+pub struct TextLimits {
+    pub event: usize,
+    pub comment: usize,
+}
+
+impl TextLimits {
+    pub fn from_rocket(rocket: &rocket::Rocket) -> TextLimits {
+        let limit = |key: &str| rocket.config().get_int(key).ok().filter(|n| *n > 0).map_or(10000, |n| n as usize);
+        TextLimits { event: limit("max_event_text"), comment: limit("max_comment_text") }
+    }
+}
+
+/// A JSON request body with limits on its size and text length.
+pub trait BodyLimits: DeserializeOwned {
+    /// Key in Rocket's `limits` table.
+    const LIMIT: &'static str;
+    /// Size in bytes when the key is not set.
+    const DEFAULT_SIZE: u64;
+
+    fn text_length(&self) -> usize;
+    fn max_text_length(limits: &TextLimits) -> usize;
+}
+
+impl BodyLimits for Event {
+    const LIMIT: &'static str = "event";
+    const DEFAULT_SIZE: u64 = 64 * 1024;
+
+    fn text_length(&self) -> usize {
+        self.text.chars().count()
+    }
+
+    fn max_text_length(limits: &TextLimits) -> usize {
+        limits.event
+    }
+}
+
+impl BodyLimits for Comment {
+    const LIMIT: &'static str = "comment";
+    const DEFAULT_SIZE: u64 = 16 * 1024;
+
+    fn text_length(&self) -> usize {
+        self.comment.chars().count()
+    }
+
+    fn max_text_length(limits: &TextLimits) -> usize {
+        limits.comment
+    }
+}
+
+/// Why a limited body was turned away, kept for the catchers.
+pub struct BodyFailure(pub Option<String>);
+
+/// Data guard like `Json<T>` that answers 413 when the body or its text is
+/// too long.
+pub struct Limited<T>(pub T);
+
+impl<T> Limited<T> {
+    pub fn into_inner(self) -> T {
+        self.0
+    }
+}
+
+impl<T> Deref for Limited<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        &self.0
+    }
+}
+
+impl<T: BodyLimits> FromDataSimple for Limited<T> {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, String> {
+        let body = match read_body(data, request.limits().get(T::LIMIT).unwrap_or(T::DEFAULT_SIZE)) {
+            Ok(body) => body,
+            Err((status, error)) => return body_failure(request, status, error),
+        };
+
+        let value: T = match serde_json::from_str(&body) {
+            Ok(value) => value,
+            Err(err) => return body_failure(request, Status::UnprocessableEntity, format!("Invalid body: {}", err)),
+        };
+        if let Outcome::Success(limits) = request.guard::<State<TextLimits>>() {
+            let max = T::max_text_length(&limits);
+            if value.text_length() > max {
+                return body_failure(request, Status::PayloadTooLarge, format!("Text exceeds {} characters", max));
+            }
+        }
+        Outcome::Success(Limited(value))
+    }
+}
+
+/// A raw import body, limited by the `import` key of Rocket's `limits`
+/// table. Bodies over the limit are answered with 413 rather than cut off.
+pub struct ImportBody(pub String);
+
+impl ImportBody {
+    const DEFAULT_SIZE: u64 = 50 * 1024 * 1024;
+}
+
+impl FromDataSimple for ImportBody {
+    type Error = String;
+
+    fn from_data(request: &Request, data: Data) -> data::Outcome<Self, String> {
+        match read_body(data, request.limits().get("import").unwrap_or(ImportBody::DEFAULT_SIZE)) {
+            Ok(body) => Outcome::Success(ImportBody(body)),
+            Err((status, error)) => body_failure(request, status, error),
+        }
+    }
+}
+
+/// Reads one byte past `size`, so that longer bodies are told apart from
+/// ones that fit exactly.
+fn read_body(data: Data, size: u64) -> Result<String, (Status, String)> {
+    let mut body = String::new();
+    if let Err(err) = data.open().take(size + 1).read_to_string(&mut body) {
+        return Err((Status::BadRequest, format!("Could not read body: {}", err)));
+    }
+    if body.len() as u64 > size {
+        return Err((Status::PayloadTooLarge, format!("Body exceeds {} bytes", size)));
+    }
+    Ok(body)
+}
+
+fn body_failure<T>(request: &Request, status: Status, error: String) -> data::Outcome<T, String> {
+    request.local_cache(|| BodyFailure(Some(error.clone())));
+    Outcome::Failure((status, error))
+}
+
+/// Describes why a body was turned away, for the error catchers.
+pub fn failure_description(request: &Request) -> String {
+    match request.local_cache(|| BodyFailure(None)).0 {
+        Some(ref error) => error.clone(),
+        None => "Invalid request body".to_string(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::thread;
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn rates_parse_counts_and_units() {
+        let rate = Rate::parse("120/min").unwrap();
+        assert_eq!(rate.capacity, 120.0);
+        assert_eq!(rate.per_second, 2.0);
+        assert!(Rate::parse("0/min").is_err());
+        assert!(Rate::parse("10/day").is_err());
+    }
+
+    #[test]
+    fn empty_buckets_report_seconds_to_wait() {
+        let limiter = RateLimiter::new(Some(Rate::parse("2/min").unwrap()), None);
+        assert_eq!(limiter.take(RouteGroup::Reads, "a"), Ok(()));
+        assert_eq!(limiter.take(RouteGroup::Reads, "a"), Ok(()));
+        assert_eq!(limiter.take(RouteGroup::Reads, "a"), Err(30));
+        assert_eq!(limiter.take(RouteGroup::Reads, "b"), Ok(()));
+        assert_eq!(limiter.take(RouteGroup::Writes, "a"), Ok(()));
+    }
+
+    #[test]
+    fn pruning_judges_buckets_by_their_own_rate() {
+        let limiter = RateLimiter::new(Some(Rate::parse("1/h").unwrap()), Some(Rate::parse("1000/s").unwrap()));
+        assert!(limiter.take(RouteGroup::Reads, "a").is_ok());
+        assert!(limiter.take(RouteGroup::Reads, "a").is_err());
+        thread::sleep(Duration::from_millis(1100));
+        for client in 0..MAX_TRACKED_CLIENTS {
+            limiter.take(RouteGroup::Writes, &client.to_string()).unwrap();
+        }
+        assert!(limiter.take(RouteGroup::Reads, "a").is_err());
+    }
+
+    #[test]
+    fn import_bodies_over_the_limit_are_refused() {
+        use rocket::config::{Config, Environment, Limits};
+        use rocket::handler::{self, Handler};
+        use rocket::local::Client;
+        use rocket::Route;
+        use rocket::http::Method;
+
+        #[derive(Clone)]
+        struct Import;
+
+        impl Handler for Import {
+            fn handle<'r>(&self, request: &'r Request, data: Data) -> handler::Outcome<'r> {
+                match ImportBody::from_data(request, data) {
+                    Outcome::Success(body) => handler::Outcome::from(request, body.0),
+                    Outcome::Failure((status, _)) => handler::Outcome::Failure(status),
+                    Outcome::Forward(data) => handler::Outcome::Forward(data),
+                }
+            }
+        }
+
+        let config = Config::build(Environment::Development)
+            .limits(Limits::new().limit("import", 4))
+            .finalize()
+            .unwrap();
+        let client = Client::new(rocket::custom(config).mount("/", vec![Route::new(Method::Post, "/import", Import)])).unwrap();
+        let mut response = client.post("/import").body("1234").dispatch();
+        assert_eq!(response.status(), Status::Ok);
+        assert_eq!(response.body_string().as_deref(), Some("1234"));
+        let response = client.post("/import").body("12345").dispatch();
+        assert_eq!(response.status(), Status::PayloadTooLarge);
+    }
+}
@@ -0,0 +1,176 @@
+use rocket::{Outcome, State};
+use rocket::request::{self, FromRequest, Request};
+
+use crate::envelope::Link;
+
+/// Link settings read from the `[global]` section of `Rocket.toml`:
+///
+/// * `public_base_url`: prefix for every generated href, e.g.
+///   `https://example.com/api`. When unset, hrefs are relative.
+/// * `trust_forwarded_headers`: derive the base URL from `Forwarded` or
+///   `X-Forwarded-Proto`/`-Host`/`-Prefix` when no base URL is configured.
+///   Only enable this behind a proxy that sets these headers.
+#[derive(Debug, Clone, Default)]
+pub struct LinkConfig {
+    pub public_base_url: Option<String>,
+    pub trust_forwarded_headers: bool,
+}
+
+impl LinkConfig {
+    pub fn from_rocket(rocket: &rocket::Rocket) -> LinkConfig {
+        LinkConfig {
+            public_base_url: rocket.config().get_string("public_base_url").ok(),
+            trust_forwarded_headers: rocket.config().get_bool("trust_forwarded_headers").unwrap_or(false),
+        }
+    }
+}
+
+/// Builds every href the API hands out, so that resources, templates, feeds
+/// and calendars agree on where things live.
+#[derive(Debug, Clone, Default)]
+pub struct LinkBuilder {
+    base: String,
+}
+
+impl LinkBuilder {
+    pub fn new(base: &str) -> LinkBuilder {
+        LinkBuilder { base: base.trim_end_matches('/').to_string() }
+    }
+
+    pub fn href(&self, path: &str) -> String {
+        format!("{}{}", self.base, path)
+    }
+
+    pub fn link(&self, key: &str, path: &str) -> Link {
+        Link { key: key.to_string(), href: self.href(path), templated: None }
+    }
+
+    /// Creates a link from an RFC 6570 URI template, e.g. `/events{?from,to}`.
+    pub fn templated(&self, key: &str, template: &str) -> Link {
+        Link { key: key.to_string(), href: self.href(template), templated: Some(true) }
+    }
+
+    pub fn events(&self) -> String {
+        self.href(&paths::events())
+    }
+
+    pub fn event(&self, event_id: &str) -> String {
+        self.href(&paths::event(event_id))
+    }
+
+    pub fn event_comments(&self, event_id: &str) -> String {
+        self.href(&paths::event_comments(event_id))
+    }
+
+    pub fn event_acknowledge(&self, event_id: &str) -> String {
+        self.href(&paths::event_acknowledge(event_id))
+    }
+
+    pub fn event_resolve(&self, event_id: &str) -> String {
+        self.href(&paths::event_resolve(event_id))
+    }
+
+    pub fn comment(&self, event_id: &str, comment_id: &str) -> String {
+        self.href(&paths::comment(event_id, comment_id))
+    }
+
+    pub fn comments(&self) -> String {
+        self.href(&paths::comments())
+    }
+}
+
+/// Paths of the API's resources, relative to the base URL. Pass them to
+/// `LinkBuilder::link` or `LinkBuilder::href`.
+pub mod paths {
+    use rocket::http::uri::Uri;
+
+    pub fn events() -> String {
+        "/events".to_string()
+    }
+
+    pub fn event(event_id: &str) -> String {
+        format!("/events/{}", Uri::percent_encode(event_id))
+    }
+
+    pub fn event_comments(event_id: &str) -> String {
+        format!("/events/{}/comments", Uri::percent_encode(event_id))
+    }
+
+    pub fn event_acknowledge(event_id: &str) -> String {
+        format!("/events/{}/acknowledge", Uri::percent_encode(event_id))
+    }
+
+    pub fn event_resolve(event_id: &str) -> String {
+        format!("/events/{}/resolve", Uri::percent_encode(event_id))
+    }
+
+    pub fn comment(event_id: &str, comment_id: &str) -> String {
+        format!("/events/{}/comments/{}", Uri::percent_encode(event_id), Uri::percent_encode(comment_id))
+    }
+
+    pub fn comments() -> String {
+        "/comments".to_string()
+    }
+}
+
+impl<'a, 'r> FromRequest<'a, 'r> for LinkBuilder {
+    type Error = ();
+
+    fn from_request(request: &'a Request<'r>) -> request::Outcome<Self, ()> {
+        let config = request.guard::<State<LinkConfig>>()
+            .succeeded()
+            .map(|config| config.inner().clone())
+            .unwrap_or_default();
+
+        if let Some(ref base) = config.public_base_url {
+            return Outcome::Success(LinkBuilder::new(base));
+        }
+        if config.trust_forwarded_headers {
+            if let Some(base) = forwarded_base(request) {
+                return Outcome::Success(LinkBuilder::new(&base));
+            }
+        }
+        Outcome::Success(LinkBuilder::default())
+    }
+}
+
+fn forwarded_base(request: &Request) -> Option<String> {
+    let headers = request.headers();
+    let mut proto = headers.get_one("X-Forwarded-Proto").map(|v| v.to_string());
+    let mut host = headers.get_one("X-Forwarded-Host").map(|v| v.to_string());
+
+    // RFC 7239: Forwarded: for=192.0.2.60;proto=https;host=example.com
+    if let Some(forwarded) = headers.get_one("Forwarded") {
+        let first = forwarded.split(',').next().unwrap_or("");
+        for pair in first.split(';') {
+            let mut parts = pair.splitn(2, '=');
+            let key = parts.next().unwrap_or("").trim().to_lowercase();
+            let value = parts.next().unwrap_or("").trim().trim_matches('"').to_string();
+            match key.as_str() {
+                "proto" => proto = Some(value),
+                "host" => host = Some(value),
+                _ => {},
+            }
+        }
+    }
+
+    let host = host?;
+    let proto = proto.unwrap_or("http".to_string());
+    let prefix = headers.get_one("X-Forwarded-Prefix").unwrap_or("").trim_end_matches('/');
+    Some(format!("{}://{}{}", proto, host, prefix))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn links_use_the_base_and_encode_ids() {
+        let links = LinkBuilder::new("https://example.com/api/");
+        let link = links.link("self", &paths::comment("a b", "c/d"));
+        assert_eq!(link.key, "self");
+        assert_eq!(link.href, "https://example.com/api/events/a%20b/comments/c%2Fd");
+        assert_eq!(link.templated, None);
+        assert_eq!(links.event("x"), "https://example.com/api/events/x");
+    }
+}
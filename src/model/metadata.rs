@@ -0,0 +1,121 @@
+use lazy_static::lazy_static;
+use regex::Regex;
+use rocket::request::{FromQuery, Query};
+use serde_json::{Map, Value};
+
+/// Largest serialized `metadata` object accepted, in bytes.
+pub const MAX_BYTES: usize = 8 * 1024;
+
+/// Most keys accepted in a `metadata` object.
+pub const MAX_KEYS: usize = 50;
+
+/// Metadata keys. Dots are left out so that `meta.<key>` is unambiguous.
+pub const KEY_REGEX: &str = "^[A-Za-z0-9_-]{1,100}$";
+
+/// Query parameters carrying metadata conditions are prefixed with this.
+pub const QUERY_PREFIX: &str = "meta.";
+
+lazy_static! {
+    static ref KEY: Regex = Regex::new(KEY_REGEX).expect("Invalid metadata key regex.");
+}
+
+pub fn is_valid_key(key: &str) -> bool {
+    KEY.is_match(key)
+}
+
+/// A condition on one metadata key: its value must equal `value`, or, when
+/// `value` is `None`, the key must be present.
+#[derive(Debug, Clone, PartialEq)]
+pub struct MetadataFilter {
+    pub key: String,
+    pub value: Option<String>,
+}
+
+impl MetadataFilter {
+    /// Parses `key=value` or `key`.
+    pub fn parse(condition: &str) -> MetadataFilter {
+        let mut parts = condition.splitn(2, '=');
+        MetadataFilter {
+            key: parts.next().unwrap_or("").trim().to_string(),
+            value: parts.next().map(|value| value.to_string()),
+        }
+    }
+
+    /// Strings are compared as they are, other values by their JSON text,
+    /// so `meta.build=42` matches both `42` and `"42"`. A present key
+    /// holding `null` counts as present.
+    pub fn matches(&self, metadata: &Map<String, Value>) -> bool {
+        match (metadata.get(&self.key), &self.value) {
+            (None, _) => false,
+            (Some(_), None) => true,
+            (Some(Value::String(actual)), Some(value)) => actual == value,
+            (Some(actual), Some(value)) => actual.to_string() == *value,
+        }
+    }
+}
+
+/// The `meta.<key>` parameters of a query string, e.g.
+/// `?meta.region=eu-west&meta.ticket`. Other parameters are ignored.
+#[derive(Debug, Clone, Default)]
+pub struct MetadataQuery(pub Vec<MetadataFilter>);
+
+impl<'q> FromQuery<'q> for MetadataQuery {
+    type Error = ();
+
+    fn from_query(query: Query<'q>) -> Result<Self, Self::Error> {
+        let filters = query
+            .filter_map(|item| {
+                let key = item.key.url_decode_lossy();
+                if !key.starts_with(QUERY_PREFIX) {
+                    return None;
+                }
+                Some(MetadataFilter {
+                    key: key[QUERY_PREFIX.len()..].to_string(),
+                    value: if item.raw.as_str().contains('=') { Some(item.value.url_decode_lossy()) } else { None },
+                })
+            })
+            .collect();
+        Ok(MetadataQuery(filters))
+    }
+}
+
+pub fn validate(metadata: &Map<String, Value>) -> Result<(), String> {
+    if metadata.len() > MAX_KEYS {
+        return Err(format!("metadata must have at most {} keys", MAX_KEYS));
+    }
+    if let Some(key) = metadata.keys().find(|key| !is_valid_key(key)) {
+        return Err(format!("metadata key {} must match {}", key, KEY_REGEX));
+    }
+    let size = serde_json::to_string(metadata).map_err(|err| err.to_string())?.len();
+    if size > MAX_BYTES {
+        return Err(format!("metadata must be at most {} bytes", MAX_BYTES));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn conditions_match_values_and_presence() {
+        let metadata = json!({ "region": "eu-west", "build": 42, "ticket": null });
+        let metadata = metadata.as_object().unwrap();
+        assert!(MetadataFilter::parse("region=eu-west").matches(metadata));
+        assert!(MetadataFilter::parse("build=42").matches(metadata));
+        assert!(MetadataFilter::parse("ticket").matches(metadata));
+        assert!(!MetadataFilter::parse("region=us-east").matches(metadata));
+        assert!(!MetadataFilter::parse("owner").matches(metadata));
+    }
+
+    #[test]
+    fn validation_checks_keys_and_size() {
+        assert!(validate(json!({ "build-id_2": 1 }).as_object().unwrap()).is_ok());
+        assert!(validate(json!({ "a.b": 1 }).as_object().unwrap()).is_err());
+        assert!(validate(json!({ "big": "x".repeat(MAX_BYTES) }).as_object().unwrap()).is_err());
+        let many: Map<String, Value> = (0..=MAX_KEYS).map(|i| (format!("k{}", i), json!(i))).collect();
+        assert!(validate(&many).is_err());
+    }
+}
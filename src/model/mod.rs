@@ -1,7 +1,14 @@
+use std::collections::HashMap;
 use serde::{Deserialize, Serialize};
 use rocket_contrib::json;
+use rocket_contrib::json::JsonValue;
+use serde_json::{Map, Value};
 
-use crate::envelope::{Payload, Link, Template, MethodType, Property, create_property};
+use crate::envelope::{Payload, Link, Template, MethodType, Property, serialize_links, serialize_templates};
+use crate::links::{paths, LinkBuilder};
+
+pub mod metadata;
+pub mod schema;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Event {
@@ -15,10 +22,121 @@ pub struct Event {
     pub source_id: Option<String>,
     #[serde(rename = "sourceName")]
     pub source_name: Option<String>,
-    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[serde(default)]
+    pub tags: Vec<String>,
+    /// Free-form details such as a commit or a ticket URL, limited by
+    /// `metadata::validate`.
+    #[serde(default)]
+    pub metadata: Map<String, Value>,
+    #[serde(default)]
+    pub severity: Severity,
+    /// Changed through `set_status`, which enforces the allowed transitions.
+    /// Left out, it is open on new events and unchanged on updates; read it
+    /// through `status()`.
+    #[serde(default, serialize_with = "serialize_status")]
+    pub status: Option<Status>,
+    /// Subject of the credentials the event was created with. Set by the
+    /// server; whatever clients send is ignored.
+    #[serde(rename = "createdBy", default)]
+    pub created_by: Option<String>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none", serialize_with = "serialize_links")]
     pub _links: Option<Vec<Link>>,
-    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none", serialize_with = "serialize_templates")]
     pub _templates: Option<Vec<Template>>,
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none")]
+    pub _embedded: Option<HashMap<String, JsonValue>>,
+}
+
+impl Event {
+    pub fn status(&self) -> Status {
+        self.status.unwrap_or_default()
+    }
+}
+
+/// Events are always written with a status, open when none was given.
+fn serialize_status<S: serde::Serializer>(status: &Option<Status>, serializer: S) -> Result<S::Ok, S::Error> {
+    status.unwrap_or_default().serialize(serializer)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Severity {
+    Info,
+    Warning,
+    Critical,
+}
+
+impl Default for Severity {
+    fn default() -> Severity {
+        Severity::Info
+    }
+}
+
+impl Severity {
+    pub fn parse(severity: &str) -> Option<Severity> {
+        match severity {
+            "info" => Some(Severity::Info),
+            "warning" => Some(Severity::Warning),
+            "critical" => Some(Severity::Critical),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Severity::Info => "info",
+            Severity::Warning => "warning",
+            Severity::Critical => "critical",
+        }
+    }
+}
+
+/// Where an event is in its lifecycle: open, then acknowledged, then
+/// resolved.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Status {
+    Open,
+    Acknowledged,
+    Resolved,
+}
+
+impl Default for Status {
+    fn default() -> Status {
+        Status::Open
+    }
+}
+
+impl Status {
+    pub fn parse(status: &str) -> Option<Status> {
+        match status {
+            "open" => Some(Status::Open),
+            "acknowledged" => Some(Status::Acknowledged),
+            "resolved" => Some(Status::Resolved),
+            _ => None,
+        }
+    }
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Status::Open => "open",
+            Status::Acknowledged => "acknowledged",
+            Status::Resolved => "resolved",
+        }
+    }
+
+    /// Open events may be acknowledged or resolved, acknowledged ones
+    /// resolved or reopened, and resolved ones only reopened. Staying put
+    /// is always allowed.
+    pub fn can_become(&self, next: Status) -> bool {
+        match (*self, next) {
+            (current, next) if current == next => true,
+            (Status::Open, _) => true,
+            (Status::Acknowledged, _) => true,
+            (Status::Resolved, Status::Open) => true,
+            (Status::Resolved, _) => false,
+        }
+    }
 }
 
 #[derive(Clone, Serialize, Deserialize)]
@@ -30,71 +148,186 @@ pub struct Comment {
     pub user_id: String,
     pub comment: String,
     pub timestamp: i64,
-    #[serde(skip_deserializing)]
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none", serialize_with = "serialize_links")]
     pub _links: Option<Vec<Link>>,
-    #[serde(skip_deserializing)]
+    #[serde(skip_deserializing, skip_serializing_if = "Option::is_none", serialize_with = "serialize_templates")]
     pub _templates: Option<Vec<Template>>,
 }
 
+#[derive(Default)]
 pub struct EventFilter {
     pub from: Option<i64>,
     pub to: Option<i64>,
     pub app_name: Option<String>,
     pub source_id: Option<String>,
     pub source_name: Option<String>,
+    pub tags: Option<Vec<String>>,
+    /// Match events with any of `tags` rather than all of them.
+    pub any_tag: bool,
+    /// Conditions on `Event::metadata`, all of which must hold.
+    pub metadata: Vec<metadata::MetadataFilter>,
+    /// Match events with any of these severities.
+    pub severity: Option<Vec<Severity>>,
+    /// Match events with any of these statuses.
+    pub status: Option<Vec<Status>>,
+}
+
+/// How many events carry a tag.
+#[derive(Clone, Serialize, Deserialize)]
+pub struct TagCount {
+    pub tag: String,
+    pub count: usize,
 }
 
+#[derive(Default)]
 pub struct CommentFilter {
     pub event_id: Option<String>,
     pub user_id: Option<String>,
 }
 
-pub fn get_events_payload(events: Vec<Event>) -> Payload {
+#[derive(Clone, Serialize, Deserialize)]
+#[serde(tag = "op", rename_all = "lowercase")]
+pub enum BatchOperation {
+    Create { event: Event },
+    Update { event: Event },
+    Delete { id: String },
+}
+
+#[derive(Clone, Serialize, Deserialize)]
+pub struct BatchResult {
+    pub index: usize,
+    pub id: Option<String>,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+pub fn validate_event(event: &Event) -> Result<(), String> {
+    schema::validate(&schema::EVENT_FIELDS, &json!(event).0).map_err(|errors| errors.join("; "))?;
+    if event.to.is_some() && event.to.unwrap() < event.from {
+        return Err("Event cannot end before it starts".to_string());
+    }
+    metadata::validate(&event.metadata)
+}
+
+/// Moves an event to `status` if its current status allows it. Resolving an
+/// event that has no end sets `to` to `now`.
+pub fn set_status(event: &mut Event, status: Status, now: i64) -> Result<(), String> {
+    if !event.status().can_become(status) {
+        return Err(format!("Event cannot go from {} to {}", event.status().as_str(), status.as_str()));
+    }
+    event.status = Some(status);
+    if status == Status::Resolved && event.to.is_none() {
+        event.to = Some(now.max(event.from));
+    }
+    Ok(())
+}
+
+pub fn validate_comment(comment: &Comment) -> Result<(), String> {
+    schema::validate(&schema::COMMENT_FIELDS, &json!(comment).0).map_err(|errors| errors.join("; "))
+}
+
+/// Builds the payload for a list of events. When `comments` is given, each
+/// event embeds the comments that belong to it.
+pub fn get_events_payload(links: &LinkBuilder, events: Vec<Event>, comments: Option<Vec<Comment>>) -> Payload {
+    let events: Vec<Event> = events
+        .into_iter()
+        .map(|event| extend_event(links, event))
+        .map(|event| match comments {
+            Some(ref comments) => embed_comments(links, event, comments),
+            None => event,
+        })
+        .collect();
     Payload {
-        data: json!(events.into_iter().map(extend_event).collect::<Vec<Event>>()),
-        links: Some(event_links()),
-        templates: Some(event_templates()),
+        data: json!(events),
+        links: Some(event_links(links)),
+        templates: Some(event_templates(links)),
+        embedded: None,
     }
 }
 
-pub fn get_event_payload(event: Event) -> Payload {
-    let copy = extend_event(event.clone());
+pub fn get_event_payload(links: &LinkBuilder, event: Event, comments: Option<Vec<Comment>>) -> Payload {
+    let copy = extend_event(links, event.clone());
     Payload {
         data: json!(event),
         links: copy._links,
         templates: copy._templates,
+        embedded: comments.map(|comments| {
+            let mut embedded = HashMap::new();
+            embedded.insert("comments".to_string(), json!(extend_comments(links, &comments)));
+            embedded
+        }),
     }
 }
 
-fn extend_event(mut event: Event) -> Event {
+fn embed_comments(links: &LinkBuilder, mut event: Event, comments: &[Comment]) -> Event {
+    let belonging: Vec<Comment> = comments
+        .iter()
+        .filter(|c| event.id.as_ref() == Some(&c.event_id))
+        .cloned()
+        .collect();
+    let mut embedded = HashMap::new();
+    embedded.insert("comments".to_string(), json!(extend_comments(links, &belonging)));
+    event._embedded = Some(embedded);
+    event
+}
+
+fn extend_comments(links: &LinkBuilder, comments: &[Comment]) -> Vec<Comment> {
+    comments.iter().cloned().map(|comment| extend_comment(links, comment)).collect()
+}
+
+fn extend_event(links: &LinkBuilder, mut event: Event) -> Event {
     match event.id {
         Some(ref id) => {
-            let mut links: Vec<Link> = Vec::new();
-            links.push(Link { key: "self".to_string(), href: format!("/events/{}", &id) });
-            links.push(Link { key: "comments".to_string(), href: format!("/events/{}/comments", &id) });
-            event._links = Some(links);
+            let mut event_links: Vec<Link> = Vec::new();
+            event_links.push(links.link("self", &paths::event(&id)));
+            event_links.push(links.link("comments", &paths::event_comments(&id)));
+            event._links = Some(event_links);
 
             let mut templates: Vec<Template> = Vec::new();
             templates.push(Template {
                 key: "delete".to_string(),
                 title: None,
                 method: MethodType::DELETE,
+                content_type: None,
                 properties: None,
-                target: Some("self".to_string()),
+                target: Some(links.event(&id)),
             });
             templates.push(Template {
                 key: "update".to_string(),
                 title: None,
                 method: MethodType::PATCH,
-                properties: Some(get_event_properties()),
-                target: Some("self".to_string()),
+                content_type: Some("application/json".to_string()),
+                properties: Some(get_event_properties(links)),
+                target: Some(links.event(&id)),
             });
+            if event.status() == Status::Open {
+                templates.push(Template {
+                    key: "acknowledge".to_string(),
+                    title: None,
+                    method: MethodType::POST,
+                    content_type: None,
+                    properties: None,
+                    target: Some(links.event_acknowledge(&id)),
+                });
+            }
+            if event.status() != Status::Resolved {
+                templates.push(Template {
+                    key: "resolve".to_string(),
+                    title: None,
+                    method: MethodType::POST,
+                    content_type: None,
+                    properties: None,
+                    target: Some(links.event_resolve(&id)),
+                });
+            }
             templates.push(Template {
                 key: "comment".to_string(),
                 title: None,
                 method: MethodType::POST,
-                properties: Some(get_comment_properties(&id)),
-                target: Some("comments".to_string()),
+                content_type: Some("application/json".to_string()),
+                properties: Some(get_comment_properties(links, &id)),
+                target: Some(links.event_comments(&id)),
             });
             event._templates = Some(templates);
 
@@ -104,74 +337,86 @@ fn extend_event(mut event: Event) -> Event {
     }
 }
 
-fn event_links() -> Vec<Link> {
-    let mut links: Vec<Link> = Vec::new();
-    links.push(Link { key: "self".to_string(), href: "/events".to_string() });
-    links
+fn event_links(links: &LinkBuilder) -> Vec<Link> {
+    let mut event_links: Vec<Link> = Vec::new();
+    event_links.push(links.link("self", &paths::events()));
+    event_links.push(links.templated("search", "/events{?from,to,appName,sourceId,sourceName,tags,tagMatch,severity,status,embed,page,pageSize}"));
+    event_links.push(links.templated("event", "/events/{id}{?embed}"));
+    event_links
 }
 
-fn event_templates() -> Vec<Template> {
+fn event_templates(links: &LinkBuilder) -> Vec<Template> {
     let mut templates: Vec<Template> = Vec::new();
     templates.push(Template {
         key: "default".to_string(),
         title: Some("Create event".to_string()),
         method: MethodType::POST,
-        properties: Some(get_event_properties()),
-        target: Some("self".to_string())
+        content_type: Some("application/json".to_string()),
+        properties: Some(get_event_properties(links)),
+        target: Some(links.events())
     });
     templates
 }
 
-fn get_event_properties() -> Vec<Property> {
-    let mut properties: Vec<Property> = Vec::new();
-    properties.push(create_property("from", false, true));
-    properties.push(create_property("to", false, false));
-    properties.push(create_property("text", false, true));
-    properties.push(create_property("appName", false, false));
-    properties.push(create_property("sourceId", false, false));
-    properties.push(create_property("sourceName", false, false));
-    properties
+fn get_event_properties(links: &LinkBuilder) -> Vec<Property> {
+    schema::to_properties(links, &schema::EVENT_FIELDS)
 }
-    
-pub fn get_comments_payload(event_id: String, comments: Vec<Comment>) -> Payload {
+
+pub fn get_comments_payload(links: &LinkBuilder, event_id: String, comments: Vec<Comment>) -> Payload {
     Payload {
-        data: json!(comments.into_iter().map(extend_comment).collect::<Vec<Comment>>()),
-        links: Some(comment_links(&event_id)),
-        templates: Some(comment_templates(&event_id)),
+        data: json!(extend_comments(links, &comments)),
+        links: Some(comment_links(links, &event_id)),
+        templates: Some(comment_templates(links, &event_id)),
+        embedded: None,
     }
 }
 
-pub fn get_comment_payload(comment: Comment) -> Payload {
-    let copy = comment.clone();
+pub fn get_comment_payload(links: &LinkBuilder, comment: Comment) -> Payload {
+    let copy = extend_comment(links, comment.clone());
     Payload {
         data: json!(comment),
         links: copy._links,
         templates: copy._templates,
+        embedded: None,
     }
 }
 
-fn extend_comment(mut comment: Comment) -> Comment {
+pub fn get_all_comments_payload(links: &LinkBuilder, comments: Vec<Comment>) -> Payload {
+    let mut comments_links: Vec<Link> = Vec::new();
+    comments_links.push(links.link("self", &paths::comments()));
+    comments_links.push(links.templated("search", "/comments{?userId,eventId}"));
+    Payload {
+        data: json!(extend_comments(links, &comments)),
+        links: Some(comments_links),
+        templates: None,
+        embedded: None,
+    }
+}
+
+fn extend_comment(links: &LinkBuilder, mut comment: Comment) -> Comment {
     match comment.id {
         Some(ref id) => {
-            let mut links: Vec<Link> = Vec::new();
-            links.push(Link { key: "event".to_string(), href: format!("/events/{}", &comment.event_id) });
-            links.push(Link { key: "self".to_string(), href: format!("/events/{}/comments/{}", &comment.event_id, &id) });
-            comment._links = Some(links);
+            let mut comment_links: Vec<Link> = Vec::new();
+            comment_links.push(links.link("event", &paths::event(&comment.event_id)));
+            comment_links.push(links.link("self", &paths::comment(&comment.event_id, &id)));
+            comment._links = Some(comment_links);
 
             let mut templates: Vec<Template> = Vec::new();
             templates.push(Template {
                 key: "delete".to_string(),
                 title: None,
                 method: MethodType::DELETE,
+                content_type: None,
                 properties: None,
-                target: Some("self".to_string()),
+                target: Some(links.comment(&comment.event_id, &id)),
             });
             templates.push(Template {
                 key: "update".to_string(),
                 title: None,
                 method: MethodType::PATCH,
-                properties: Some(get_comment_properties(&comment.event_id)),
-                target: Some("self".to_string()),
+                content_type: Some("application/json".to_string()),
+                properties: Some(get_comment_properties(links, &comment.event_id)),
+                target: Some(links.comment(&comment.event_id, &id)),
             });
             comment._templates = Some(templates);
 
@@ -181,41 +426,81 @@ fn extend_comment(mut comment: Comment) -> Comment {
     }
 }
 
-fn get_comment_properties(event_id: &str) -> Vec<Property> {
-    let mut properties: Vec<Property> = Vec::new();
-    properties.push(Property {
-        name: "eventId".to_string(), prompt: None, read_only: true, required: true, templated: None, value: Some(event_id.to_string())
-    });
-    properties.push(Property {
-        name: "userId".to_string(), prompt: None, read_only: false, required: true, templated: None, value: None
-    });
-    properties.push(Property {
-        name: "comment".to_string(), prompt: None, read_only: false, required: true, templated: None, value: None
-    });
-    properties.push(Property {
-        name: "timestamp".to_string(), prompt: None, read_only: false, required: false, templated: None, value: None
-    });
-    properties
+fn get_comment_properties(links: &LinkBuilder, event_id: &str) -> Vec<Property> {
+    schema::to_properties(links, &schema::COMMENT_FIELDS)
+        .into_iter()
+        .map(|mut property| {
+            if property.name == "eventId" {
+                property.value = Some(event_id.to_string());
+            }
+            property
+        })
+        .collect()
 }
 
-fn comment_links(event_id: &str) -> Vec<Link> {
-    let mut links: Vec<Link> = Vec::new();
-    links.push(Link {
-        key: "self".to_string(),
-        href: format!("/events/{}/comments", &event_id),
-    });
-    links
+fn comment_links(links: &LinkBuilder, event_id: &str) -> Vec<Link> {
+    let mut comment_links: Vec<Link> = Vec::new();
+    comment_links.push(links.link("self", &paths::event_comments(event_id)));
+    comment_links
 }
 
-fn comment_templates(event_id: &str) -> Vec<Template> {
+fn comment_templates(links: &LinkBuilder, event_id: &str) -> Vec<Template> {
     let mut templates: Vec<Template> = Vec::new();
     templates.push(Template {
         key: "default".to_string(),
         title: Some("Create comment".to_string()),
         method: MethodType::POST,
-        properties: Some(get_comment_properties(event_id)),
-        target: Some("self".to_string())
+        content_type: Some("application/json".to_string()),
+        properties: Some(get_comment_properties(links, event_id)),
+        target: Some(links.event_comments(&event_id))
     });
     templates
 }
 
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    fn event(value: Value) -> Event {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn resolved_events_may_only_be_reopened() {
+        use Status::*;
+        for (current, next, allowed) in vec![
+            (Open, Acknowledged, true),
+            (Open, Resolved, true),
+            (Acknowledged, Open, true),
+            (Acknowledged, Resolved, true),
+            (Resolved, Open, true),
+            (Resolved, Acknowledged, false),
+            (Resolved, Resolved, true),
+        ] {
+            assert_eq!(current.can_become(next), allowed, "{:?} to {:?}", current, next);
+        }
+    }
+
+    #[test]
+    fn resolving_ends_open_events() {
+        let mut open = event(json!({ "from": 10, "text": "a" }));
+        assert_eq!(open.status(), Status::Open);
+        set_status(&mut open, Status::Resolved, 5).unwrap();
+        assert_eq!(open.status, Some(Status::Resolved));
+        assert_eq!(open.to, Some(10));
+
+        let mut ended = event(json!({ "from": 10, "to": 15, "text": "a", "status": "resolved" }));
+        assert!(set_status(&mut ended, Status::Acknowledged, 20).is_err());
+        assert_eq!(ended.status, Some(Status::Resolved));
+        set_status(&mut ended, Status::Open, 20).unwrap();
+        assert_eq!(ended.to, Some(15));
+    }
+
+    #[test]
+    fn events_without_a_status_are_written_open() {
+        let value = serde_json::to_value(event(json!({ "from": 10, "text": "a" }))).unwrap();
+        assert_eq!(value["status"], json!("open"));
+    }
+}
@@ -0,0 +1,288 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+
+use lazy_static::lazy_static;
+use regex::Regex;
+use serde_json::Value;
+
+use crate::envelope::{Property, PropertyOptions};
+use crate::links::LinkBuilder;
+
+pub enum FieldType {
+    Number,
+    /// A point in time. Bodies carry milliseconds since the epoch; forms
+    /// show a date and time picker.
+    DateTimeLocal,
+    Text,
+    Textarea,
+}
+
+impl FieldType {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            FieldType::Number => "number",
+            FieldType::DateTimeLocal => "datetime-local",
+            FieldType::Text => "text",
+            FieldType::Textarea => "textarea",
+        }
+    }
+}
+
+pub enum FieldOptions {
+    /// A fixed set of allowed values.
+    Inline(&'static [&'static str]),
+    /// Suggested values fetched from a resource. Not enforced on validation.
+    Link(&'static str),
+    /// Like `Link`, for a resource listing objects, with the value in the
+    /// named field.
+    LinkField(&'static str, &'static str),
+}
+
+/// Declarative description of one field in a request body. It is used both
+/// to generate HAL-FORMS properties and to validate POST and PATCH bodies.
+pub struct Field {
+    pub name: &'static str,
+    pub field_type: FieldType,
+    pub required: bool,
+    pub read_only: bool,
+    pub min: Option<i64>,
+    pub max: Option<i64>,
+    pub regex: Option<&'static str>,
+    pub max_length: Option<usize>,
+    /// Set for fields holding a list of values, at most this many. The other
+    /// constraints apply to each value.
+    pub max_items: Option<usize>,
+    pub options: Option<FieldOptions>,
+}
+
+impl Field {
+    const fn new(name: &'static str, field_type: FieldType) -> Field {
+        Field {
+            name,
+            field_type,
+            required: false,
+            read_only: false,
+            min: None,
+            max: None,
+            regex: None,
+            max_length: None,
+            max_items: None,
+            options: None,
+        }
+    }
+}
+
+pub static EVENT_FIELDS: [Field; 10] = [
+    Field { required: true, min: Some(0), ..Field::new("from", FieldType::DateTimeLocal) },
+    Field { min: Some(0), ..Field::new("to", FieldType::DateTimeLocal) },
+    Field { required: true, max_length: Some(10000), ..Field::new("text", FieldType::Textarea) },
+    Field {
+        regex: Some("^[A-Za-z0-9_.-]+$"),
+        max_length: Some(100),
+        options: Some(FieldOptions::Link("/events/appNames")),
+        ..Field::new("appName", FieldType::Text)
+    },
+    Field { max_length: Some(200), ..Field::new("sourceId", FieldType::Text) },
+    Field { max_length: Some(200), ..Field::new("sourceName", FieldType::Text) },
+    Field {
+        regex: Some("^[A-Za-z0-9_.:-]+$"),
+        max_length: Some(50),
+        max_items: Some(20),
+        options: Some(FieldOptions::LinkField("/tags", "tag")),
+        ..Field::new("tags", FieldType::Text)
+    },
+    Field {
+        options: Some(FieldOptions::Inline(&["info", "warning", "critical"])),
+        ..Field::new("severity", FieldType::Text)
+    },
+    Field {
+        options: Some(FieldOptions::Inline(&["open", "acknowledged", "resolved"])),
+        ..Field::new("status", FieldType::Text)
+    },
+    Field { read_only: true, ..Field::new("createdBy", FieldType::Text) },
+];
+
+pub static COMMENT_FIELDS: [Field; 4] = [
+    Field { required: true, read_only: true, ..Field::new("eventId", FieldType::Text) },
+    Field { required: true, max_length: Some(100), ..Field::new("userId", FieldType::Text) },
+    Field { required: true, max_length: Some(10000), ..Field::new("comment", FieldType::Textarea) },
+    Field { min: Some(0), ..Field::new("timestamp", FieldType::Number) },
+];
+
+lazy_static! {
+    /// Field regexes, compiled on first use.
+    static ref REGEXES: Mutex<HashMap<&'static str, Regex>> = Mutex::new(HashMap::new());
+}
+
+fn is_match(pattern: &'static str, text: &str) -> bool {
+    let mut regexes = REGEXES.lock().expect("Field regex cache poisoned.");
+    regexes.entry(pattern)
+        .or_insert_with(|| Regex::new(pattern).expect("Invalid field regex."))
+        .is_match(text)
+}
+
+pub fn to_properties(links: &LinkBuilder, fields: &[Field]) -> Vec<Property> {
+    fields.iter().map(|field| to_property(links, field)).collect()
+}
+
+pub fn to_property(links: &LinkBuilder, field: &Field) -> Property {
+    Property {
+        name: field.name.to_string(),
+        prompt: None,
+        read_only: field.read_only,
+        required: field.required,
+        templated: None,
+        value: None,
+        property_type: Some(field.field_type.as_str().to_string()),
+        min: field.min,
+        max: field.max,
+        regex: field.regex.map(|regex| regex.to_string()),
+        max_length: field.max_length,
+        options: field.options.as_ref().map(|options| match options {
+            FieldOptions::Inline(values) => PropertyOptions {
+                inline: Some(values.iter().map(|v| v.to_string()).collect()),
+                link: None,
+                value_field: None,
+                max_items: Some(field.max_items.unwrap_or(1)),
+            },
+            FieldOptions::Link(href) => PropertyOptions {
+                inline: None,
+                link: Some(links.link("options", href)),
+                value_field: None,
+                max_items: Some(field.max_items.unwrap_or(1)),
+            },
+            FieldOptions::LinkField(href, value_field) => PropertyOptions {
+                inline: None,
+                link: Some(links.link("options", href)),
+                value_field: Some(value_field.to_string()),
+                max_items: Some(field.max_items.unwrap_or(1)),
+            },
+        }),
+    }
+}
+
+/// Checks a serialized body against its field descriptions and returns all
+/// violations, so a client can fix them in one go.
+pub fn validate(fields: &[Field], body: &Value) -> Result<(), Vec<String>> {
+    let mut errors: Vec<String> = Vec::new();
+    for field in fields.iter() {
+        let value = body.get(field.name).unwrap_or(&Value::Null);
+        if let Err(error) = validate_field(field, value) {
+            errors.push(error);
+        }
+    }
+    if errors.is_empty() { Ok(()) } else { Err(errors) }
+}
+
+fn validate_field(field: &Field, value: &Value) -> Result<(), String> {
+    match value {
+        Value::Array(values) => {
+            let max_items = field.max_items.ok_or(format!("{} must be a single value", field.name))?;
+            if values.len() > max_items {
+                return Err(format!("{} must have at most {} values", field.name, max_items));
+            }
+            for value in values.iter() {
+                if value.is_null() || value.is_array() {
+                    return Err(format!("{} must be a list of values", field.name));
+                }
+                validate_field(field, value)?;
+            }
+        },
+        Value::Null => {
+            if field.required {
+                return Err(format!("{} is required", field.name));
+            }
+        },
+        Value::Number(number) => {
+            let number = number.as_i64().ok_or(format!("{} must be an integer", field.name))?;
+            if field.min.map_or(false, |min| number < min) {
+                return Err(format!("{} must be at least {}", field.name, field.min.unwrap()));
+            }
+            if field.max.map_or(false, |max| number > max) {
+                return Err(format!("{} must be at most {}", field.name, field.max.unwrap()));
+            }
+        },
+        Value::String(text) => {
+            if field.required && text.trim().is_empty() {
+                return Err(format!("{} is required", field.name));
+            }
+            if field.max_length.map_or(false, |max_length| text.chars().count() > max_length) {
+                return Err(format!("{} must be at most {} characters", field.name, field.max_length.unwrap()));
+            }
+            if let Some(pattern) = field.regex {
+                if !is_match(pattern, text) {
+                    return Err(format!("{} must match {}", field.name, pattern));
+                }
+            }
+            if let Some(FieldOptions::Inline(values)) = &field.options {
+                if !values.contains(&text.as_str()) {
+                    return Err(format!("{} must be one of {}", field.name, values.join(", ")));
+                }
+            }
+        },
+        _ => {},
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+
+    #[test]
+    fn valid_event_passes() {
+        let body = json!({ "from": 1, "text": "Deploy", "appName": "api", "tags": ["a", "b:c"], "severity": "info" });
+        assert_eq!(validate(&EVENT_FIELDS, &body), Ok(()));
+    }
+
+    #[test]
+    fn all_violations_are_reported() {
+        let body = json!({ "from": -1, "text": " ", "appName": "no spaces", "severity": "fatal" });
+        let errors = validate(&EVENT_FIELDS, &body).unwrap_err();
+        assert_eq!(errors, vec![
+            "from must be at least 0".to_string(),
+            "text is required".to_string(),
+            "appName must match ^[A-Za-z0-9_.-]+$".to_string(),
+            "severity must be one of info, warning, critical".to_string(),
+        ]);
+    }
+
+    #[test]
+    fn missing_required_fields_are_reported() {
+        let errors = validate(&EVENT_FIELDS, &json!({})).unwrap_err();
+        assert_eq!(errors, vec!["from is required".to_string(), "text is required".to_string()]);
+    }
+
+    #[test]
+    fn lists_are_checked_per_value() {
+        let too_many: Vec<String> = (0..21).map(|i| format!("t{}", i)).collect();
+        let errors = validate(&EVENT_FIELDS, &json!({ "from": 1, "text": "x", "tags": too_many })).unwrap_err();
+        assert_eq!(errors, vec!["tags must have at most 20 values".to_string()]);
+        let errors = validate(&EVENT_FIELDS, &json!({ "from": 1, "text": "x", "tags": ["ok", "not ok"] })).unwrap_err();
+        assert_eq!(errors, vec!["tags must match ^[A-Za-z0-9_.:-]+$".to_string()]);
+    }
+
+    #[test]
+    fn single_value_fields_reject_lists() {
+        let errors = validate(&EVENT_FIELDS, &json!({ "from": 1, "text": ["x"] })).unwrap_err();
+        assert_eq!(errors, vec!["text must be a single value".to_string()]);
+    }
+
+    #[test]
+    fn text_length_counts_characters() {
+        let text: String = std::iter::repeat('ø').take(10000).collect();
+        assert_eq!(validate(&EVENT_FIELDS, &json!({ "from": 1, "text": text })), Ok(()));
+        let text: String = std::iter::repeat('ø').take(10001).collect();
+        assert!(validate(&EVENT_FIELDS, &json!({ "from": 1, "text": text })).is_err());
+    }
+
+    #[test]
+    fn times_are_offered_as_date_pickers() {
+        let properties = to_properties(&LinkBuilder::default(), &EVENT_FIELDS);
+        let from = properties.iter().find(|p| p.name == "from").unwrap();
+        assert_eq!(from.property_type.as_deref(), Some("datetime-local"));
+        assert_eq!(validate(&EVENT_FIELDS, &json!({ "from": -1, "text": "a" })), Err(vec!["from must be at least 0".to_string()]));
+    }
+}
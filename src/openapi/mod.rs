@@ -0,0 +1,513 @@
+use rocket::Route;
+use rocket::http::Method;
+use serde_json::{Map, Value, json};
+
+use crate::model::metadata;
+use crate::model::schema::{self, Field, FieldOptions, FieldType};
+
+/// Envelope error codes returned by the routes in `bin.rs`.
+pub static ERROR_CODES: [(i32, &str); 19] = [
+    (1, "Events could not be read"),
+    (2, "Event could not be created or updated"),
+    (3, "Event not found"),
+    (4, "Event or comment could not be deleted, or comments could not be read"),
+    (5, "Comment could not be created or updated"),
+    (6, "Batch could not be run"),
+    (7, "Export failed or format is unknown"),
+    (8, "Import failed or format is unknown"),
+    (9, "Comment not found or does not belong to the event"),
+    (10, "Request body failed validation"),
+    (11, "Missing or invalid API key or token (status 401)"),
+    (12, "The API key or token lacks the required scope (status 403)"),
+    (13, "API key could not be listed, created or deleted"),
+    (14, "Only the author of a comment or an admin may change it (status 403)"),
+    (15, "The event's appName does not belong to the caller's tenant"),
+    (16, "Too many requests, see Retry-After (status 429)"),
+    (17, "Request body or text is too large (status 413)"),
+    (18, "Webhook could not be listed, created or deleted"),
+    (19, "The event's status cannot change to the one requested"),
+];
+
+/// Routes that can be called without an API key.
+static PUBLIC_ROUTES: [&str; 2] = ["get_openapi", "get_docs"];
+
+/// Routes taking `Limited` bodies, which are checked against the size and
+/// text limits and answered with 422 when they cannot be parsed.
+static LIMITED_ROUTES: [&str; 4] = ["create_event", "update_event", "create_comment", "update_comment"];
+
+/// Routes taking raw bodies, which are only checked against a size limit.
+static SIZE_LIMITED_ROUTES: [&str; 1] = ["import_events"];
+
+/// POST routes that act on a resource and take no body.
+static ACTION_ROUTES: [&str; 2] = ["acknowledge_event", "resolve_event"];
+
+/// Builds an OpenAPI 3 document from the mounted routes. Paths, methods and
+/// parameters come from the routes themselves, and the `Event` and `Comment`
+/// schemas from the field descriptions in `model::schema`, so the document
+/// follows the code without being edited by hand.
+pub fn generate<'a>(routes: impl Iterator<Item = &'a Route>) -> Value {
+    let mut paths = Map::new();
+    for route in routes {
+        let path = to_openapi_path(route.uri.path());
+        let method = route.method.as_str().to_lowercase();
+        let operation = to_operation(route);
+        let item = paths.entry(path).or_insert_with(|| Value::Object(Map::new()));
+        if let Value::Object(item) = item {
+            item.insert(method, operation);
+        }
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "event-api",
+            "version": env!("CARGO_PKG_VERSION"),
+            "description": error_code_description(),
+        },
+        "paths": paths,
+        "security": [{ "apiKey": [] }],
+        "components": {
+            "securitySchemes": {
+                "apiKey": {
+                    "type": "http",
+                    "scheme": "bearer",
+                    "description": "An API key or a JWT, sent as `Authorization: Bearer <token>`.",
+                },
+            },
+            "schemas": {
+                "Event": model_schema(&schema::EVENT_FIELDS, json!({
+                    "id": { "type": "string", "readOnly": true },
+                    "metadata": {
+                        "type": "object",
+                        "additionalProperties": true,
+                        "maxProperties": metadata::MAX_KEYS,
+                        "propertyNames": { "pattern": metadata::KEY_REGEX },
+                        "description": format!("Free-form details, at most {} bytes as JSON.", metadata::MAX_BYTES),
+                    },
+                    "_links": { "$ref": "#/components/schemas/Links" },
+                    "_templates": { "$ref": "#/components/schemas/Templates" },
+                    "_embedded": { "type": "object", "additionalProperties": true },
+                })),
+                "Comment": model_schema(&schema::COMMENT_FIELDS, json!({
+                    "id": { "type": "string", "readOnly": true },
+                    "_links": { "$ref": "#/components/schemas/Links" },
+                    "_templates": { "$ref": "#/components/schemas/Templates" },
+                })),
+                "BatchOperation": {
+                    "type": "object",
+                    "required": ["op"],
+                    "properties": {
+                        "op": { "type": "string", "enum": ["create", "update", "delete"] },
+                        "event": { "$ref": "#/components/schemas/Event" },
+                        "id": { "type": "string" },
+                    },
+                },
+                "NewApiKey": {
+                    "type": "object",
+                    "required": ["name", "scopes"],
+                    "properties": {
+                        "name": { "type": "string" },
+                        "tenant": { "type": "string", "nullable": true },
+                        "scopes": {
+                            "type": "array",
+                            "items": { "type": "string", "enum": ["events:read", "events:write", "comments:write", "admin"] },
+                        },
+                    },
+                },
+                "Annotation": {
+                    "type": "object",
+                    "properties": {
+                        "id": { "type": "string", "readOnly": true },
+                        "time": { "type": "integer", "format": "int64" },
+                        "timeEnd": { "type": "integer", "format": "int64" },
+                        "isRegion": { "type": "boolean", "readOnly": true },
+                        "text": { "type": "string" },
+                        "tags": { "type": "array", "items": { "type": "string" } },
+                    },
+                },
+                "Alert": {
+                    "type": "object",
+                    "required": ["status", "startsAt", "fingerprint"],
+                    "properties": {
+                        "status": { "type": "string", "enum": ["firing", "resolved"] },
+                        "labels": { "type": "object", "additionalProperties": { "type": "string" } },
+                        "annotations": { "type": "object", "additionalProperties": { "type": "string" } },
+                        "startsAt": { "type": "string", "format": "date-time" },
+                        "endsAt": { "type": "string", "format": "date-time" },
+                        "fingerprint": { "type": "string" },
+                    },
+                },
+                "NewWebhook": {
+                    "type": "object",
+                    "required": ["url", "operations"],
+                    "properties": {
+                        "url": { "type": "string", "format": "uri" },
+                        "secret": { "type": "string", "nullable": true },
+                        "appName": { "type": "string", "nullable": true },
+                        "sourceId": { "type": "string", "nullable": true },
+                        "operations": {
+                            "type": "array",
+                            "items": {
+                                "type": "string",
+                                "enum": [
+                                    "event.created", "event.updated", "event.deleted",
+                                    "comment.created", "comment.updated", "comment.deleted",
+                                ],
+                            },
+                        },
+                    },
+                },
+                "Envelope": {
+                    "type": "object",
+                    "required": ["status"],
+                    "properties": {
+                        "status": { "type": "string", "enum": ["OK", "Error"] },
+                        "data": {},
+                        "error": { "$ref": "#/components/schemas/Error" },
+                        "pageNumber": { "type": "integer", "nullable": true },
+                        "nextPage": { "type": "string", "nullable": true },
+                        "totalPages": { "type": "integer", "nullable": true },
+                        "_links": { "$ref": "#/components/schemas/Links" },
+                        "_templates": { "$ref": "#/components/schemas/Templates" },
+                        "_embedded": { "type": "object", "additionalProperties": true },
+                    },
+                },
+                "Error": {
+                    "type": "object",
+                    "properties": {
+                        "code": { "type": "integer", "enum": ERROR_CODES.iter().map(|(code, _)| *code).collect::<Vec<i32>>() },
+                        "description": { "type": "string" },
+                    },
+                },
+                "Links": {
+                    "type": "object",
+                    "additionalProperties": { "$ref": "#/components/schemas/Link" },
+                },
+                "Link": {
+                    "type": "object",
+                    "required": ["href"],
+                    "properties": {
+                        "href": { "type": "string" },
+                        "templated": { "type": "boolean" },
+                    },
+                },
+                "Templates": {
+                    "type": "object",
+                    "additionalProperties": { "$ref": "#/components/schemas/Template" },
+                },
+                "Template": {
+                    "type": "object",
+                    "required": ["method"],
+                    "properties": {
+                        "method": { "type": "string", "enum": ["GET", "POST", "PATCH", "DELETE"] },
+                        "contentType": { "type": "string" },
+                        "properties": { "type": "array", "items": { "$ref": "#/components/schemas/Property" } },
+                        "target": { "type": "string" },
+                        "title": { "type": "string" },
+                    },
+                },
+                "Property": {
+                    "type": "object",
+                    "required": ["name", "readOnly", "required"],
+                    "properties": {
+                        "name": { "type": "string" },
+                        "prompt": { "type": "string" },
+                        "readOnly": { "type": "boolean" },
+                        "required": { "type": "boolean" },
+                        "templated": { "type": "boolean" },
+                        "value": { "type": "string" },
+                        "type": { "type": "string" },
+                        "min": { "type": "integer" },
+                        "max": { "type": "integer" },
+                        "regex": { "type": "string" },
+                        "maxLength": { "type": "integer" },
+                        "options": { "type": "object" },
+                    },
+                },
+            },
+        },
+    })
+}
+
+/// Names of the mounted routes that take a body the document does not
+/// describe.
+pub fn undescribed_bodies<'a>(routes: impl Iterator<Item = &'a Route>) -> Vec<&'static str> {
+    routes
+        .filter(|route| takes_body(route))
+        .filter_map(|route| route.name)
+        .filter(|name| request_body(name).is_none())
+        .collect()
+}
+
+fn takes_body(route: &Route) -> bool {
+    (route.method == Method::Post || route.method == Method::Patch) && !ACTION_ROUTES.contains(&route.name.unwrap_or(""))
+}
+
+fn to_operation(route: &Route) -> Value {
+    let name = route.name.unwrap_or("");
+    let mut operation = Map::new();
+    operation.insert("operationId".to_string(), json!(name));
+    operation.insert("tags".to_string(), json!([tag(route.uri.path())]));
+
+    let mut parameters: Vec<Value> = Vec::new();
+    for segment in route.uri.path().split('/') {
+        if let Some(param) = dynamic_name(segment) {
+            parameters.push(json!({
+                "name": param,
+                "in": "path",
+                "required": true,
+                "schema": { "type": "string" },
+            }));
+        }
+    }
+    if let Some(query) = route.uri.query() {
+        for segment in query.split('&') {
+            if segment == "<meta..>" {
+                parameters.push(json!({
+                    "name": "meta",
+                    "in": "query",
+                    "required": false,
+                    "description": "Metadata conditions: `meta.<key>=<value>` for a value, or `meta.<key>` for a key being present.",
+                    "style": "form",
+                    "explode": true,
+                    "schema": { "type": "object", "additionalProperties": { "type": "string" } },
+                }));
+            } else if let Some(param) = dynamic_name(segment) {
+                parameters.push(json!({
+                    "name": param,
+                    "in": "query",
+                    "required": false,
+                    "schema": query_schema(param),
+                }));
+            }
+        }
+    }
+    operation.insert("parameters".to_string(), json!(parameters));
+
+    if takes_body(route) {
+        if let Some(body) = request_body(name) {
+            operation.insert("requestBody".to_string(), body);
+        }
+    }
+    if PUBLIC_ROUTES.contains(&name) {
+        operation.insert("security".to_string(), json!([]));
+        operation.insert("responses".to_string(), json!({ "200": response(name) }));
+    } else {
+        let error = json!({
+            "application/json": { "schema": { "$ref": "#/components/schemas/Envelope" } },
+        });
+        operation.insert("responses".to_string(), json!({
+            "200": response(name),
+            "401": { "description": "Missing or invalid API key or token.", "content": error.clone() },
+            "403": { "description": "The API key or token lacks the required scope, or the caller may not change the comment.", "content": error.clone() },
+            "429": { "description": "Too many requests, retry after the seconds in `Retry-After`.", "content": error.clone() },
+        }));
+        if let Some(responses) = operation.get_mut("responses").and_then(|r| r.as_object_mut()) {
+            if LIMITED_ROUTES.contains(&name) || SIZE_LIMITED_ROUTES.contains(&name) {
+                responses.insert("413".to_string(), json!({ "description": "The body or its text is too large.", "content": error.clone() }));
+            }
+            if LIMITED_ROUTES.contains(&name) {
+                responses.insert("422".to_string(), json!({ "description": "The body is not valid JSON for the resource.", "content": error }));
+            }
+        }
+    }
+    Value::Object(operation)
+}
+
+/// `None` for routes whose body has not been described.
+fn request_body(name: &str) -> Option<Value> {
+    let content = match name {
+        "create_event" | "update_event" => json!({
+            "application/json": { "schema": { "$ref": "#/components/schemas/Event" } },
+        }),
+        "create_comment" | "update_comment" => json!({
+            "application/json": { "schema": { "$ref": "#/components/schemas/Comment" } },
+        }),
+        "batch_events" => json!({
+            "application/json": {
+                "schema": { "type": "array", "items": { "$ref": "#/components/schemas/BatchOperation" } },
+            },
+        }),
+        "create_api_key" => json!({
+            "application/json": { "schema": { "$ref": "#/components/schemas/NewApiKey" } },
+        }),
+        "grafana_annotations" => json!({
+            "application/json": {
+                "schema": {
+                    "type": "object",
+                    "required": ["range"],
+                    "properties": {
+                        "range": {
+                            "type": "object",
+                            "properties": { "from": { "type": "string" }, "to": { "type": "string" } },
+                        },
+                        "annotation": { "type": "object", "properties": { "query": { "type": "string" } } },
+                    },
+                },
+            },
+        }),
+        "create_grafana_annotation" | "update_grafana_annotation" => json!({
+            "application/json": { "schema": { "$ref": "#/components/schemas/Annotation" } },
+        }),
+        "ingest_alertmanager" => json!({
+            "application/json": {
+                "schema": {
+                    "type": "object",
+                    "required": ["alerts"],
+                    "properties": {
+                        "alerts": { "type": "array", "items": { "$ref": "#/components/schemas/Alert" } },
+                    },
+                },
+            },
+        }),
+        "create_webhook" => json!({
+            "application/json": { "schema": { "$ref": "#/components/schemas/NewWebhook" } },
+        }),
+        "import_events" => json!({
+            "application/x-ndjson": { "schema": { "type": "string" } },
+            "text/csv": { "schema": { "type": "string" } },
+            "text/calendar": { "schema": { "type": "string" } },
+        }),
+        _ => return None,
+    };
+    Some(json!({ "required": true, "content": content }))
+}
+
+fn response(name: &str) -> Value {
+    let content = match name {
+        "export_events" => json!({
+            "application/x-ndjson": { "schema": { "type": "string" } },
+            "text/csv": { "schema": { "type": "string" } },
+            "text/calendar": { "schema": { "type": "string" } },
+        }),
+        "get_events_feed" | "get_comments_feed" => json!({
+            "application/atom+xml": { "schema": { "type": "string" } },
+        }),
+        "get_events_calendar" => json!({
+            "text/calendar": { "schema": { "type": "string" } },
+        }),
+        "grafana_annotations" | "get_grafana_annotations" => json!({
+            "application/json": { "schema": { "type": "array", "items": { "$ref": "#/components/schemas/Annotation" } } },
+        }),
+        "grafana_health" | "create_grafana_annotation" | "update_grafana_annotation" | "delete_grafana_annotation" => json!({
+            "application/json": { "schema": { "type": "object" } },
+        }),
+        "get_openapi" => json!({ "application/json": { "schema": { "type": "object" } } }),
+        "get_docs" => json!({ "text/html": { "schema": { "type": "string" } } }),
+        _ => json!({
+            "application/json": { "schema": { "$ref": "#/components/schemas/Envelope" } },
+            "application/hal+json": { "schema": { "type": "object" } },
+            "application/prs.hal-forms+json": { "schema": { "type": "object" } },
+        }),
+    };
+    json!({
+        "description": "Successful responses and envelope errors other than authentication failures are returned with status 200.",
+        "content": content,
+    })
+}
+
+fn model_schema(fields: &[Field], extra: Value) -> Value {
+    let mut properties = Map::new();
+    let mut required: Vec<&str> = Vec::new();
+    for field in fields.iter() {
+        if field.required {
+            required.push(field.name);
+        }
+        properties.insert(field.name.to_string(), field_schema(field));
+    }
+    if let Value::Object(extra) = extra {
+        for (key, value) in extra.into_iter() {
+            properties.insert(key, value);
+        }
+    }
+    json!({ "type": "object", "required": required, "properties": properties })
+}
+
+fn field_schema(field: &Field) -> Value {
+    let mut schema = Map::new();
+    match field.field_type {
+        FieldType::Number | FieldType::DateTimeLocal => {
+            schema.insert("type".to_string(), json!("integer"));
+            schema.insert("format".to_string(), json!("int64"));
+        },
+        FieldType::Text | FieldType::Textarea => {
+            schema.insert("type".to_string(), json!("string"));
+        },
+    }
+    if !field.required {
+        schema.insert("nullable".to_string(), json!(true));
+    }
+    if field.read_only {
+        schema.insert("readOnly".to_string(), json!(true));
+    }
+    if let Some(min) = field.min {
+        schema.insert("minimum".to_string(), json!(min));
+    }
+    if let Some(max) = field.max {
+        schema.insert("maximum".to_string(), json!(max));
+    }
+    if let Some(regex) = field.regex {
+        schema.insert("pattern".to_string(), json!(regex));
+    }
+    if let Some(max_length) = field.max_length {
+        schema.insert("maxLength".to_string(), json!(max_length));
+    }
+    if let Some(FieldOptions::Inline(values)) = &field.options {
+        schema.insert("enum".to_string(), json!(values));
+    }
+    match field.max_items {
+        Some(max_items) => {
+            schema.remove("nullable");
+            json!({ "type": "array", "items": Value::Object(schema), "maxItems": max_items })
+        },
+        None => Value::Object(schema),
+    }
+}
+
+fn query_schema(name: &str) -> Value {
+    match name {
+        "from" | "to" => json!({ "type": "integer", "format": "int64" }),
+        "limit" => json!({ "type": "integer", "minimum": 0 }),
+        "page" => json!({ "type": "integer", "minimum": 1, "description": "Page to return, counting from 1." }),
+        "pageSize" => json!({ "type": "integer", "minimum": 1, "maximum": 1000, "description": "Events per page; defaults to 100 when only `page` is given." }),
+        "allOrNothing" | "comments" | "preserveIds" | "dryRun" | "matchAny" => json!({ "type": "boolean" }),
+        "tagMatch" => json!({ "type": "string", "enum": ["all", "any"] }),
+        "severity" => json!({ "type": "string", "description": "Comma-separated: info, warning, critical." }),
+        "status" => json!({ "type": "string", "description": "Comma-separated: open, acknowledged, resolved." }),
+        _ => json!({ "type": "string" }),
+    }
+}
+
+fn error_code_description() -> String {
+    let mut description = "Errors are returned as envelopes with status `Error` and one of these codes:\n\n".to_string();
+    for (code, text) in ERROR_CODES.iter() {
+        description.push_str(&format!("* `{}`: {}\n", code, text));
+    }
+    description
+}
+
+fn to_openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match dynamic_name(segment) {
+            Some(name) => format!("{{{}}}", name),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<String>>()
+        .join("/")
+}
+
+fn tag(path: &str) -> String {
+    path.split('/')
+        .find(|segment| !segment.is_empty())
+        .map(|segment| segment.trim_end_matches(".ics").to_string())
+        .unwrap_or("root".to_string())
+}
+
+/// Returns the parameter name of a `<name>` or `<name..>` route segment.
+fn dynamic_name(segment: &str) -> Option<&str> {
+    if segment.starts_with('<') && segment.ends_with('>') {
+        Some(segment[1..segment.len() - 1].trim_end_matches(".."))
+    } else {
+        None
+    }
+}
@@ -0,0 +1,486 @@
+use std::collections::HashMap;
+use std::error::Error;
+use std::io::{self, Read};
+use std::iter;
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+
+use crate::db::{self, EventDb};
+use crate::ical;
+use crate::links::LinkBuilder;
+use crate::model::{self, Event, Comment, Severity, Status};
+
+static EVENT_COLUMNS: [&str; 11] = [
+    "id", "from", "to", "text", "appName", "sourceId", "sourceName", "tags", "metadata", "severity", "status",
+];
+static COMMENT_COLUMNS: [&str; 5] = ["id", "eventId", "userId", "comment", "timestamp"];
+/// Columns added later, which older exports lack.
+static OPTIONAL_COLUMNS: [&str; 4] = ["tags", "metadata", "severity", "status"];
+/// Separates tags within the `tags` column.
+static TAG_SEPARATOR: char = ';';
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Format {
+    Ndjson,
+    Csv,
+    Ical,
+}
+
+impl Format {
+    pub fn parse(format: &str) -> Option<Format> {
+        match format.to_lowercase().as_str() {
+            "ndjson" | "jsonl" => Some(Format::Ndjson),
+            "csv" => Some(Format::Csv),
+            "ics" | "ical" => Some(Format::Ical),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportError {
+    pub record: usize,
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ImportReport {
+    pub total: usize,
+    pub imported: usize,
+    #[serde(rename = "dryRun")]
+    pub dry_run: bool,
+    pub ids: Vec<String>,
+    pub errors: Vec<ImportError>,
+    /// Ids of imported records that replaced stored ones under
+    /// `preserveIds`. The rest were created.
+    #[serde(skip)]
+    pub replaced: Vec<String>,
+}
+
+/// Reads an export one record at a time, so that it can be streamed to the
+/// client instead of being built up as a single string.
+pub struct ExportReader {
+    records: Box<dyn Iterator<Item = io::Result<String>>>,
+    buffer: Vec<u8>,
+    position: usize,
+}
+
+impl ExportReader {
+    fn new<I: Iterator<Item = io::Result<String>> + 'static>(records: I) -> ExportReader {
+        ExportReader { records: Box::new(records), buffer: Vec::new(), position: 0 }
+    }
+}
+
+impl Read for ExportReader {
+    fn read(&mut self, out: &mut [u8]) -> io::Result<usize> {
+        while self.position == self.buffer.len() {
+            match self.records.next() {
+                Some(record) => {
+                    self.buffer = record?.into_bytes();
+                    self.position = 0;
+                },
+                None => return Ok(0),
+            }
+        }
+        let length = out.len().min(self.buffer.len() - self.position);
+        out[..length].copy_from_slice(&self.buffer[self.position..self.position + length]);
+        self.position += length;
+        Ok(length)
+    }
+}
+
+pub fn export_events(links: &LinkBuilder, events: Vec<Event>, format: Format) -> ExportReader {
+    match format {
+        Format::Ndjson => ExportReader::new(events.into_iter().map(|event| ndjson_line(&event))),
+        Format::Csv => {
+            let header = csv_line(&EVENT_COLUMNS);
+            let rows = events.into_iter().map(|event| {
+                let metadata = if event.metadata.is_empty() { String::new() } else { serde_json::to_string(&event.metadata)? };
+                csv_line(&[
+                    event.id.unwrap_or_default(),
+                    event.from.to_string(),
+                    event.to.map(|to| to.to_string()).unwrap_or_default(),
+                    event.text,
+                    event.app_name.unwrap_or_default(),
+                    event.source_id.unwrap_or_default(),
+                    event.source_name.unwrap_or_default(),
+                    event.tags.join(&TAG_SEPARATOR.to_string()),
+                    metadata,
+                    event.severity.as_str().to_string(),
+                    event.status().as_str().to_string(),
+                ])
+            });
+            ExportReader::new(iter::once(header).chain(rows))
+        },
+        Format::Ical => {
+            let links = links.clone();
+            let stamp = ical::stamp();
+            let vevents = events.into_iter().map(move |event| Ok(ical::write_vevent(&links, &event, &stamp)));
+            ExportReader::new(iter::once(Ok(ical::calendar_start())).chain(vevents).chain(iter::once(Ok(ical::calendar_end()))))
+        },
+    }
+}
+
+pub fn export_comments(comments: Vec<Comment>, format: Format) -> Result<ExportReader, Box<dyn Error>> {
+    match format {
+        Format::Ndjson => Ok(ExportReader::new(comments.into_iter().map(|comment| ndjson_line(&comment)))),
+        Format::Csv => {
+            let header = csv_line(&COMMENT_COLUMNS);
+            let rows = comments.into_iter().map(|comment| {
+                csv_line(&[
+                    comment.id.unwrap_or_default(),
+                    comment.event_id,
+                    comment.user_id,
+                    comment.comment,
+                    comment.timestamp.to_string(),
+                ])
+            });
+            Ok(ExportReader::new(iter::once(header).chain(rows)))
+        },
+        Format::Ical => Err("Comments cannot be written as iCalendar".into()),
+    }
+}
+
+pub fn write_events(links: &LinkBuilder, events: &[Event], format: Format) -> Result<String, Box<dyn Error>> {
+    let mut data = String::new();
+    export_events(links, events.to_vec(), format).read_to_string(&mut data)?;
+    Ok(data)
+}
+
+pub fn write_comments(comments: &[Comment], format: Format) -> Result<String, Box<dyn Error>> {
+    let mut data = String::new();
+    export_comments(comments.to_vec(), format)?.read_to_string(&mut data)?;
+    Ok(data)
+}
+
+/// Parses every record in `data`, keeping per-record failures so that
+/// an import can report them by position instead of failing as a whole.
+pub fn read_events(data: &str, format: Format) -> Vec<Result<Event, String>> {
+    match format {
+        Format::Ndjson => read_ndjson(data),
+        Format::Csv => read_csv(data, &EVENT_COLUMNS, |get| {
+            Ok(Event {
+                id: get("id"),
+                from: parse_number(get("from"), "from")?.ok_or("Missing value for from")?,
+                to: parse_number(get("to"), "to")?,
+                text: get("text").unwrap_or_default(),
+                app_name: get("appName"),
+                source_id: get("sourceId"),
+                source_name: get("sourceName"),
+                tags: get("tags")
+                    .map(|tags| tags.split(TAG_SEPARATOR).map(|t| t.trim().to_string()).filter(|t| !t.is_empty()).collect())
+                    .unwrap_or_default(),
+                metadata: get("metadata")
+                    .map(|metadata| serde_json::from_str(&metadata).map_err(|_| "metadata must be a JSON object".to_string()))
+                    .transpose()?
+                    .unwrap_or_default(),
+                severity: get("severity")
+                    .map(|severity| Severity::parse(&severity).ok_or(format!("Invalid severity {}", severity)))
+                    .transpose()?
+                    .unwrap_or_default(),
+                status: get("status")
+                    .map(|status| Status::parse(&status).ok_or(format!("Invalid status {}", status)))
+                    .transpose()?,
+                created_by: None,
+                _links: None,
+                _templates: None,
+                _embedded: None,
+            })
+        }),
+        Format::Ical => ical::read_calendar(data),
+    }
+}
+
+pub fn read_comments(data: &str, format: Format) -> Vec<Result<Comment, String>> {
+    match format {
+        Format::Ndjson => read_ndjson(data),
+        Format::Csv => read_csv(data, &COMMENT_COLUMNS, |get| {
+            Ok(Comment {
+                id: get("id"),
+                event_id: get("eventId").unwrap_or_default(),
+                user_id: get("userId").unwrap_or_default(),
+                comment: get("comment").unwrap_or_default(),
+                timestamp: parse_number(get("timestamp"), "timestamp")?.unwrap_or(0),
+                _links: None,
+                _templates: None,
+            })
+        }),
+        Format::Ical => vec![Err("Comments cannot be read from iCalendar".to_string())],
+    }
+}
+
+/// Imported events are credited to `author`, whatever the records say. With
+/// `preserve_ids`, events replacing stored ones keep their author and go
+/// through the same status transitions as updates.
+pub fn import_events(edb: &dyn EventDb, data: &str, format: Format, author: Option<String>, preserve_ids: bool, dry_run: bool) -> Result<ImportReport, Box<dyn Error>> {
+    let records = read_events(data, format);
+    let total = records.len();
+    let mut valid: Vec<Event> = Vec::new();
+    let mut errors: Vec<ImportError> = Vec::new();
+
+    let now = Utc::now().timestamp_millis();
+    for (record, result) in records.into_iter().enumerate() {
+        let result = result
+            .and_then(|event| model::validate_event(&event).map(|_| event))
+            .and_then(|mut event| if preserve_ids { db::check_status(edb, &mut event, now).map(|_| event) } else { Ok(event) });
+        match result {
+            Ok(mut event) => {
+                let existing = event.id.as_ref().filter(|_| preserve_ids).and_then(|id| edb.get_event(id.clone()).ok());
+                event.created_by = match existing {
+                    Some(existing) => existing.created_by,
+                    None => author.clone(),
+                };
+                valid.push(event);
+            },
+            Err(error) => errors.push(ImportError { record, error }),
+        }
+    }
+
+    let replaced: Vec<String> = valid.iter()
+        .filter_map(|e| e.id.clone())
+        .filter(|id| preserve_ids && edb.get_event(id.clone()).is_ok())
+        .collect();
+    let imported = if dry_run { valid } else { edb.import_events(valid, preserve_ids)? };
+    Ok(ImportReport {
+        total,
+        imported: imported.len(),
+        dry_run,
+        ids: imported.into_iter().filter_map(|e| e.id).collect(),
+        errors,
+        replaced,
+    })
+}
+
+/// Imported comments are credited to `author` when there is one, as when
+/// they are posted. Comments replacing stored ones keep their author.
+pub fn import_comments(edb: &dyn EventDb, data: &str, format: Format, author: Option<String>, preserve_ids: bool, dry_run: bool) -> Result<ImportReport, Box<dyn Error>> {
+    let records = read_comments(data, format);
+    let total = records.len();
+    let mut valid: Vec<Comment> = Vec::new();
+    let mut errors: Vec<ImportError> = Vec::new();
+
+    let mut known_events: HashMap<String, bool> = HashMap::new();
+    let mut event_exists = |event_id: &str| *known_events
+        .entry(event_id.to_string())
+        .or_insert_with(|| edb.get_event(event_id.to_string()).is_ok());
+
+    for (record, result) in records.into_iter().enumerate() {
+        let result = result
+            .map(|mut comment| {
+                let existing = comment.id.as_ref().filter(|_| preserve_ids).and_then(|id| edb.get_comment(id.clone()).ok());
+                match (existing, &author) {
+                    (Some(existing), _) => comment.user_id = existing.user_id,
+                    (None, Some(author)) => comment.user_id = author.clone(),
+                    (None, None) => {},
+                }
+                comment
+            })
+            .and_then(|comment| model::validate_comment(&comment).map(|_| comment))
+            .and_then(|comment| if event_exists(&comment.event_id) { Ok(comment) } else { Err("Event not found".to_string()) });
+        match result {
+            Ok(comment) => valid.push(comment),
+            Err(error) => errors.push(ImportError { record, error }),
+        }
+    }
+
+    let replaced: Vec<String> = valid.iter()
+        .filter_map(|c| c.id.clone())
+        .filter(|id| preserve_ids && edb.get_comment(id.clone()).is_ok())
+        .collect();
+    let imported = if dry_run { valid } else { edb.import_comments(valid, preserve_ids)? };
+    Ok(ImportReport {
+        total,
+        imported: imported.len(),
+        dry_run,
+        ids: imported.into_iter().filter_map(|c| c.id).collect(),
+        errors,
+        replaced,
+    })
+}
+
+fn ndjson_line<T: Serialize>(item: &T) -> io::Result<String> {
+    let mut line = serde_json::to_string(item)?;
+    line.push('\n');
+    Ok(line)
+}
+
+/// One CSV record, quoted as needed and terminated by a newline.
+fn csv_line<S: AsRef<[u8]>>(fields: &[S]) -> io::Result<String> {
+    let mut writer = csv::Writer::from_writer(vec![]);
+    writer.write_record(fields)?;
+    let data = writer.into_inner().map_err(|err| io::Error::new(io::ErrorKind::Other, err.to_string()))?;
+    String::from_utf8(data).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
+
+fn read_ndjson<T: for<'de> Deserialize<'de>>(data: &str) -> Vec<Result<T, String>> {
+    data.lines()
+        .filter(|line| !line.trim().is_empty())
+        .map(|line| serde_json::from_str(line).map_err(|err| err.to_string()))
+        .collect()
+}
+
+fn read_csv<T, F>(data: &str, columns: &[&str], build: F) -> Vec<Result<T, String>>
+where
+    F: Fn(&dyn Fn(&str) -> Option<String>) -> Result<T, String>,
+{
+    let mut reader = csv::Reader::from_reader(data.as_bytes());
+    let headers = match reader.headers() {
+        Ok(headers) => headers.clone(),
+        Err(err) => return vec![Err(err.to_string())],
+    };
+    if let Some(missing) = columns.iter().find(|c| !OPTIONAL_COLUMNS.contains(c) && !headers.iter().any(|h| h == **c)) {
+        return vec![Err(format!("Missing column {}", missing))];
+    }
+
+    reader.records()
+        .map(|record| {
+            let record = record.map_err(|err| err.to_string())?;
+            let get = |column: &str| {
+                headers.iter()
+                    .position(|h| h == column)
+                    .and_then(|i| record.get(i))
+                    .filter(|value| !value.is_empty())
+                    .map(|value| value.to_string())
+            };
+            build(&get)
+        })
+        .collect()
+}
+
+fn parse_number(value: Option<String>, column: &str) -> Result<Option<i64>, String> {
+    match value {
+        Some(value) => value.trim().parse::<i64>()
+            .map(Some)
+            .map_err(|_| format!("Invalid number for {}: {}", column, value)),
+        None => Ok(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use serde_json::json;
+
+    use super::*;
+    use crate::db::file_based::FileBasedEventDb;
+
+    fn event(value: serde_json::Value) -> Event {
+        serde_json::from_value(value).unwrap()
+    }
+
+    #[test]
+    fn csv_round_trips_events() {
+        let original = event(json!({
+            "id": "e1",
+            "from": 1000,
+            "to": 2000,
+            "text": "Deploy, \"quoted\"\nsecond line",
+            "appName": "api",
+            "tags": ["deploy", "prod"],
+            "metadata": { "build": 42 },
+            "severity": "warning",
+        }));
+        let data = write_events(&LinkBuilder::default(), &[original], Format::Csv).unwrap();
+        let events: Vec<Event> = read_events(&data, Format::Csv).into_iter().map(|e| e.unwrap()).collect();
+        assert_eq!(events.len(), 1);
+        assert_eq!(events[0].text, "Deploy, \"quoted\"\nsecond line");
+        assert_eq!(events[0].to, Some(2000));
+        assert_eq!(events[0].tags, vec!["deploy", "prod"]);
+        assert_eq!(events[0].metadata.get("build"), Some(&json!(42)));
+        assert_eq!(events[0].severity, Severity::Warning);
+        assert_eq!(events[0].status, Some(Status::Open));
+    }
+
+    #[test]
+    fn csv_without_optional_columns_is_read() {
+        let data = "id,from,to,text,appName,sourceId,sourceName\n,5,,hello,,,\n";
+        let events = read_events(data, Format::Csv);
+        assert_eq!(events[0].as_ref().unwrap().text, "hello");
+    }
+
+    #[test]
+    fn csv_without_required_column_fails() {
+        let errors = read_events("id,from\n,5\n", Format::Csv);
+        assert_eq!(errors[0].as_ref().err().map(|e| e.as_str()), Some("Missing column to"));
+    }
+
+    #[test]
+    fn ndjson_keeps_failures_per_record() {
+        let records = read_events("{\"from\":1,\"text\":\"a\"}\nnot json\n\n{\"from\":2,\"text\":\"b\"}\n", Format::Ndjson);
+        assert_eq!(records.len(), 3);
+        assert!(records[0].is_ok());
+        assert!(records[1].is_err());
+        assert!(records[2].is_ok());
+    }
+
+    #[test]
+    fn export_reads_in_small_chunks() {
+        let events = vec![event(json!({ "from": 1, "text": "a" })), event(json!({ "from": 2, "text": "b" }))];
+        let mut export = export_events(&LinkBuilder::default(), events, Format::Ndjson);
+        let mut data = Vec::new();
+        let mut chunk = [0u8; 3];
+        loop {
+            let length = export.read(&mut chunk).unwrap();
+            if length == 0 {
+                break;
+            }
+            data.extend_from_slice(&chunk[..length]);
+        }
+        let lines: Vec<Event> = read_ndjson::<Event>(&String::from_utf8(data).unwrap()).into_iter().map(|e| e.unwrap()).collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(lines[1].text, "b");
+    }
+
+    #[test]
+    fn comments_on_missing_events_are_rejected() {
+        let edb = FileBasedEventDb::new(std::env::temp_dir().join(format!("event-api-test-{}", uuid::Uuid::new_v4())));
+        let data = "{\"eventId\":\"missing\",\"userId\":\"jon\",\"comment\":\"hi\",\"timestamp\":1}\n";
+        let report = import_comments(&edb, data, Format::Ndjson, None, false, false).unwrap();
+        assert_eq!(report.imported, 0);
+        assert_eq!(report.errors[0].error, "Event not found");
+    }
+
+    #[test]
+    fn imports_are_credited_to_the_caller() {
+        let edb = FileBasedEventDb::new(std::env::temp_dir().join(format!("event-api-test-{}", uuid::Uuid::new_v4())));
+        let data = "{\"from\":1,\"text\":\"a\",\"createdBy\":\"mallory\"}\n";
+        let report = import_events(&edb, data, Format::Ndjson, Some("alice".to_string()), false, false).unwrap();
+        let event = edb.get_event(report.ids[0].clone()).unwrap();
+        assert_eq!(event.created_by.as_deref(), Some("alice"));
+
+        let data = format!("{{\"eventId\":\"{}\",\"userId\":\"mallory\",\"comment\":\"hi\",\"timestamp\":1}}\n", report.ids[0]);
+        let report = import_comments(&edb, &data, Format::Ndjson, Some("alice".to_string()), false, false).unwrap();
+        let comment = edb.get_comment(report.ids[0].clone()).unwrap();
+        assert_eq!(comment.user_id, "alice");
+    }
+
+    #[test]
+    fn preserved_ids_go_through_status_transitions() {
+        let edb = FileBasedEventDb::new(std::env::temp_dir().join(format!("event-api-test-{}", uuid::Uuid::new_v4())));
+        let data = "{\"id\":\"a\",\"from\":1,\"text\":\"a\",\"status\":\"resolved\"}\n";
+        import_events(&edb, data, Format::Ndjson, None, true, false).unwrap();
+
+        let data = "{\"id\":\"a\",\"from\":1,\"text\":\"a\",\"status\":\"acknowledged\"}\n{\"id\":\"b\",\"from\":1,\"text\":\"b\",\"status\":\"acknowledged\"}\n";
+        let report = import_events(&edb, data, Format::Ndjson, None, true, false).unwrap();
+        assert_eq!(report.ids, vec!["b"]);
+        assert_eq!(report.errors[0].record, 0);
+        assert_eq!(edb.get_event("a".to_string()).unwrap().status(), Status::Resolved);
+    }
+
+    #[test]
+    fn replacing_imports_keep_the_stored_author() {
+        let edb = FileBasedEventDb::new(std::env::temp_dir().join(format!("event-api-test-{}", uuid::Uuid::new_v4())));
+        let data = "{\"id\":\"a\",\"from\":1,\"text\":\"a\"}\n";
+        import_events(&edb, data, Format::Ndjson, Some("alice".to_string()), true, false).unwrap();
+        let data = "{\"id\":\"a\",\"from\":1,\"text\":\"b\"}\n{\"id\":\"b\",\"from\":1,\"text\":\"c\"}\n";
+        import_events(&edb, data, Format::Ndjson, Some("mallory".to_string()), true, false).unwrap();
+        let replaced = edb.get_event("a".to_string()).unwrap();
+        assert_eq!(replaced.text, "b");
+        assert_eq!(replaced.created_by.as_deref(), Some("alice"));
+        assert_eq!(edb.get_event("b".to_string()).unwrap().created_by.as_deref(), Some("mallory"));
+
+        let data = "{\"id\":\"c\",\"eventId\":\"a\",\"userId\":\"x\",\"comment\":\"hi\",\"timestamp\":1}\n";
+        import_comments(&edb, data, Format::Ndjson, Some("alice".to_string()), true, false).unwrap();
+        import_comments(&edb, &data.replace("hi", "edited"), Format::Ndjson, Some("mallory".to_string()), true, false).unwrap();
+        assert_eq!(edb.get_comment("c".to_string()).unwrap().user_id, "alice");
+    }
+}
@@ -0,0 +1,617 @@
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::thread;
+use std::time::Duration;
+
+use chrono::Utc;
+use hmac::{Hmac, Mac, NewMac};
+use reqwest::Url;
+use reqwest::blocking::Client;
+use reqwest::redirect::Policy;
+use serde::{Deserialize, Serialize};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use sha2::Sha256;
+use uuid::Uuid;
+
+use crate::db::tenant::Tenant;
+use crate::model::{Comment, Event};
+
+static WEBHOOKS_DIR: &str = "data";
+static WEBHOOKS_JSON: &str = "webhooks.json";
+static QUEUE_JSON: &str = "webhook_queue.json";
+static DELIVERIES_JSON: &str = "webhook_deliveries.json";
+static SECRET_PREFIX: &str = "whsec_";
+
+/// Delivery attempts kept in the log, oldest first out.
+const DELIVERY_LOG_LIMIT: usize = 1000;
+/// Longest wait between two attempts, in seconds.
+const MAX_RETRY_DELAY: u64 = 6 * 3600;
+/// How often the queue is checked for due deliveries.
+const POLL_INTERVAL: Duration = Duration::from_secs(1);
+
+/// A change a subscription can receive.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+pub enum Operation {
+    #[serde(rename = "event.created")]
+    EventCreated,
+    #[serde(rename = "event.updated")]
+    EventUpdated,
+    #[serde(rename = "event.deleted")]
+    EventDeleted,
+    #[serde(rename = "comment.created")]
+    CommentCreated,
+    #[serde(rename = "comment.updated")]
+    CommentUpdated,
+    #[serde(rename = "comment.deleted")]
+    CommentDeleted,
+}
+
+impl Operation {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Operation::EventCreated => "event.created",
+            Operation::EventUpdated => "event.updated",
+            Operation::EventDeleted => "event.deleted",
+            Operation::CommentCreated => "comment.created",
+            Operation::CommentUpdated => "comment.updated",
+            Operation::CommentDeleted => "comment.deleted",
+        }
+    }
+}
+
+/// A webhook subscription. Events, and comments on events, matching
+/// `appName` and `sourceId` are posted to `url` for the listed operations.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Subscription {
+    pub id: String,
+    pub url: String,
+    /// Key for the `X-Webhook-Signature` HMAC. Only shown on creation.
+    #[serde(skip_serializing_if = "String::is_empty", default)]
+    pub secret: String,
+    #[serde(rename = "appName", skip_serializing_if = "Option::is_none", default)]
+    pub app_name: Option<String>,
+    #[serde(rename = "sourceId", skip_serializing_if = "Option::is_none", default)]
+    pub source_id: Option<String>,
+    pub operations: Vec<Operation>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub tenant: Option<String>,
+    pub created: i64,
+}
+
+impl Subscription {
+    /// The subscription without its secret, for listing.
+    pub fn redacted(&self) -> Subscription {
+        Subscription { secret: String::new(), ..self.clone() }
+    }
+
+    fn matches(&self, operation: Operation, event: &Event) -> bool {
+        self.operations.contains(&operation)
+            && (self.app_name.is_none() || self.app_name == event.app_name)
+            && (self.source_id.is_none() || self.source_id == event.source_id)
+    }
+}
+
+/// Request body for creating a subscription. A secret is generated when
+/// none is given.
+#[derive(Debug, Clone, Deserialize)]
+pub struct NewSubscription {
+    pub url: String,
+    #[serde(default)]
+    pub secret: Option<String>,
+    #[serde(rename = "appName", default)]
+    pub app_name: Option<String>,
+    #[serde(rename = "sourceId", default)]
+    pub source_id: Option<String>,
+    pub operations: Vec<Operation>,
+}
+
+/// A payload waiting to be posted. The body is kept as sent, so that
+/// retries carry the same signature.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Delivery {
+    pub id: String,
+    #[serde(rename = "webhookId")]
+    pub webhook_id: String,
+    pub operation: Operation,
+    pub body: String,
+    pub attempts: u32,
+    #[serde(rename = "nextAttempt")]
+    pub next_attempt: i64,
+    pub created: i64,
+}
+
+/// One attempt at posting a delivery, as shown by the delivery log.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeliveryAttempt {
+    #[serde(rename = "deliveryId")]
+    pub delivery_id: String,
+    #[serde(rename = "webhookId")]
+    pub webhook_id: String,
+    pub operation: Operation,
+    pub attempt: u32,
+    pub timestamp: i64,
+    pub success: bool,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub status: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none", default)]
+    pub error: Option<String>,
+    /// When the delivery is tried again, if it failed and has attempts left.
+    #[serde(rename = "nextAttempt", skip_serializing_if = "Option::is_none", default)]
+    pub next_attempt: Option<i64>,
+}
+
+/// Webhook settings read from the `[global]` section of `Rocket.toml`:
+///
+/// * `webhooks_dir`: directory holding the subscriptions, the retry queue
+///   and the delivery log. Defaults to `data`.
+/// * `webhook_max_attempts`: attempts before a delivery is given up.
+///   Defaults to `8`.
+/// * `webhook_retry_seconds`: wait before the first retry, doubled for
+///   each one after it. Defaults to `30`.
+/// * `webhook_timeout`: seconds to wait for a receiver. Defaults to `10`.
+///
+/// Payloads are signed with HMAC-SHA256 over the body, sent as
+/// `X-Webhook-Signature: sha256=<hex>`.
+#[derive(Debug, Clone)]
+pub struct Webhooks {
+    dir: PathBuf,
+    tenants: Vec<Tenant>,
+    max_attempts: u32,
+    retry_seconds: u64,
+    timeout: u64,
+    lock: Arc<Mutex<()>>,
+}
+
+impl Webhooks {
+    pub fn new<P: AsRef<Path>>(dir: P, tenants: Vec<Tenant>) -> Webhooks {
+        Webhooks {
+            dir: dir.as_ref().to_path_buf(),
+            tenants,
+            max_attempts: 8,
+            retry_seconds: 30,
+            timeout: 10,
+            lock: Arc::new(Mutex::new(())),
+        }
+    }
+
+    pub fn from_rocket(rocket: &rocket::Rocket, tenants: Vec<Tenant>) -> Webhooks {
+        let config = rocket.config();
+        let positive = |key: &str, default: i64| config.get_int(key).ok().filter(|n| *n > 0).unwrap_or(default);
+        Webhooks {
+            max_attempts: positive("webhook_max_attempts", 8) as u32,
+            retry_seconds: positive("webhook_retry_seconds", 30) as u64,
+            timeout: positive("webhook_timeout", 10) as u64,
+            ..Webhooks::new(config.get_string("webhooks_dir").unwrap_or(WEBHOOKS_DIR.to_string()), tenants)
+        }
+    }
+
+    pub fn list(&self) -> Result<Vec<Subscription>, Box<dyn Error>> {
+        read_json(&self.dir.join(WEBHOOKS_JSON))
+    }
+
+    /// Stores a subscription and returns it with its secret. A tenant's
+    /// subscription may not point at a loopback, private or link-local
+    /// address, so that tenants cannot reach the server's own network. This
+    /// is checked again before every delivery.
+    pub fn create(&self, new: NewSubscription, tenant: Option<String>) -> Result<Subscription, Box<dyn Error>> {
+        let url = Url::parse(&new.url)?;
+        if url.scheme() != "http" && url.scheme() != "https" {
+            return Err("Webhook URLs must be http or https".into());
+        }
+        if tenant.is_some() && url.socket_addrs(|| None)?.iter().any(|address| is_internal(address.ip())) {
+            return Err("Webhook URLs must not point at internal addresses".into());
+        }
+        if new.operations.is_empty() {
+            return Err("A webhook needs at least one operation".into());
+        }
+        let secret = match new.secret {
+            Some(secret) if !secret.is_empty() => secret,
+            _ => format!("{}{}", SECRET_PREFIX, Uuid::new_v4().to_simple()),
+        };
+        let subscription = Subscription {
+            id: Uuid::new_v4().to_hyphenated().to_string(),
+            url: url.to_string(),
+            secret,
+            app_name: new.app_name,
+            source_id: new.source_id,
+            operations: new.operations,
+            tenant,
+            created: Utc::now().timestamp_millis(),
+        };
+
+        let _guard = self.lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut subscriptions = self.list()?;
+        subscriptions.push(subscription.clone());
+        write_json(&self.dir.join(WEBHOOKS_JSON), &subscriptions)?;
+        Ok(subscription)
+    }
+
+    /// Removes a subscription and drops its queued deliveries. Returns
+    /// whether it existed.
+    pub fn delete(&self, webhook_id: &str) -> Result<bool, Box<dyn Error>> {
+        let _guard = self.lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut subscriptions = self.list()?;
+        let count = subscriptions.len();
+        subscriptions.retain(|s| s.id != webhook_id);
+        if subscriptions.len() == count {
+            return Ok(false);
+        }
+        write_json(&self.dir.join(WEBHOOKS_JSON), &subscriptions)?;
+
+        let mut queue: Vec<Delivery> = read_json(&self.dir.join(QUEUE_JSON))?;
+        queue.retain(|d| d.webhook_id != webhook_id);
+        write_json(&self.dir.join(QUEUE_JSON), &queue)?;
+        Ok(true)
+    }
+
+    /// The logged attempts for a subscription, newest first.
+    pub fn deliveries(&self, webhook_id: &str) -> Result<Vec<DeliveryAttempt>, Box<dyn Error>> {
+        let log: Vec<DeliveryAttempt> = read_json(&self.dir.join(DELIVERIES_JSON))?;
+        Ok(log.into_iter().rev().filter(|a| a.webhook_id == webhook_id).collect())
+    }
+
+    /// Queues a delivery for every subscription matching the change. For
+    /// comments, `event` is the event commented on.
+    pub fn notify(&self, operation: Operation, event: &Event, comment: Option<&Comment>) -> Result<(), Box<dyn Error>> {
+        let subscriptions: Vec<Subscription> = self.list()?
+            .into_iter()
+            .filter(|s| s.matches(operation, event) && self.in_tenant(s, event))
+            .collect();
+        if subscriptions.is_empty() {
+            return Ok(());
+        }
+
+        let now = Utc::now().timestamp_millis();
+        let data = match comment {
+            Some(comment) => json!(comment),
+            None => json!(event),
+        };
+        let deliveries: Vec<Delivery> = subscriptions.iter()
+            .map(|subscription| {
+                let id = Uuid::new_v4().to_hyphenated().to_string();
+                let body = json!({
+                    "id": id,
+                    "webhookId": subscription.id,
+                    "operation": operation,
+                    "timestamp": now,
+                    "data": data,
+                });
+                Delivery {
+                    id,
+                    webhook_id: subscription.id.clone(),
+                    operation,
+                    body: body.to_string(),
+                    attempts: 0,
+                    next_attempt: now,
+                    created: now,
+                }
+            })
+            .collect();
+
+        let _guard = self.lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut queue: Vec<Delivery> = read_json(&self.dir.join(QUEUE_JSON))?;
+        queue.extend(deliveries);
+        write_json(&self.dir.join(QUEUE_JSON), &queue)
+    }
+
+    /// Subscriptions bound to a tenant only hear about its events.
+    fn in_tenant(&self, subscription: &Subscription, event: &Event) -> bool {
+        match subscription.tenant {
+            Some(ref tenant_id) => self.tenants.iter().any(|t| t.id == *tenant_id && t.owns(event)),
+            None => true,
+        }
+    }
+
+    /// Starts a thread posting queued deliveries as they fall due.
+    pub fn start(&self) {
+        let webhooks = self.clone();
+        thread::spawn(move || loop {
+            if let Err(err) = webhooks.deliver_due() {
+                eprintln!("Could not deliver webhooks: {}", err);
+            }
+            thread::sleep(POLL_INTERVAL);
+        });
+    }
+
+    /// Posts the deliveries that are due, then reschedules the failed ones
+    /// with exponential backoff and logs every attempt. The lock is not held
+    /// while posting, so that changes are not held up by slow receivers.
+    pub fn deliver_due(&self) -> Result<(), Box<dyn Error>> {
+        let now = Utc::now().timestamp_millis();
+        let (due, subscriptions) = {
+            let _guard = self.lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+            let queue: Vec<Delivery> = read_json(&self.dir.join(QUEUE_JSON))?;
+            let due: Vec<Delivery> = queue.into_iter().filter(|d| d.next_attempt <= now).collect();
+            (due, self.list()?)
+        };
+        if due.is_empty() {
+            return Ok(());
+        }
+
+        let client = self.client(None)?;
+        let mut attempts: Vec<DeliveryAttempt> = Vec::new();
+        for delivery in due.iter() {
+            let subscription = match subscriptions.iter().find(|s| s.id == delivery.webhook_id) {
+                Some(subscription) => subscription,
+                None => continue,
+            };
+            let attempt = delivery.attempts + 1;
+            let sent = match subscription.tenant {
+                Some(_) => self.tenant_client(subscription).and_then(|client| send(&client, subscription, delivery)),
+                None => send(&client, subscription, delivery),
+            };
+            let (status, error) = match sent {
+                Ok(status) if status < 300 => (Some(status), None),
+                Ok(status) => (Some(status), Some(format!("Receiver answered {}", status))),
+                Err(err) => (None, Some(err)),
+            };
+            let next_attempt = match error {
+                Some(_) if attempt < self.max_attempts => Some(now + self.retry_delay(attempt) as i64 * 1000),
+                _ => None,
+            };
+            attempts.push(DeliveryAttempt {
+                delivery_id: delivery.id.clone(),
+                webhook_id: delivery.webhook_id.clone(),
+                operation: delivery.operation,
+                attempt,
+                timestamp: Utc::now().timestamp_millis(),
+                success: error.is_none(),
+                status,
+                error,
+                next_attempt,
+            });
+        }
+
+        let _guard = self.lock.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let mut queue: Vec<Delivery> = read_json(&self.dir.join(QUEUE_JSON))?;
+        queue.retain(|d| !due.iter().any(|done| done.id == d.id) || attempts.iter().any(|a| a.delivery_id == d.id && a.next_attempt.is_some()));
+        for delivery in queue.iter_mut() {
+            if let Some(attempt) = attempts.iter().find(|a| a.delivery_id == delivery.id) {
+                delivery.attempts = attempt.attempt;
+                delivery.next_attempt = attempt.next_attempt.unwrap_or(delivery.next_attempt);
+            }
+        }
+        write_json(&self.dir.join(QUEUE_JSON), &queue)?;
+
+        let mut log: Vec<DeliveryAttempt> = read_json(&self.dir.join(DELIVERIES_JSON))?;
+        log.extend(attempts);
+        if log.len() > DELIVERY_LOG_LIMIT {
+            log.drain(..log.len() - DELIVERY_LOG_LIMIT);
+        }
+        write_json(&self.dir.join(DELIVERIES_JSON), &log)
+    }
+
+    /// A client that does not follow redirects, since a receiver could
+    /// otherwise send deliveries on to any address. `pinned` sends requests
+    /// for a host to the given address.
+    fn client(&self, pinned: Option<(&str, SocketAddr)>) -> reqwest::Result<Client> {
+        let mut builder = Client::builder()
+            .timeout(Duration::from_secs(self.timeout))
+            .redirect(Policy::none());
+        if let Some((host, address)) = pinned {
+            builder = builder.resolve(host, address);
+        }
+        builder.build()
+    }
+
+    /// Resolves a tenant's URL again before every attempt, so that a host
+    /// rebound to an internal address since the subscription was created is
+    /// refused, and pins the request to the address that was checked.
+    fn tenant_client(&self, subscription: &Subscription) -> Result<Client, String> {
+        let url = Url::parse(&subscription.url).map_err(|err| err.to_string())?;
+        let addresses = url.socket_addrs(|| None).map_err(|err| err.to_string())?;
+        let address = match addresses.first() {
+            Some(address) if !addresses.iter().any(|a| is_internal(a.ip())) => *address,
+            Some(_) => return Err("Webhook URL resolves to an internal address".to_string()),
+            None => return Err("Webhook URL does not resolve".to_string()),
+        };
+        self.client(url.domain().map(|domain| (domain, address))).map_err(|err| err.to_string())
+    }
+
+    /// Seconds to wait after the given failed attempt.
+    fn retry_delay(&self, attempt: u32) -> u64 {
+        self.retry_seconds
+            .saturating_mul(1u64 << (attempt - 1).min(30))
+            .min(MAX_RETRY_DELAY)
+    }
+}
+
+fn is_internal(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback() || ip.is_private() || ip.is_link_local() || ip.is_unspecified() || ip.is_broadcast(),
+        IpAddr::V6(ip) => {
+            let first = ip.segments()[0];
+            ip.is_loopback()
+                || ip.is_unspecified()
+                || first & 0xffc0 == 0xfe80
+                || first & 0xfe00 == 0xfc00
+                || ip.to_ipv4().map_or(false, |ip| is_internal(IpAddr::V4(ip)))
+        },
+    }
+}
+
+/// Posts a delivery and returns the receiver's status code.
+fn send(client: &Client, subscription: &Subscription, delivery: &Delivery) -> Result<u16, String> {
+    let response = client.post(&subscription.url)
+        .header("Content-Type", "application/json")
+        .header("X-Webhook-Id", delivery.id.as_str())
+        .header("X-Webhook-Operation", delivery.operation.as_str())
+        .header("X-Webhook-Signature", format!("sha256={}", sign(&subscription.secret, &delivery.body)))
+        .body(delivery.body.clone())
+        .send()
+        .map_err(|err| err.to_string())?;
+    Ok(response.status().as_u16())
+}
+
+/// The hex HMAC-SHA256 of a body, for receivers to check against
+/// `X-Webhook-Signature`.
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac = Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("HMAC accepts keys of any length");
+    mac.update(body.as_bytes());
+    format!("{:x}", mac.finalize().into_bytes())
+}
+
+/// Reads a JSON list. A missing file is an empty list.
+fn read_json<T: DeserializeOwned>(path: &Path) -> Result<Vec<T>, Box<dyn Error>> {
+    if !path.exists() {
+        return Ok(Vec::new());
+    }
+    Ok(serde_json::from_str(&fs::read_to_string(path)?)?)
+}
+
+fn write_json<T: Serialize>(path: &Path, values: &[T]) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    fs::write(path, serde_json::to_string_pretty(values)?)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{BufRead, BufReader, Read, Write};
+    use std::net::TcpListener;
+    use std::sync::mpsc;
+
+    use super::*;
+
+    fn temp_webhooks() -> Webhooks {
+        Webhooks::new(std::env::temp_dir().join(format!("event-api-test-{}", Uuid::new_v4())), Vec::new())
+    }
+
+    fn subscription(url: &str) -> NewSubscription {
+        NewSubscription {
+            url: url.to_string(),
+            secret: Some("s3cret".to_string()),
+            app_name: None,
+            source_id: None,
+            operations: vec![Operation::EventCreated],
+        }
+    }
+
+    /// Answers one request per status with that status, and passes the
+    /// requests on as text.
+    fn receiver(statuses: Vec<u16>) -> (String, mpsc::Receiver<String>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/hook", listener.local_addr().unwrap());
+        let (sender, requests) = mpsc::channel();
+        thread::spawn(move || {
+            for status in statuses {
+                let (mut stream, _) = listener.accept().unwrap();
+                let mut reader = BufReader::new(stream.try_clone().unwrap());
+                let mut request = String::new();
+                let mut length = 0;
+                loop {
+                    let mut line = String::new();
+                    reader.read_line(&mut line).unwrap();
+                    if let Some(value) = line.to_lowercase().strip_prefix("content-length:") {
+                        length = value.trim().parse().unwrap();
+                    }
+                    request.push_str(&line);
+                    if line == "\r\n" {
+                        break;
+                    }
+                }
+                let mut body = vec![0u8; length];
+                reader.read_exact(&mut body).unwrap();
+                request.push_str(&String::from_utf8(body).unwrap());
+                write!(stream, "HTTP/1.1 {} Status\r\nContent-Length: 0\r\nConnection: close\r\n\r\n", status).unwrap();
+                sender.send(request).unwrap();
+            }
+        });
+        (url, requests)
+    }
+
+    fn header<'a>(request: &'a str, name: &str) -> Option<&'a str> {
+        request.lines()
+            .find(|line| line.to_lowercase().starts_with(&format!("{}:", name)))
+            .map(|line| line[name.len() + 1..].trim())
+    }
+
+    #[test]
+    fn deliveries_are_signed_retried_and_persisted() {
+        let (url, requests) = receiver(vec![500, 200]);
+        let webhooks = temp_webhooks();
+        let subscription = webhooks.create(subscription(&url), None).unwrap();
+        let event: Event = serde_json::from_value(json!({ "id": "e1", "from": 1, "text": "deploy" })).unwrap();
+        webhooks.notify(Operation::EventCreated, &event, None).unwrap();
+
+        webhooks.deliver_due().unwrap();
+        let request = requests.recv().unwrap();
+        let body = &request[request.find("\r\n\r\n").unwrap() + 4..];
+        let expected = format!("sha256={}", sign("s3cret", body));
+        assert_eq!(header(&request, "x-webhook-signature"), Some(expected.as_str()));
+        assert_eq!(header(&request, "x-webhook-operation"), Some("event.created"));
+
+        let log = webhooks.deliveries(&subscription.id).unwrap();
+        assert!(!log[0].success);
+        assert_eq!(log[0].status, Some(500));
+        let queue: Vec<Delivery> = read_json(&webhooks.dir.join(QUEUE_JSON)).unwrap();
+        assert_eq!(queue[0].attempts, 1);
+        assert!(queue[0].next_attempt >= log[0].timestamp + 29000);
+
+        // Not due yet, so nothing is sent.
+        webhooks.deliver_due().unwrap();
+        assert_eq!(webhooks.deliveries(&subscription.id).unwrap().len(), 1);
+
+        // The queue survives a restart; make the retry due and send it.
+        let restarted = Webhooks::new(&webhooks.dir, Vec::new());
+        let queue: Vec<Delivery> = queue.into_iter().map(|d| Delivery { next_attempt: 0, ..d }).collect();
+        write_json(&restarted.dir.join(QUEUE_JSON), &queue).unwrap();
+        restarted.deliver_due().unwrap();
+        let retried = requests.recv().unwrap();
+        assert_eq!(&retried[retried.find("\r\n\r\n").unwrap() + 4..], body);
+
+        let log = restarted.deliveries(&subscription.id).unwrap();
+        assert!(log[0].success);
+        assert_eq!(log[0].attempt, 2);
+        let queue: Vec<Delivery> = read_json(&restarted.dir.join(QUEUE_JSON)).unwrap();
+        assert!(queue.is_empty());
+    }
+
+    #[test]
+    fn retries_back_off_up_to_a_limit() {
+        let webhooks = temp_webhooks();
+        assert_eq!(webhooks.retry_delay(1), 30);
+        assert_eq!(webhooks.retry_delay(2), 60);
+        assert_eq!(webhooks.retry_delay(4), 240);
+        assert_eq!(webhooks.retry_delay(40), MAX_RETRY_DELAY);
+    }
+
+    #[test]
+    fn tenants_may_not_target_internal_addresses() {
+        let webhooks = temp_webhooks();
+        for url in ["http://127.0.0.1/hook", "http://10.0.0.1/", "http://169.254.169.254/", "http://[::1]/", "http://[fe80::1]/"].iter() {
+            assert!(webhooks.create(subscription(url), Some("blue".to_string())).is_err(), "{}", url);
+        }
+        assert!(webhooks.create(subscription("http://192.0.2.1/hook"), Some("blue".to_string())).is_ok());
+        assert!(webhooks.create(subscription("http://127.0.0.1/hook"), None).is_ok());
+    }
+
+    #[test]
+    fn redirects_are_not_followed() {
+        let (url, requests) = receiver(vec![302]);
+        let webhooks = temp_webhooks();
+        let subscription = webhooks.create(subscription(&url), None).unwrap();
+        let event: Event = serde_json::from_value(json!({ "id": "e1", "from": 1, "text": "deploy" })).unwrap();
+        webhooks.notify(Operation::EventCreated, &event, None).unwrap();
+        webhooks.deliver_due().unwrap();
+        requests.recv().unwrap();
+        let log = webhooks.deliveries(&subscription.id).unwrap();
+        assert_eq!(log[0].status, Some(302));
+        assert!(!log[0].success);
+    }
+
+    #[test]
+    fn tenant_addresses_are_checked_before_every_attempt() {
+        let webhooks = temp_webhooks();
+        let mut subscription = webhooks.create(subscription("http://192.0.2.1/hook"), Some("blue".to_string())).unwrap();
+        assert!(webhooks.tenant_client(&subscription).is_ok());
+        subscription.url = "http://127.0.0.1/hook".to_string();
+        assert!(webhooks.tenant_client(&subscription).is_err());
+    }
+}